@@ -2,9 +2,11 @@
 
 use std::borrow::Cow;
 use std::collections::LinkedList;
+use std::time::Duration;
 
 use bevy::prelude::*;
 use dashmap::DashMap;
+pub use relay_client::{ConnectionStatus, Notification, QueueFull, RoomName};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 pub use uuid::Uuid;
@@ -22,22 +24,105 @@ impl Connection {
     pub const fn identifier(&self) -> Option<Uuid> {
         self.0.identifier()
     }
+
+    /// Returns the status of the connection.
+    pub const fn status(&self) -> ConnectionStatus {
+        self.0.status()
+    }
+
+    /// Returns the last error that made the connection fail.
+    pub fn last_error(&self) -> Option<&str> {
+        self.0.last_error()
+    }
+
+    /// Returns the last measured round-trip time to the relay server.
+    pub const fn rtt(&self) -> Option<Duration> {
+        self.0.rtt()
+    }
+
+    /// Be notified with [RelayNotification]s when the given peer comes online
+    /// or goes offline.
+    pub fn subscribe_presence(&mut self, peer: Uuid) -> Result<(), QueueFull> {
+        self.0.subscribe_presence(peer)
+    }
+
+    /// Stop being notified about the presence of the given peer.
+    pub fn unsubscribe_presence(&mut self, peer: Uuid) -> Result<(), QueueFull> {
+        self.0.unsubscribe_presence(peer)
+    }
+
+    /// Returns the rooms the connection is a member of.
+    pub fn rooms(&self) -> impl Iterator<Item = &RoomName> {
+        self.0.rooms()
+    }
+
+    /// Create a new room and join it.
+    pub fn create_room(&self, room: RoomName) -> Result<(), QueueFull> {
+        self.0.create_room(room)
+    }
+
+    /// Join a room, creating it if it doesn't exist.
+    pub fn join_room(&mut self, room: RoomName) -> Result<(), QueueFull> {
+        self.0.join_room(room)
+    }
+
+    /// Leave a room.
+    pub fn leave_room(&mut self, room: RoomName) -> Result<(), QueueFull> {
+        self.0.leave_room(room)
+    }
+
+    /// Ask for the members of a room, received as a [RelayNotification].
+    pub fn list_room(&self, room: RoomName) -> Result<(), QueueFull> {
+        self.0.list_room(room)
+    }
 }
 
+/// An [Event] triggered when a notification is received from the relay
+/// server.
+#[derive(Event)]
+pub struct RelayNotification(pub Notification);
+
 /// A bevy plugin to make multiplayer game using a relay server.
-pub struct NetworkPlugin(String);
+pub struct NetworkPlugin {
+    /// The domain of the relay server.
+    domain: String,
+
+    /// The name of the identity profile to use.
+    profile: Option<String>,
+}
 
 impl NetworkPlugin {
     /// Create a new [NetworkPlugin] plugin with the given domain for the relay
     /// server.
     pub fn new<'a>(domain: impl Into<Cow<'a, str>>) -> Self {
-        Self(domain.into().into_owned())
+        Self {
+            domain: domain.into().into_owned(),
+            profile: None,
+        }
+    }
+
+    /// Use the identity profile with the given name instead of the default
+    /// identity.
+    pub fn with_profile(mut self, profile: impl Into<String>) -> Self {
+        self.profile = Some(profile.into());
+        self
     }
 }
 
 /// Update the relay connection.
-fn update_connection(mut connection: ResMut<Connection>, received_messages: Res<ReceivedMessages>) {
+fn update_connection(
+    mut connection: ResMut<Connection>,
+    received_messages: Res<ReceivedMessages>,
+    mut notifications: EventWriter<RelayNotification>,
+) {
     let messages = connection.0.update();
+    notifications.send_batch(
+        connection
+            .0
+            .take_notifications()
+            .into_iter()
+            .map(RelayNotification),
+    );
     for (sender, mut message) in messages {
         if message.len() < 2 {
             error!("message too short received");
@@ -61,10 +146,15 @@ fn clear_received_messages(received_messages: Res<ReceivedMessages>) {
 
 impl Plugin for NetworkPlugin {
     fn build(&self, app: &mut App) {
+        let mut connection = relay_client::Connection::builder(format!("wss://{}", self.domain));
+        if let Some(profile) = &self.profile {
+            connection = connection.profile(profile);
+        }
         app.insert_resource(Connection(
-            relay_client::Connection::new(&self.0).expect("could not create connection"),
+            connection.build().expect("could not create connection"),
         ))
         .insert_resource(ReceivedMessages(DashMap::new()))
+        .add_event::<RelayNotification>()
         .add_systems(PreUpdate, update_connection)
         .add_systems(PreUpdate, clear_received_messages.after(update_connection));
     }
@@ -80,11 +170,41 @@ struct LastEventId(u16);
 #[derive(Event)]
 pub struct SendTo<T: Event + DeserializeOwned + Serialize>(pub Uuid, pub T);
 
+/// An [Event] used to send an [Event] to all the other members of a room on
+/// the relay server.
+#[derive(Event)]
+pub struct SendToRoom<T: Event + DeserializeOwned + Serialize>(pub RoomName, pub T);
+
 /// An [Event] used to receive an [Event] from another client on the relay
 /// server.
 #[derive(Event)]
 pub struct Receive<T: Event + DeserializeOwned + Serialize>(pub Uuid, pub T);
 
+/// Serialize an [Event] followed by its event id.
+fn serialize_event<T: Serialize>(event: &T, event_id: u16) -> Option<Vec<u8>> {
+    // Get the size of the serialized event.
+    let size = match bincode::serialized_size(event) {
+        Ok(size) => size,
+        Err(e) => {
+            error!("failed to serialize event: {}", e);
+            return None;
+        }
+    };
+
+    // Serialize the event we add 18 here because we will add the event id (2
+    // bytes) at the end and after that, the relay client will add the target id
+    // (16 bytes).
+    let mut data = Vec::with_capacity(size as usize + 18);
+    if let Err(e) = bincode::serialize_into(&mut data, event) {
+        error!("failed to serialize event: {}", e);
+        return None;
+    }
+
+    // Add the event id.
+    data.extend_from_slice(&event_id.to_be_bytes());
+    Some(data)
+}
+
 /// A trait that extends a bevy [App] to add multiplayer support.
 pub trait NetworkAppExt {
     /// Setup the application to manage network events of type `T`.
@@ -100,34 +220,32 @@ impl NetworkAppExt for App {
 
         // Register the event.
         self.add_event::<SendTo<T>>()
+            .add_event::<SendToRoom<T>>()
             .add_event::<Receive<T>>()
             .add_systems(
                 PreUpdate,
                 (move |mut events: EventReader<SendTo<T>>, connection: Res<Connection>| {
                     for event in events.read() {
-                        // Get the size of the serialized event.
-                        let size = match bincode::serialized_size(&event.1) {
-                            Ok(size) => size,
-                            Err(e) => {
-                                error!("failed to serialize event: {}", e);
-                                continue;
-                            }
+                        let Some(data) = serialize_event(&event.1, event_id) else {
+                            continue;
                         };
-
-                        // Serialize the event we add 18 here because we will add the event id (2
-                        // bytes) at the end and after that, the relay client will add the target id
-                        // at the end (16 bytes).
-                        let mut data = Vec::with_capacity(size as usize + 18);
-                        if let Err(e) = bincode::serialize_into(&mut data, &event.1) {
-                            error!("failed to serialize event: {}", e);
+                        if let Err(e) = connection.0.send(event.0, data) {
+                            error!("failed to send event: {}", e);
+                        }
+                    }
+                })
+                .before(update_connection),
+            )
+            .add_systems(
+                PreUpdate,
+                (move |mut events: EventReader<SendToRoom<T>>, connection: Res<Connection>| {
+                    for event in events.read() {
+                        let Some(data) = serialize_event(&event.1, event_id) else {
                             continue;
+                        };
+                        if let Err(e) = connection.0.send_to_room(event.0.clone(), data) {
+                            error!("failed to send event: {}", e);
                         }
-
-                        // Add the event id.
-                        data.extend_from_slice(&event_id.to_be_bytes());
-
-                        // Send the event.
-                        connection.0.send(event.0, data);
                     }
                 })
                 .before(update_connection),
@@ -17,7 +17,14 @@ pub struct NetworkingPlugin;
 
 impl Plugin for NetworkingPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(NetworkPlugin::new("relay.cocosol.fr".to_string()))
+        // Allow to run multiple clients on the same machine with different
+        // identities.
+        let mut network_plugin = NetworkPlugin::new("relay.cocosol.fr".to_string());
+        if let Ok(profile) = std::env::var("BORDER_WARS_PROFILE") {
+            network_plugin = network_plugin.with_profile(profile);
+        }
+
+        app.add_plugins(network_plugin)
             .add_plugins(ConnectionPlugin)
             .add_systems(Update, handle_start_game)
             .add_network_event::<StartGame>()
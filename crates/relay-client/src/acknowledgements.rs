@@ -0,0 +1,402 @@
+//! The acknowledged messages, sent again until their target receives them.
+
+use std::collections::{BTreeMap, HashSet, LinkedList, VecDeque};
+use std::time::{Duration, Instant};
+
+use relay_protocol::{Flags, Frame, MessageId, Request};
+use uuid::Uuid;
+
+/// The configuration of the acknowledged messages of a
+/// [Connection](crate::Connection).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcknowledgementConfig {
+    /// The time to wait for the receipt of a message before sending it again.
+    pub timeout: Duration,
+
+    /// The number of times a message is sent before it's considered lost.
+    pub max_attempts: u32,
+
+    /// The time during which the identifiers of the received messages are
+    /// remembered, to ignore the messages received again.
+    ///
+    /// This should be longer than the time during which the peers send their
+    /// messages again, otherwise a message could be received twice.
+    pub duplicate_window: Duration,
+}
+
+impl Default for AcknowledgementConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(2),
+            max_attempts: 5,
+            duplicate_window: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// What happened to an acknowledged message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// The message was received by its target, or stored by the relay server
+    /// if it's durable.
+    Acknowledged {
+        /// The target of the message.
+        target: Uuid,
+
+        /// The identifier of the message.
+        id: MessageId,
+    },
+
+    /// No receipt was received after sending the message the maximum number
+    /// of times.
+    Failed {
+        /// The target of the message.
+        target: Uuid,
+
+        /// The identifier of the message.
+        id: MessageId,
+    },
+}
+
+/// An acknowledged message waiting for its receipt.
+struct Pending {
+    /// The target of the message.
+    target: Uuid,
+
+    /// The frame of the message, sent again if needed.
+    frame: Frame<Request>,
+
+    /// The time the message was last sent.
+    sent_at: Instant,
+
+    /// The number of times the message was sent.
+    attempts: u32,
+}
+
+/// Keeps track of the acknowledged messages sent and received.
+pub struct Acknowledgements {
+    /// The configuration of the acknowledged messages.
+    config: AcknowledgementConfig,
+
+    /// The identifier of the next message.
+    next_id: u64,
+
+    /// The messages waiting for their receipt, by identifier.
+    pending: BTreeMap<MessageId, Pending>,
+
+    /// The recently received messages, by sender.
+    received: HashSet<(Uuid, MessageId)>,
+
+    /// The recently received messages with the time they were first
+    /// received, from the oldest to the newest.
+    received_order: VecDeque<(Instant, Uuid, MessageId)>,
+
+    /// The deliveries that have not been taken yet.
+    deliveries: LinkedList<Delivery>,
+}
+
+impl Acknowledgements {
+    /// Create a new [Acknowledgements] with the given configuration.
+    ///
+    /// The identifiers start at a random value, so the messages of a new
+    /// [Connection](crate::Connection) are not mistaken for the ones of a
+    /// previous one by their targets.
+    pub fn new(config: AcknowledgementConfig) -> Self {
+        Self {
+            config,
+            next_id: rand::random(),
+            pending: BTreeMap::new(),
+            received: HashSet::new(),
+            received_order: VecDeque::new(),
+            deliveries: LinkedList::new(),
+        }
+    }
+
+    /// Create the frame of a new acknowledged message, and returns it with
+    /// the identifier of the message.
+    ///
+    /// The message is only tracked once [track](Self::track) is called.
+    pub fn prepare(
+        &mut self,
+        target: Uuid,
+        flags: Flags,
+        payload: &[u8],
+    ) -> (MessageId, Frame<Request>) {
+        let id = MessageId(self.next_id);
+        self.next_id = self.next_id.wrapping_add(1);
+        let frame = Frame::Data {
+            peer: target,
+            flags: flags | Flags::ACKNOWLEDGED,
+            payload: id.prefix(payload),
+        };
+        (id, frame)
+    }
+
+    /// Wait for the receipt of a message that was just sent.
+    pub fn track(&mut self, id: MessageId, target: Uuid, frame: Frame<Request>) {
+        let pending = Pending {
+            target,
+            frame,
+            sent_at: Instant::now(),
+            attempts: 1,
+        };
+        self.pending.insert(id, pending);
+    }
+
+    /// Record a receipt received from a peer.
+    pub fn receipt(&mut self, peer: Uuid, payload: &[u8]) {
+        let Some((id, _)) = MessageId::split(payload) else {
+            return;
+        };
+        if self
+            .pending
+            .get(&id)
+            .is_some_and(|pending| pending.target == peer)
+        {
+            self.pending.remove(&id);
+            self.deliveries
+                .push_back(Delivery::Acknowledged { target: peer, id });
+        }
+    }
+
+    /// Handle an acknowledged message received from a peer, and returns the
+    /// receipt to send with its payload, or [None] as payload if it was
+    /// already received.
+    ///
+    /// Returns [None] if the message is malformed.
+    pub fn receive(
+        &mut self,
+        peer: Uuid,
+        payload: &[u8],
+    ) -> Option<(Frame<Request>, Option<Vec<u8>>)> {
+        self.receive_at(peer, payload, Instant::now())
+    }
+
+    /// Handle an acknowledged message received from a peer at the given
+    /// time.
+    ///
+    /// See [receive](Self::receive).
+    fn receive_at(
+        &mut self,
+        peer: Uuid,
+        payload: &[u8],
+        now: Instant,
+    ) -> Option<(Frame<Request>, Option<Vec<u8>>)> {
+        let (id, message) = MessageId::split(payload)?;
+        let receipt = Frame::Data {
+            peer,
+            flags: Flags::RECEIPT,
+            payload: id.to_bytes().to_vec(),
+        };
+
+        // The receipt is sent again for a duplicate, since the previous one
+        // may have been lost, but its payload is not received twice.
+        while let Some(&(received_at, sender, id)) = self.received_order.front() {
+            if now.duration_since(received_at) < self.config.duplicate_window {
+                break;
+            }
+            self.received.remove(&(sender, id));
+            self.received_order.pop_front();
+        }
+        if !self.received.insert((peer, id)) {
+            return Some((receipt, None));
+        }
+        self.received_order.push_back((now, peer, id));
+        Some((receipt, Some(message.to_vec())))
+    }
+
+    /// Returns the messages to send again because their receipt didn't come
+    /// in time, and records the failure of the ones sent too many times.
+    pub fn poll(&mut self) -> Vec<Frame<Request>> {
+        self.poll_at(Instant::now())
+    }
+
+    /// Returns the messages to send again at the given time.
+    ///
+    /// See [poll](Self::poll).
+    fn poll_at(&mut self, now: Instant) -> Vec<Frame<Request>> {
+        let mut frames = Vec::new();
+        let mut failed = Vec::new();
+        for (&id, pending) in &mut self.pending {
+            if now.duration_since(pending.sent_at) < self.config.timeout {
+                continue;
+            }
+            if pending.attempts >= self.config.max_attempts {
+                failed.push(id);
+                continue;
+            }
+            pending.sent_at = now;
+            pending.attempts += 1;
+            frames.push(pending.frame.clone());
+        }
+        for id in failed {
+            if let Some(pending) = self.pending.remove(&id) {
+                let target = pending.target;
+                self.deliveries.push_back(Delivery::Failed { target, id });
+            }
+        }
+        frames
+    }
+
+    /// Returns the time the next message should be sent again if its receipt
+    /// doesn't come, if any message is waiting for its receipt.
+    pub fn next_poll(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|pending| pending.sent_at + self.config.timeout)
+            .min()
+    }
+
+    /// Take the deliveries that happened since the last call.
+    pub fn take_deliveries(&mut self) -> LinkedList<Delivery> {
+        std::mem::take(&mut self.deliveries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the acknowledged messages.
+
+    use super::*;
+
+    /// The configuration used by the tests.
+    const CONFIG: AcknowledgementConfig = AcknowledgementConfig {
+        timeout: Duration::from_secs(2),
+        max_attempts: 3,
+        duplicate_window: Duration::from_secs(60),
+    };
+
+    /// The peer used by the tests.
+    const PEER: Uuid = Uuid::from_u128(1);
+
+    /// Send an acknowledged message, and returns its identifier and the time
+    /// it was sent.
+    fn send(acknowledgements: &mut Acknowledgements) -> (MessageId, Instant) {
+        let (id, frame) = acknowledgements.prepare(PEER, Flags::NONE, b"hello");
+        acknowledgements.track(id, PEER, frame);
+        (id, Instant::now())
+    }
+
+    /// A message is sent again each time its receipt doesn't come in time.
+    #[test]
+    fn retransmission() {
+        let mut acknowledgements = Acknowledgements::new(CONFIG);
+        let (id, sent_at) = send(&mut acknowledgements);
+        assert!(acknowledgements.poll_at(sent_at).is_empty());
+
+        let frames = acknowledgements.poll_at(sent_at + CONFIG.timeout);
+        let expected = Frame::Data {
+            peer: PEER,
+            flags: Flags::ACKNOWLEDGED,
+            payload: id.prefix(b"hello"),
+        };
+        assert_eq!(frames, [expected]);
+
+        // The timeout starts again from the retransmission.
+        assert!(acknowledgements
+            .poll_at(sent_at + CONFIG.timeout + Duration::from_secs(1))
+            .is_empty());
+        assert_eq!(
+            acknowledgements.poll_at(sent_at + CONFIG.timeout * 2).len(),
+            1
+        );
+    }
+
+    /// The next poll is due when the receipt of a message times out.
+    #[test]
+    fn next_poll() {
+        let mut acknowledgements = Acknowledgements::new(CONFIG);
+        assert_eq!(acknowledgements.next_poll(), None);
+        let (_, sent_at) = send(&mut acknowledgements);
+        let next_poll = acknowledgements
+            .next_poll()
+            .expect("a receipt should be awaited");
+        assert!(next_poll <= sent_at + CONFIG.timeout);
+        assert_eq!(acknowledgements.poll_at(next_poll).len(), 1);
+    }
+
+    /// A message is lost once it was sent the maximum number of times.
+    #[test]
+    fn give_up() {
+        let mut acknowledgements = Acknowledgements::new(CONFIG);
+        let (id, sent_at) = send(&mut acknowledgements);
+        for attempt in 1..CONFIG.max_attempts {
+            let frames = acknowledgements.poll_at(sent_at + CONFIG.timeout * attempt);
+            assert_eq!(frames.len(), 1);
+        }
+        let frames = acknowledgements.poll_at(sent_at + CONFIG.timeout * CONFIG.max_attempts);
+        assert!(frames.is_empty());
+        let deliveries = acknowledgements.take_deliveries();
+        assert!(deliveries
+            .iter()
+            .eq([&Delivery::Failed { target: PEER, id }]));
+
+        // The lost message is forgotten.
+        assert!(acknowledgements
+            .poll_at(sent_at + CONFIG.timeout * 10)
+            .is_empty());
+        assert!(acknowledgements.take_deliveries().is_empty());
+    }
+
+    /// A receipt stops the retransmissions, unless it comes from another
+    /// peer.
+    #[test]
+    fn receipt() {
+        let mut acknowledgements = Acknowledgements::new(CONFIG);
+        let (id, sent_at) = send(&mut acknowledgements);
+        acknowledgements.receipt(Uuid::from_u128(2), &id.to_bytes());
+        assert!(acknowledgements.take_deliveries().is_empty());
+
+        acknowledgements.receipt(PEER, &id.to_bytes());
+        let deliveries = acknowledgements.take_deliveries();
+        assert!(deliveries
+            .iter()
+            .eq([&Delivery::Acknowledged { target: PEER, id }]));
+        assert!(acknowledgements
+            .poll_at(sent_at + CONFIG.timeout)
+            .is_empty());
+    }
+
+    /// A message received again is acknowledged again but only received once
+    /// during the duplicate window.
+    #[test]
+    fn duplicate_window() {
+        let mut acknowledgements = Acknowledgements::new(CONFIG);
+        let payload = MessageId(42).prefix(b"hello");
+        let receipt = Frame::Data {
+            peer: PEER,
+            flags: Flags::RECEIPT,
+            payload: MessageId(42).to_bytes().to_vec(),
+        };
+        let now = Instant::now();
+        assert_eq!(
+            acknowledgements.receive_at(PEER, &payload, now),
+            Some((receipt.clone(), Some(b"hello".to_vec())))
+        );
+        assert_eq!(
+            acknowledgements.receive_at(PEER, &payload, now + Duration::from_secs(30)),
+            Some((receipt.clone(), None))
+        );
+
+        // The same identifier from another peer is another message.
+        let other = Uuid::from_u128(2);
+        let answer = acknowledgements.receive_at(other, &payload, now);
+        assert_eq!(
+            answer.and_then(|(_, message)| message),
+            Some(b"hello".to_vec())
+        );
+
+        // The message is forgotten after the window.
+        assert_eq!(
+            acknowledgements.receive_at(PEER, &payload, now + CONFIG.duplicate_window),
+            Some((receipt, Some(b"hello".to_vec())))
+        );
+    }
+
+    /// An acknowledged message without identifier is rejected.
+    #[test]
+    fn malformed_message() {
+        let mut acknowledgements = Acknowledgements::new(CONFIG);
+        assert_eq!(acknowledgements.receive(PEER, b"short"), None);
+    }
+}
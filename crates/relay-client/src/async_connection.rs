@@ -0,0 +1,291 @@
+//! A [Connection] driven by the tokio runtime, with an asynchronous API.
+
+use std::borrow::Cow;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::pin::{pin, Pin};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::Stream;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::sleep_until;
+use uuid::Uuid;
+
+use crate::{Connection, ConnectionStatus, QueueFull};
+
+/// The time between two updates of a [Connection] using a custom
+/// [Transport](crate::Transport), which can't wake it up when something is
+/// received.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The number of received messages that can wait to be taken from an
+/// [AsyncConnection] before the [Connection] stops being updated.
+const MESSAGE_CAPACITY: usize = 1024;
+
+/// The state shared by an [AsyncConnection] and the task updating it.
+struct Shared {
+    /// The connection to the relay server.
+    connection: Mutex<Connection>,
+
+    /// The notification sent when the connection should be updated.
+    wake: Arc<Notify>,
+
+    /// The interval between two updates if the transport can't send the
+    /// notification, or [None] if it can.
+    poll_interval: Option<Duration>,
+
+    /// The notification sent after each update, when the outbound queue may
+    /// have room again.
+    updated: Notify,
+}
+
+impl Shared {
+    /// Lock the connection, even if a panic happened while it was locked.
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A connection to a relay server for asynchronous code, created with
+/// [ConnectionBuilder::connect](crate::ConnectionBuilder::connect).
+///
+/// It's a [Connection] updated by a tokio task whenever something is
+/// received from the relay server or sent to it, and when its next ping,
+/// retransmission, key request or reconnection is due, so it shares its
+/// handshake, registration, reconnection and framing. The received messages
+/// are taken from the [Stream] implementation, which should be polled
+/// regularly as the [Connection] stops being updated while too many messages
+/// are waiting.
+///
+/// The task is stopped when the [AsyncConnection] is dropped. This requires
+/// the `async` feature.
+pub struct AsyncConnection {
+    /// The state shared with the task.
+    shared: Arc<Shared>,
+
+    /// The status of the connection after the last update.
+    status: watch::Receiver<ConnectionStatus>,
+
+    /// The messages received from other clients.
+    messages: Receiver<(Uuid, Vec<u8>)>,
+
+    /// The task updating the connection.
+    task: JoinHandle<()>,
+}
+
+impl AsyncConnection {
+    /// Start updating a [Connection] in the background and wait until it's
+    /// connected, or returns an error if the first attempt fails.
+    ///
+    /// The [Connection] is updated when the given notification is sent, and
+    /// at the given interval if its transport can't send it.
+    pub(crate) async fn start(
+        connection: Connection,
+        wake: Arc<Notify>,
+        poll_interval: Option<Duration>,
+    ) -> io::Result<Self> {
+        // Update the connection in a new task.
+        let (status_sender, status) = watch::channel(connection.status());
+        let (sender, messages) = channel(MESSAGE_CAPACITY);
+        let shared = Arc::new(Shared {
+            connection: Mutex::new(connection),
+            wake,
+            poll_interval,
+            updated: Notify::new(),
+        });
+        let task = tokio::spawn(update(Arc::clone(&shared), status_sender, sender));
+        let mut connection = Self {
+            shared,
+            status,
+            messages,
+            task,
+        };
+
+        // Wait for the end of the first attempt.
+        let active = connection
+            .status
+            .wait_for(|status| {
+                matches!(
+                    status,
+                    ConnectionStatus::Active | ConnectionStatus::BackingOff { .. }
+                )
+            })
+            .await
+            .map_err(io::Error::other)?
+            .eq(&ConnectionStatus::Active);
+        if !active {
+            let error = connection.shared.lock().last_error().map(str::to_owned);
+            return Err(io::Error::other(error.unwrap_or_default()));
+        }
+        Ok(connection)
+    }
+
+    /// Get the identifier of the connection.
+    pub fn identifier(&self) -> Option<Uuid> {
+        self.shared.lock().identifier()
+    }
+
+    /// Returns the status of the connection.
+    pub fn status(&self) -> ConnectionStatus {
+        *self.status.borrow()
+    }
+
+    /// Lock the underlying [Connection], to use the features that don't have
+    /// an asynchronous counterpart, like the presence, the rooms or the
+    /// acknowledged messages.
+    ///
+    /// The [Connection] must not be updated through the returned guard, and
+    /// it's not updated while the guard is held. It's updated once the guard
+    /// is dropped, to send what was queued through it.
+    pub fn connection(&self) -> ConnectionGuard<'_> {
+        ConnectionGuard {
+            connection: self.shared.lock(),
+            wake: &self.shared.wake,
+        }
+    }
+
+    /// Send a message to the target client.
+    ///
+    /// Unlike [Connection::send], this waits until there is room in the
+    /// outbound queue instead of applying the
+    /// [QueuePolicy](crate::QueuePolicy) of the connection. It still returns
+    /// a [QueueFull] error if the queue can't be used.
+    pub async fn send<'a>(
+        &self,
+        target_id: Uuid,
+        message: impl Into<Cow<'a, [u8]>>,
+    ) -> Result<(), QueueFull> {
+        let message = message.into();
+        self.wait_for_room().await.send(target_id, message)?;
+        self.shared.wake.notify_one();
+        Ok(())
+    }
+
+    /// Send a durable message to the target client.
+    ///
+    /// See [Connection::send_durable] and [send](Self::send).
+    pub async fn send_durable<'a>(
+        &self,
+        target_id: Uuid,
+        message: impl Into<Cow<'a, [u8]>>,
+    ) -> Result<(), QueueFull> {
+        let message = message.into();
+        self.wait_for_room()
+            .await
+            .send_durable(target_id, message)?;
+        self.shared.wake.notify_one();
+        Ok(())
+    }
+
+    /// Wait until there is room in the outbound queue, and returns the
+    /// locked connection.
+    async fn wait_for_room(&self) -> MutexGuard<'_, Connection> {
+        loop {
+            let mut updated = pin!(self.shared.updated.notified());
+            updated.as_mut().enable();
+            {
+                let connection = self.shared.lock();
+                if !connection.to_send.is_full() {
+                    return connection;
+                }
+            }
+            updated.await;
+        }
+    }
+}
+
+/// The [Connection] of an [AsyncConnection], locked by
+/// [AsyncConnection::connection].
+pub struct ConnectionGuard<'a> {
+    /// The locked connection.
+    connection: MutexGuard<'a, Connection>,
+
+    /// The notification sent when the guard is dropped.
+    wake: &'a Notify,
+}
+
+impl Deref for ConnectionGuard<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+impl DerefMut for ConnectionGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.wake.notify_one();
+    }
+}
+
+impl Stream for AsyncConnection {
+    type Item = (Uuid, Vec<u8>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.messages.poll_recv(cx)
+    }
+}
+
+impl Drop for AsyncConnection {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Update a connection whenever it's woken up, and when its next update is
+/// due.
+async fn update(
+    shared: Arc<Shared>,
+    status: watch::Sender<ConnectionStatus>,
+    messages: Sender<(Uuid, Vec<u8>)>,
+) {
+    loop {
+        // Update the connection, and wake the senders waiting for room in the
+        // outbound queue.
+        let (received, current, next_update) = {
+            let mut connection = shared.lock();
+            let received = connection.update();
+            (received, connection.status(), connection.next_update())
+        };
+        status.send_if_modified(|status| {
+            let changed = *status != current;
+            *status = current;
+            changed
+        });
+        shared.updated.notify_waiters();
+
+        // Hand over the received messages.
+        for message in received {
+            if messages.send(message).await.is_err() {
+                return;
+            }
+        }
+
+        // Wait for something to happen, or until the next update is due.
+        let polled = shared
+            .poll_interval
+            .map(|interval| Instant::now() + interval);
+        let deadline = next_update.into_iter().chain(polled).min();
+        tokio::select! {
+            () = shared.wake.notified() => (),
+            () = async {
+                match deadline {
+                    Some(deadline) => sleep_until(deadline.into()).await,
+                    None => std::future::pending().await,
+                }
+            } => (),
+        }
+    }
+}
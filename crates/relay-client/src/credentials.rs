@@ -0,0 +1,349 @@
+//! The storage of the credentials used to authenticate with a relay server.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+/// The credentials used to authenticate with a relay server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    /// The identifier of the client.
+    pub identifier: Uuid,
+
+    /// The secret key proving the identity of the client.
+    pub secret: Uuid,
+}
+
+impl Credentials {
+    /// Parse the credentials from the 32 bytes sent by the relay server.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() != 32 {
+            return None;
+        }
+        Some(Self {
+            identifier: Uuid::from_slice(&data[..16]).ok()?,
+            secret: Uuid::from_slice(&data[16..]).ok()?,
+        })
+    }
+
+    /// Returns the 32 bytes representation of the credentials.
+    pub fn to_bytes(self) -> [u8; 32] {
+        let mut data = [0; 32];
+        data[..16].copy_from_slice(self.identifier.as_bytes());
+        data[16..].copy_from_slice(self.secret.as_bytes());
+        data
+    }
+}
+
+/// A file storing [Credentials].
+#[derive(Debug, Clone)]
+pub struct CredentialStore {
+    /// The path of the file.
+    path: PathBuf,
+}
+
+impl CredentialStore {
+    /// Create a [CredentialStore] using the file at the given path.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Create a [CredentialStore] using the `.relay-data` file in the home
+    /// directory of the user.
+    pub fn in_home() -> io::Result<Self> {
+        let mut path = home::home_dir().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "could not find home directory")
+        })?;
+        path.push(".relay-data");
+        Ok(Self::new(path))
+    }
+
+    /// Returns the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Load the credentials from the file.
+    ///
+    /// Returns [None] if the file does not exist, and an error with the
+    /// [io::ErrorKind::InvalidData] kind if the file is corrupt.
+    pub fn load(&self) -> io::Result<Option<Credentials>> {
+        let contents = match fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Credentials::from_bytes(&contents).map(Some).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupt credentials in {}", self.path.display()),
+            )
+        })
+    }
+
+    /// Save the credentials to the file.
+    ///
+    /// The file is replaced atomically, and on unix it's only readable and
+    /// writable by the user.
+    pub fn save(&self, credentials: Credentials) -> io::Result<()> {
+        write_private(&self.path, &credentials.to_bytes())
+    }
+
+    /// Returns the path of the file storing the encryption keys that go with
+    /// the credentials.
+    pub fn keys_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".keys");
+        path.into()
+    }
+
+    /// Remove the file, and the file of the encryption keys.
+    ///
+    /// Nothing is done if the files do not exist.
+    pub fn delete(&self) -> io::Result<()> {
+        remove_file(&self.keys_path())?;
+        remove_file(&self.path)
+    }
+}
+
+/// Replace the contents of a file that only the user can read.
+///
+/// The data is first written to a temporary file which then replaces the
+/// old one, so the file is never left half written. On unix, the file is only
+/// readable and writable by the user.
+pub fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    // Create the parent directory if needed.
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Write the data to a temporary file.
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&temp_path)?;
+    #[cfg(unix)]
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+
+    // Replace the old file with the new one.
+    fs::rename(&temp_path, path)
+}
+
+/// Remove a file, doing nothing if it does not exist.
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// A directory containing named identity profiles.
+///
+/// Each profile has its own [CredentialStore], which allows multiple clients
+/// to run on the same machine with different identities.
+#[derive(Debug, Clone)]
+pub struct Profiles {
+    /// The path of the directory.
+    directory: PathBuf,
+}
+
+impl Profiles {
+    /// Create a [Profiles] using the given directory.
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+
+    /// Create a [Profiles] using the `.relay-profiles` directory in the home
+    /// directory of the user.
+    pub fn in_home() -> io::Result<Self> {
+        let mut directory = home::home_dir().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "could not find home directory")
+        })?;
+        directory.push(".relay-profiles");
+        Ok(Self::new(directory))
+    }
+
+    /// Returns the [CredentialStore] of the profile with the given name.
+    ///
+    /// A profile name can only contain ascii letters, digits, `-` and `_`.
+    pub fn store(&self, name: &str) -> io::Result<CredentialStore> {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid profile name: {name:?}"),
+            ));
+        }
+        Ok(CredentialStore::new(self.directory.join(name)))
+    }
+
+    /// Returns the names of the profiles that have stored credentials.
+    pub fn list(&self) -> io::Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if self.store(&name).is_ok() {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Delete the profile with the given name.
+    pub fn delete(&self, name: &str) -> io::Result<()> {
+        self.store(name)?.delete()
+    }
+
+    /// Rotate the identity of the profile with the given name.
+    ///
+    /// The stored credentials and encryption keys are discarded, so a new
+    /// identity will be registered the next time a
+    /// [Connection](crate::Connection) uses this profile. Returns the
+    /// discarded credentials if there were any.
+    pub fn rotate(&self, name: &str) -> io::Result<Option<Credentials>> {
+        let store = self.store(name)?;
+        let credentials = store.load().or_else(|e| match e.kind() {
+            io::ErrorKind::InvalidData => Ok(None),
+            _ => Err(e),
+        })?;
+        store.delete()?;
+        Ok(credentials)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    /// Returns new random credentials.
+    fn random_credentials() -> Credentials {
+        Credentials {
+            identifier: Uuid::new_v4(),
+            secret: Uuid::new_v4(),
+        }
+    }
+
+    /// The saved credentials are loaded back, and replace the previous ones.
+    #[test]
+    fn save_and_load() -> io::Result<()> {
+        let directory = tempdir()?;
+        let store = CredentialStore::new(directory.path().join("nested").join("credentials"));
+        assert_eq!(store.load()?, None);
+
+        let first = random_credentials();
+        store.save(first)?;
+        assert_eq!(store.load()?, Some(first));
+        let second = random_credentials();
+        store.save(second)?;
+        assert_eq!(store.load()?, Some(second));
+
+        // The temporary file was renamed to the credentials file.
+        let mut temp_path = store.path().as_os_str().to_owned();
+        temp_path.push(".tmp");
+        assert!(!Path::new(&temp_path).exists());
+        Ok(())
+    }
+
+    /// The credentials file is only readable and writable by the user.
+    #[cfg(unix)]
+    #[test]
+    fn private_permissions() -> io::Result<()> {
+        let directory = tempdir()?;
+        let path = directory.path().join("credentials");
+
+        // The permissions are restricted even if the file already existed.
+        fs::write(&path, b"previous")?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644))?;
+        CredentialStore::new(&path).save(random_credentials())?;
+        let mode = fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        Ok(())
+    }
+
+    /// A truncated or corrupt credentials file is reported as invalid data.
+    #[test]
+    fn corrupt_file() -> io::Result<()> {
+        let directory = tempdir()?;
+        let store = CredentialStore::new(directory.path().join("credentials"));
+        let data = random_credentials().to_bytes();
+        for contents in [&data[..20], &[], &[data.as_slice(), b"extra"].concat()] {
+            fs::write(store.path(), contents)?;
+            let error = store.load().err().map(|e| e.kind());
+            assert_eq!(error, Some(io::ErrorKind::InvalidData));
+        }
+        Ok(())
+    }
+
+    /// Deleting a store removes the credentials and the keys, and succeeds
+    /// if they don't exist.
+    #[test]
+    fn delete() -> io::Result<()> {
+        let directory = tempdir()?;
+        let store = CredentialStore::new(directory.path().join("credentials"));
+        store.save(random_credentials())?;
+        fs::write(store.keys_path(), b"keys")?;
+        store.delete()?;
+        assert!(!store.path().exists());
+        assert!(!store.keys_path().exists());
+        store.delete()
+    }
+
+    /// Each profile has its own credentials, and only the valid names are
+    /// accepted and listed.
+    #[test]
+    fn profile_selection() -> io::Result<()> {
+        let directory = tempdir()?;
+        let profiles = Profiles::new(directory.path().join("profiles"));
+        assert_eq!(profiles.list()?, Vec::<String>::new());
+        for name in ["", "../escape", "a b", "name.keys"] {
+            let error = profiles.store(name).err().map(|e| e.kind());
+            assert_eq!(error, Some(io::ErrorKind::InvalidInput), "{name:?}");
+        }
+
+        // Save different credentials in two profiles.
+        let (alice, bob) = (random_credentials(), random_credentials());
+        profiles.store("alice")?.save(alice)?;
+        profiles.store("bob_2")?.save(bob)?;
+        fs::write(profiles.store("alice")?.keys_path(), b"keys")?;
+        fs::create_dir(directory.path().join("profiles").join("folder"))?;
+        assert_eq!(profiles.list()?, ["alice", "bob_2"]);
+        assert_eq!(profiles.store("alice")?.load()?, Some(alice));
+        assert_eq!(profiles.store("bob_2")?.load()?, Some(bob));
+
+        // Rotating a profile discards its credentials only.
+        assert_eq!(profiles.rotate("alice")?, Some(alice));
+        assert_eq!(profiles.store("alice")?.load()?, None);
+        assert_eq!(profiles.rotate("alice")?, None);
+        profiles.delete("bob_2")?;
+        assert_eq!(profiles.list()?, Vec::<String>::new());
+        Ok(())
+    }
+}
@@ -0,0 +1,756 @@
+//! The end-to-end encryption of the messages exchanged with other clients.
+//!
+//! Each client has a X25519 key pair, whose public key is sent to its peers
+//! in [KEY_EXCHANGE](Flags::KEY_EXCHANGE) messages. The relay server stamps
+//! these messages with the identifier of their authenticated sender, so each
+//! public key is bound to a client identifier, and it's pinned the first time
+//! it's received: a different key announced later for the same identifier is
+//! rejected. The key of the messages exchanged by two clients is derived from
+//! their Diffie-Hellman shared secret, their identifiers and their public
+//! keys, and the messages are sealed with XChaCha20-Poly1305.
+//!
+//! The sealed messages authenticate their sender and target, and the flags
+//! and identifier they were sent with, so the relay server can remove the
+//! identifier of an acknowledged message it acknowledges itself, but can't
+//! swap it or add flags. The nonces received from each peer are remembered
+//! to reject the messages replayed by the relay server.
+
+use std::collections::{HashMap, HashSet, LinkedList, VecDeque};
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce};
+use log::warn;
+use rand::rngs::OsRng;
+use relay_protocol::{Flags, Frame, MessageId, Request};
+use uuid::Uuid;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::credentials::write_private;
+
+/// The context of the derivation of the keys shared by two clients.
+const KEY_CONTEXT: &str = "relay-client 2024-03-01 end-to-end message key";
+
+/// The time to wait before asking again for the key of a peer.
+const KEY_REQUEST_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The length of the random nonce at the start of an encrypted payload.
+const NONCE_LENGTH: usize = 24;
+
+/// The maximum number of messages waiting for the key of a single peer.
+const MAX_WAITING: usize = 1024;
+
+/// The maximum number of nonces remembered for each peer to reject the
+/// replayed messages.
+const MAX_SEEN: usize = 4096;
+
+/// The flags of a message authenticated with its content.
+const SEALED_FLAGS: Flags = Flags(Flags::ACKNOWLEDGED.0 | Flags::DURABLE.0);
+
+/// The kind of a key exchange message that asks the peer for its key.
+const KEY_REQUEST: u8 = 0;
+
+/// The kind of a key exchange message that answers a [KEY_REQUEST].
+const KEY_ANSWER: u8 = 1;
+
+/// The length of a public key.
+const KEY_LENGTH: usize = 32;
+
+/// The messages waiting for the key of a peer.
+#[derive(Default)]
+struct Waiting {
+    /// The last time the key was asked to the peer, if it was.
+    requested_at: Option<Instant>,
+
+    /// The frames to encrypt once the key is known, in order.
+    frames: LinkedList<Frame<Request>>,
+}
+
+/// The last nonces received from a peer.
+#[derive(Default)]
+struct Seen {
+    /// The remembered nonces.
+    nonces: HashSet<[u8; NONCE_LENGTH]>,
+
+    /// The remembered nonces, from the oldest to the newest.
+    order: VecDeque<[u8; NONCE_LENGTH]>,
+}
+
+impl Seen {
+    /// Remember a nonce, and returns false if it was already received.
+    fn insert(&mut self, nonce: [u8; NONCE_LENGTH]) -> bool {
+        if !self.nonces.insert(nonce) {
+            return false;
+        }
+        self.order.push_back(nonce);
+        if self.order.len() > MAX_SEEN {
+            if let Some(oldest) = self.order.pop_front() {
+                self.nonces.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// The key pair of a client and the pinned public keys of its peers.
+pub struct Encryption {
+    /// The secret key of the client.
+    secret: StaticSecret,
+
+    /// The public key of the client.
+    public: PublicKey,
+
+    /// The file where the keys are stored, if they are persistent.
+    path: Option<PathBuf>,
+
+    /// The pinned public keys of the peers.
+    peers: HashMap<Uuid, PublicKey>,
+
+    /// The ciphers of the messages exchanged with each peer.
+    ciphers: HashMap<Uuid, XChaCha20Poly1305>,
+
+    /// The messages waiting for the key of each peer.
+    waiting: HashMap<Uuid, Waiting>,
+
+    /// The last nonces received from each peer.
+    seen: HashMap<Uuid, Seen>,
+}
+
+impl Encryption {
+    /// Load the keys from the given file, or generate a new key pair and
+    /// save it if there is none.
+    ///
+    /// The file contains the secret key followed by the identifier and the
+    /// public key of each pinned peer. Without a file, a new key pair is
+    /// generated and nothing is saved.
+    pub fn load(path: Option<PathBuf>) -> io::Result<Self> {
+        let contents = match &path {
+            Some(path) => match std::fs::read(path) {
+                Ok(contents) => Some(contents),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            },
+            None => None,
+        };
+
+        // Read the stored keys if there are some.
+        let mut peers = HashMap::new();
+        let generated = contents.is_none();
+        let secret = match contents {
+            Some(contents) => {
+                let corrupt = || {
+                    let path = path.as_deref().unwrap_or_else(|| "".as_ref());
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("corrupt encryption keys in {}", path.display()),
+                    )
+                };
+                let (secret, entries) = contents
+                    .split_first_chunk::<KEY_LENGTH>()
+                    .ok_or_else(corrupt)?;
+                if entries.len() % (16 + KEY_LENGTH) != 0 {
+                    return Err(corrupt());
+                }
+                for entry in entries.chunks_exact(16 + KEY_LENGTH) {
+                    let (peer, key) = entry.split_at(16);
+                    let key: [u8; KEY_LENGTH] = key.try_into().map_err(|_| corrupt())?;
+                    let peer = Uuid::from_slice(peer).map_err(|_| corrupt())?;
+                    peers.insert(peer, PublicKey::from(key));
+                }
+                StaticSecret::from(*secret)
+            }
+            None => StaticSecret::random_from_rng(OsRng),
+        };
+
+        // Create the encryption and save the new key pair.
+        let encryption = Self {
+            public: PublicKey::from(&secret),
+            secret,
+            path,
+            peers,
+            ciphers: HashMap::new(),
+            waiting: HashMap::new(),
+            seen: HashMap::new(),
+        };
+        if generated {
+            encryption.save()?;
+        }
+        Ok(encryption)
+    }
+
+    /// Save the keys to the file, if any.
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut data = Vec::with_capacity(KEY_LENGTH + self.peers.len() * (16 + KEY_LENGTH));
+        data.extend_from_slice(self.secret.as_bytes());
+        for (peer, key) in &self.peers {
+            data.extend_from_slice(peer.as_bytes());
+            data.extend_from_slice(key.as_bytes());
+        }
+        write_private(path, &data)
+    }
+
+    /// Save the keys to the file, logging the failures.
+    fn save_or_warn(&self) {
+        if let Err(e) = self.save() {
+            if let Some(path) = &self.path {
+                warn!("failed to save encryption keys to {}: {e}", path.display());
+            }
+        }
+    }
+
+    /// Returns the public key of the client.
+    pub const fn public_key(&self) -> PublicKey {
+        self.public
+    }
+
+    /// Returns the pinned public key of a peer.
+    pub fn peer_key(&self, peer: Uuid) -> Option<PublicKey> {
+        self.peers.get(&peer).copied()
+    }
+
+    /// Forget the public key of a peer, so the next key it announces is
+    /// trusted.
+    pub fn forget(&mut self, peer: Uuid) {
+        self.ciphers.remove(&peer);
+        if self.peers.remove(&peer).is_some() {
+            self.save_or_warn();
+        }
+    }
+
+    /// Returns the cipher of the messages exchanged with a peer, or [None]
+    /// if its key is unknown.
+    fn cipher(&mut self, identifier: Uuid, peer: Uuid) -> Option<&XChaCha20Poly1305> {
+        if !self.ciphers.contains_key(&peer) {
+            let public = self.peers.get(&peer)?;
+            let shared = self.secret.diffie_hellman(public);
+            if !shared.was_contributory() {
+                return None;
+            }
+
+            // Bind the key to the identifiers and public keys of both clients,
+            // always in the same order.
+            let mut parties = [(identifier, self.public), (peer, *public)];
+            parties.sort_by_key(|(identifier, _)| *identifier);
+            let mut material = shared.as_bytes().to_vec();
+            for (identifier, public) in parties {
+                material.extend_from_slice(identifier.as_bytes());
+                material.extend_from_slice(public.as_bytes());
+            }
+            let key = blake3::derive_key(KEY_CONTEXT, &material);
+            self.ciphers
+                .insert(peer, XChaCha20Poly1305::new(&key.into()));
+        }
+        self.ciphers.get(&peer)
+    }
+
+    /// Returns the key exchange frame announcing the public key of the client
+    /// to a peer.
+    fn key_exchange(&self, peer: Uuid, kind: u8) -> Frame<Request> {
+        let mut payload = Vec::with_capacity(1 + KEY_LENGTH);
+        payload.push(kind);
+        payload.extend_from_slice(self.public.as_bytes());
+        Frame::Data {
+            peer,
+            flags: Flags::KEY_EXCHANGE,
+            payload,
+        }
+    }
+
+    /// Encrypt a frame about to be sent by the client with the given
+    /// identifier.
+    ///
+    /// The control frames, the receipts and the key exchanges are returned
+    /// unchanged. If the key of the target is unknown, the frame is kept
+    /// until it is received and [None] is returned.
+    pub fn seal(&mut self, identifier: Uuid, frame: Frame<Request>) -> Option<Frame<Request>> {
+        let (peer, flags, payload) = match frame {
+            Frame::Data {
+                peer,
+                flags,
+                payload,
+            } if !flags.contains(Flags::RECEIPT)
+                && !flags.contains(Flags::KEY_EXCHANGE)
+                && !flags.contains(Flags::ENCRYPTED) =>
+            {
+                (peer, flags, payload)
+            }
+            frame => return Some(frame),
+        };
+
+        // Wait for the key of the target if it's unknown. An acknowledged
+        // message sent again while waiting is only kept once.
+        let Some(cipher) = self.cipher(identifier, peer) else {
+            let waiting = self.waiting.entry(peer).or_default();
+            let frame = Frame::Data {
+                peer,
+                flags,
+                payload,
+            };
+            let id = acknowledged_id(&frame);
+            if id.is_some()
+                && waiting
+                    .frames
+                    .iter()
+                    .any(|frame| acknowledged_id(frame) == id)
+            {
+                return None;
+            }
+            if waiting.frames.len() >= MAX_WAITING {
+                warn!("too many messages waiting for the key of {peer}, dropping the oldest");
+                waiting.frames.pop_front();
+            }
+            waiting.frames.push_back(frame);
+            return None;
+        };
+
+        // Encrypt the message, leaving the identifier of an acknowledged
+        // message readable by the relay server, and authenticating it with
+        // the flags in the context of the message.
+        let (header, message) = match flags.contains(Flags::ACKNOWLEDGED) {
+            true => payload.split_at(MessageId::LENGTH.min(payload.len())),
+            false => payload.split_at(0),
+        };
+        let nonce: [u8; NONCE_LENGTH] = rand::random();
+        let mut context = Vec::with_capacity(1 + header.len());
+        context.push(flags.intersection(SEALED_FLAGS).0);
+        context.extend_from_slice(header);
+        let aad = associated_data(identifier, peer, &context);
+        let sealed = match cipher.encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: message,
+                aad: &aad,
+            },
+        ) {
+            Ok(sealed) => sealed,
+            Err(_) => {
+                warn!("failed to encrypt a message to {peer}");
+                return None;
+            }
+        };
+        let mut encrypted =
+            Vec::with_capacity(header.len() + NONCE_LENGTH + context.len() + sealed.len());
+        encrypted.extend_from_slice(header);
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&context);
+        encrypted.extend_from_slice(&sealed);
+        Some(Frame::Data {
+            peer,
+            flags: flags | Flags::ENCRYPTED,
+            payload: encrypted,
+        })
+    }
+
+    /// Decrypt the payload of a message received by the client with the
+    /// given identifier.
+    ///
+    /// Returns [None] if the message can't be authenticated, if it was
+    /// already received, or if its flags or identifier differ from the ones
+    /// it was sent with, except for the identifier and the
+    /// [ACKNOWLEDGED](Flags::ACKNOWLEDGED) flag removed by the relay server
+    /// when it acknowledges the message itself. If the key of the peer is
+    /// unknown, it's asked during the next [poll](Self::poll).
+    pub fn open(
+        &mut self,
+        identifier: Uuid,
+        peer: Uuid,
+        flags: Flags,
+        payload: &[u8],
+    ) -> Option<Vec<u8>> {
+        let Some(cipher) = self.cipher(identifier, peer) else {
+            self.waiting.entry(peer).or_default();
+            return None;
+        };
+        let (header, sealed) = match flags.contains(Flags::ACKNOWLEDGED) {
+            true => payload.split_at_checked(MessageId::LENGTH)?,
+            false => payload.split_at(0),
+        };
+        let (nonce, sealed) = sealed.split_first_chunk::<NONCE_LENGTH>()?;
+
+        // Check the flags and identifier against the ones the message was
+        // sent with.
+        let (&sent_flags, sealed) = sealed.split_first()?;
+        let sent_flags = Flags(sent_flags);
+        let (sent_header, sealed) = match sent_flags.contains(Flags::ACKNOWLEDGED) {
+            true => sealed.split_at_checked(MessageId::LENGTH)?,
+            false => sealed.split_at(0),
+        };
+        let flags = flags.intersection(SEALED_FLAGS);
+        if sent_flags.difference(SEALED_FLAGS) != Flags::NONE
+            || flags.difference(sent_flags) != Flags::NONE
+            || (!header.is_empty() && header != sent_header)
+        {
+            return None;
+        }
+        let mut context = Vec::with_capacity(1 + sent_header.len());
+        context.push(sent_flags.0);
+        context.extend_from_slice(sent_header);
+
+        // Decrypt the message, and reject it if it was already received.
+        let aad = associated_data(peer, identifier, &context);
+        let message = cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: sealed,
+                    aad: &aad,
+                },
+            )
+            .ok()?;
+        if !self.seen.entry(peer).or_default().insert(*nonce) {
+            warn!("received a replayed message from {peer}");
+            return None;
+        }
+        let mut opened = Vec::with_capacity(header.len() + message.len());
+        opened.extend_from_slice(header);
+        opened.extend_from_slice(&message);
+        Some(opened)
+    }
+
+    /// Handle a key exchange message received from a peer.
+    ///
+    /// Returns the frames to send: the answer to a key request and the
+    /// messages that were waiting for the key, to encrypt now. Returns
+    /// [None] if the message is malformed, or if it announces a key different
+    /// from the pinned key of the peer.
+    pub fn exchange(&mut self, peer: Uuid, payload: &[u8]) -> Option<LinkedList<Frame<Request>>> {
+        let (&kind, key) = payload.split_first()?;
+        let key = PublicKey::from(<[u8; KEY_LENGTH]>::try_from(key).ok()?);
+        if kind != KEY_REQUEST && kind != KEY_ANSWER
+            || !self.secret.diffie_hellman(&key).was_contributory()
+        {
+            return None;
+        }
+
+        // Pin the key of the peer, rejecting a different one.
+        match self.peers.get(&peer) {
+            Some(pinned) if *pinned != key => {
+                warn!("{peer} announced a different encryption key, ignoring it");
+                return None;
+            }
+            Some(_) => (),
+            None => {
+                self.peers.insert(peer, key);
+                self.save_or_warn();
+            }
+        }
+
+        // Answer the request and release the waiting messages.
+        let mut frames = LinkedList::new();
+        if kind == KEY_REQUEST {
+            frames.push_back(self.key_exchange(peer, KEY_ANSWER));
+        }
+        if let Some(waiting) = self.waiting.remove(&peer) {
+            frames.extend(waiting.frames);
+        }
+        Some(frames)
+    }
+
+    /// Returns the time the key of a peer should be asked again, if messages
+    /// are waiting for a key.
+    pub fn next_poll(&self) -> Option<Instant> {
+        self.waiting
+            .values()
+            .map(|waiting| {
+                waiting
+                    .requested_at
+                    .map_or_else(Instant::now, |requested_at| {
+                        requested_at + KEY_REQUEST_INTERVAL
+                    })
+            })
+            .min()
+    }
+
+    /// Returns the key requests to send to the peers whose key is needed.
+    pub fn poll(&mut self) -> Vec<Frame<Request>> {
+        let now = Instant::now();
+        let mut peers = Vec::new();
+        self.waiting.retain(|&peer, waiting| {
+            let due = waiting.requested_at.is_none_or(|requested_at| {
+                now.duration_since(requested_at) >= KEY_REQUEST_INTERVAL
+            });
+            if due {
+                waiting.requested_at = Some(now);
+                peers.push(peer);
+            }
+
+            // Only keep asking for the key if messages are waiting for it.
+            !waiting.frames.is_empty()
+        });
+        peers
+            .into_iter()
+            .map(|peer| self.key_exchange(peer, KEY_REQUEST))
+            .collect()
+    }
+}
+
+/// Returns the identifier of an acknowledged message, if it is one.
+fn acknowledged_id(frame: &Frame<Request>) -> Option<MessageId> {
+    match frame {
+        Frame::Data { flags, payload, .. } if flags.contains(Flags::ACKNOWLEDGED) => {
+            MessageId::split(payload).map(|(id, _)| id)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the data authenticated with a message, binding it to its sender,
+/// its target, and the context of flags and identifier it was sent with.
+fn associated_data(sender: Uuid, target: Uuid, context: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(32 + context.len());
+    data.extend_from_slice(sender.as_bytes());
+    data.extend_from_slice(target.as_bytes());
+    data.extend_from_slice(context);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the end-to-end encryption.
+
+    use super::*;
+
+    /// The identifier of the first client.
+    const ALICE: Uuid = Uuid::from_u128(1);
+
+    /// The identifier of the second client.
+    const BOB: Uuid = Uuid::from_u128(2);
+
+    /// Returns a new key pair that isn't saved.
+    fn encryption() -> Encryption {
+        Encryption::load(None).expect("keys without a file should be generated")
+    }
+
+    /// Returns the payload of a key exchange frame.
+    fn key_payload(frame: Frame<Request>) -> Vec<u8> {
+        match frame {
+            Frame::Data { payload, .. } => payload,
+            Frame::Control(_) => Vec::new(),
+        }
+    }
+
+    /// Returns two clients that exchanged their keys.
+    fn exchanged() -> (Encryption, Encryption) {
+        let mut alice = encryption();
+        let mut bob = encryption();
+        let request = key_payload(alice.key_exchange(BOB, KEY_REQUEST));
+        let answer = bob.exchange(ALICE, &request).unwrap_or_default();
+        assert_eq!(answer.len(), 1);
+        let answer = answer.into_iter().map(key_payload).next();
+        assert!(alice.exchange(BOB, &answer.unwrap_or_default()).is_some());
+        (alice, bob)
+    }
+
+    /// Returns the flags and payload of a sealed frame.
+    fn sealed(encryption: &mut Encryption, flags: Flags, payload: &[u8]) -> (Flags, Vec<u8>) {
+        let frame = Frame::Data {
+            peer: BOB,
+            flags,
+            payload: payload.to_vec(),
+        };
+        match encryption.seal(ALICE, frame) {
+            Some(Frame::Data { flags, payload, .. }) => (flags, payload),
+            frame => panic!("the frame should be sealed, got {frame:?}"),
+        }
+    }
+
+    /// A key exchange pins the key of each client for the other one.
+    #[test]
+    fn key_exchange() {
+        let (alice, bob) = exchanged();
+        assert_eq!(alice.peer_key(BOB), Some(bob.public_key()));
+        assert_eq!(bob.peer_key(ALICE), Some(alice.public_key()));
+    }
+
+    /// A sealed message is opened by its target only.
+    #[test]
+    fn round_trip() {
+        let (mut alice, mut bob) = exchanged();
+        let (flags, payload) = sealed(&mut alice, Flags::DURABLE, b"hello");
+        assert_eq!(flags, Flags::DURABLE | Flags::ENCRYPTED);
+        assert!(!payload.windows(5).any(|window| window == b"hello"));
+        assert_eq!(
+            bob.open(BOB, ALICE, flags, &payload),
+            Some(b"hello".to_vec())
+        );
+
+        // The message can't be opened as if it came from another client.
+        assert_eq!(bob.open(BOB, Uuid::from_u128(3), flags, &payload), None);
+    }
+
+    /// The identifier of an acknowledged message stays readable.
+    #[test]
+    fn acknowledged_round_trip() {
+        let (mut alice, mut bob) = exchanged();
+        let message = MessageId(9).prefix(b"hello");
+        let (flags, payload) = sealed(&mut alice, Flags::ACKNOWLEDGED, &message);
+        assert_eq!(
+            MessageId::split(&payload).map(|(id, _)| id),
+            Some(MessageId(9))
+        );
+        assert_eq!(bob.open(BOB, ALICE, flags, &payload), Some(message));
+    }
+
+    /// The identifier of an acknowledged message can't be swapped with the
+    /// one of another message.
+    #[test]
+    fn swapped_header() {
+        let (mut alice, mut bob) = exchanged();
+        let first = MessageId(1).prefix(b"first");
+        let second = MessageId(2).prefix(b"second");
+        let (flags, first_sealed) = sealed(&mut alice, Flags::ACKNOWLEDGED, &first);
+        let (_, second_sealed) = sealed(&mut alice, Flags::ACKNOWLEDGED, &second);
+        let mut swapped = [first_sealed.clone(), second_sealed.clone()];
+        let (first_swapped, second_swapped) = swapped.split_at_mut(1);
+        first_swapped[0][..MessageId::LENGTH]
+            .swap_with_slice(&mut second_swapped[0][..MessageId::LENGTH]);
+        for payload in &swapped {
+            assert_eq!(bob.open(BOB, ALICE, flags, payload), None);
+        }
+        assert_eq!(bob.open(BOB, ALICE, flags, &first_sealed), Some(first));
+        assert_eq!(bob.open(BOB, ALICE, flags, &second_sealed), Some(second));
+    }
+
+    /// The flags of a message can't be added by the relay server, which can
+    /// only remove the identifier of an acknowledged message.
+    #[test]
+    fn changed_flags() {
+        let (mut alice, mut bob) = exchanged();
+        let (flags, payload) = sealed(&mut alice, Flags::NONE, b"hello");
+        assert_eq!(bob.open(BOB, ALICE, flags | Flags::DURABLE, &payload), None);
+        let mut acknowledged = MessageId(3).prefix(b"");
+        acknowledged.extend_from_slice(&payload);
+        let flags = flags | Flags::ACKNOWLEDGED;
+        assert_eq!(bob.open(BOB, ALICE, flags, &acknowledged), None);
+
+        // A durable acknowledged message is stored without its identifier.
+        let message = MessageId(4).prefix(b"stored");
+        let sent = Flags::ACKNOWLEDGED | Flags::DURABLE;
+        let (flags, payload) = sealed(&mut alice, sent, &message);
+        let stored = &payload[MessageId::LENGTH..];
+        let flags = flags.difference(Flags::ACKNOWLEDGED);
+        assert_eq!(
+            bob.open(BOB, ALICE, flags, stored),
+            Some(b"stored".to_vec())
+        );
+    }
+
+    /// A message is only opened once.
+    #[test]
+    fn replayed_message() {
+        let (mut alice, mut bob) = exchanged();
+        let (flags, payload) = sealed(&mut alice, Flags::NONE, b"hello");
+        assert_eq!(
+            bob.open(BOB, ALICE, flags, &payload),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(bob.open(BOB, ALICE, flags, &payload), None);
+
+        // The same content sealed again is a different message.
+        let (flags, payload) = sealed(&mut alice, Flags::NONE, b"hello");
+        assert_eq!(
+            bob.open(BOB, ALICE, flags, &payload),
+            Some(b"hello".to_vec())
+        );
+    }
+
+    /// A tampered or truncated message is rejected.
+    #[test]
+    fn tampered_message() {
+        let (mut alice, mut bob) = exchanged();
+        let (flags, payload) = sealed(&mut alice, Flags::NONE, b"hello");
+        for index in 0..payload.len() {
+            let mut tampered = payload.clone();
+            tampered[index] ^= 1;
+            assert_eq!(bob.open(BOB, ALICE, flags, &tampered), None);
+        }
+        for length in 0..payload.len() {
+            assert_eq!(bob.open(BOB, ALICE, flags, &payload[..length]), None);
+        }
+    }
+
+    /// A different key announced for a pinned peer is rejected until the
+    /// pinned key is forgotten.
+    #[test]
+    fn pinned_key() {
+        let (mut alice, bob) = exchanged();
+        let mallory = encryption();
+        let announce = key_payload(mallory.key_exchange(ALICE, KEY_ANSWER));
+        assert_eq!(alice.exchange(BOB, &announce), None);
+        assert_eq!(alice.peer_key(BOB), Some(bob.public_key()));
+
+        alice.forget(BOB);
+        assert!(alice.exchange(BOB, &announce).is_some());
+        assert_eq!(alice.peer_key(BOB), Some(mallory.public_key()));
+    }
+
+    /// The key pair and the pinned keys are kept across restarts.
+    #[test]
+    fn persistent_keys() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("relay-keys-{}", Uuid::new_v4()));
+        let mut alice = Encryption::load(Some(path.clone()))?;
+        let bob = encryption();
+        let answer = key_payload(bob.key_exchange(ALICE, KEY_ANSWER));
+        assert!(alice.exchange(BOB, &answer).is_some());
+
+        let mut reloaded = Encryption::load(Some(path.clone()))?;
+        std::fs::remove_file(path)?;
+        assert_eq!(reloaded.public_key(), alice.public_key());
+        assert_eq!(reloaded.peer_key(BOB), Some(bob.public_key()));
+
+        // A different key is still rejected after the restart.
+        let mallory = encryption();
+        let announce = key_payload(mallory.key_exchange(ALICE, KEY_ANSWER));
+        assert_eq!(reloaded.exchange(BOB, &announce), None);
+        Ok(())
+    }
+
+    /// A malformed key exchange is rejected.
+    #[test]
+    fn malformed_key_exchange() {
+        let mut alice = encryption();
+        assert_eq!(alice.exchange(BOB, &[]), None);
+        assert_eq!(alice.exchange(BOB, &[KEY_ANSWER; 10]), None);
+        let mut unknown = key_payload(encryption().key_exchange(ALICE, KEY_ANSWER));
+        unknown[0] = 0xff;
+        assert_eq!(alice.exchange(BOB, &unknown), None);
+
+        // A low order key is not contributory.
+        let mut low_order = vec![KEY_ANSWER];
+        low_order.extend_from_slice(&[0; KEY_LENGTH]);
+        assert_eq!(alice.exchange(BOB, &low_order), None);
+        assert_eq!(alice.peer_key(BOB), None);
+    }
+
+    /// An acknowledged message sent again while the key of its target is
+    /// unknown is only sent once the key is received.
+    #[test]
+    fn waiting_retransmissions() {
+        let mut alice = encryption();
+        let bob = encryption();
+        let message = Frame::Data {
+            peer: BOB,
+            flags: Flags::ACKNOWLEDGED,
+            payload: MessageId(7).prefix(b"hello"),
+        };
+        assert_eq!(alice.seal(ALICE, message.clone()), None);
+        assert_eq!(alice.seal(ALICE, message.clone()), None);
+
+        // Another message is still kept.
+        let other = Frame::Data {
+            peer: BOB,
+            flags: Flags::NONE,
+            payload: b"world".to_vec(),
+        };
+        assert_eq!(alice.seal(ALICE, other.clone()), None);
+
+        let answer = key_payload(bob.key_exchange(ALICE, KEY_ANSWER));
+        let frames = alice.exchange(BOB, &answer);
+        assert_eq!(frames, Some(LinkedList::from([message, other])));
+    }
+}
@@ -0,0 +1,124 @@
+//! The keepalive used to detect dead connections to the relay server.
+
+use std::time::{Duration, Instant};
+
+/// The configuration of the keepalive of a [Connection](crate::Connection).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    /// The interval between two pings sent to the relay server.
+    pub interval: Duration,
+
+    /// The time without receiving anything from the relay server after which
+    /// the connection is considered dead, which is also the time the relay
+    /// server has to answer the handshake.
+    ///
+    /// This should be longer than the interval, otherwise an healthy
+    /// connection could be considered dead.
+    pub timeout: Duration,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Keeps track of the pings sent on an active connection.
+#[derive(Debug)]
+pub struct Heartbeat {
+    /// The configuration of the keepalive.
+    config: KeepaliveConfig,
+
+    /// The time of the next ping.
+    next_ping: Instant,
+
+    /// The payload and sending time of the last ping without a pong.
+    pending: Option<(u64, Instant)>,
+
+    /// The payload of the last ping.
+    counter: u64,
+
+    /// The time something was last received from the relay server.
+    last_received: Instant,
+
+    /// The last measured round-trip time.
+    pub rtt: Option<Duration>,
+}
+
+impl Heartbeat {
+    /// Create a new [Heartbeat] with the given configuration.
+    pub fn new(config: KeepaliveConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            config,
+            next_ping: now,
+            pending: None,
+            counter: 0,
+            last_received: now,
+            rtt: None,
+        }
+    }
+
+    /// Restart the keepalive for a new connection.
+    pub fn reset(&mut self) {
+        let now = Instant::now();
+        self.next_ping = now;
+        self.pending = None;
+        self.last_received = now;
+    }
+
+    /// Returns the time without receiving anything after which the
+    /// connection is considered dead.
+    pub const fn timeout(&self) -> Duration {
+        self.config.timeout
+    }
+
+    /// Record that something was received from the relay server.
+    pub fn received(&mut self) {
+        self.last_received = Instant::now();
+    }
+
+    /// Record a pong received from the relay server.
+    pub fn pong(&mut self, payload: &[u8]) {
+        self.received();
+        let Some((counter, sent_at)) = self.pending else {
+            return;
+        };
+        if payload == counter.to_be_bytes() {
+            self.rtt = Some(sent_at.elapsed());
+            self.pending = None;
+        }
+    }
+
+    /// Returns the time the keepalive should be polled again, to send the
+    /// next ping or to detect that the connection is dead.
+    pub fn next_poll(&self) -> Instant {
+        self.next_ping.min(self.last_received + self.config.timeout)
+    }
+
+    /// Returns the payload of the ping to send, if it's time to send one.
+    ///
+    /// Returns an error if the connection is considered dead.
+    pub fn poll(&mut self) -> Result<Option<Vec<u8>>, String> {
+        // Check if the connection is dead.
+        let silence = self.last_received.elapsed();
+        if silence > self.config.timeout {
+            return Err(format!(
+                "relay connection timed out: nothing received for {silence:.1?}"
+            ));
+        }
+
+        // Check if a new ping should be sent.
+        let now = Instant::now();
+        if now < self.next_ping {
+            return Ok(None);
+        }
+        self.next_ping = now + self.config.interval;
+        self.counter = self.counter.wrapping_add(1);
+        self.pending = Some((self.counter, now));
+        Ok(Some(self.counter.to_be_bytes().to_vec()))
+    }
+}
@@ -1,341 +1,1032 @@
 //! A library containing a client to use a relay server.
 
 use std::borrow::Cow;
-use std::collections::LinkedList;
-use std::fs;
+use std::collections::{HashSet, LinkedList};
 use std::io::{self};
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::path::PathBuf;
-use std::sync::Mutex;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "async")]
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use log::warn;
-use mio::net::TcpStream;
-use rand::seq::SliceRandom;
-use tungstenite::handshake::MidHandshake;
-use tungstenite::stream::MaybeTlsStream;
-use tungstenite::{ClientHandshake, HandshakeError, Message, WebSocket};
+use relay_protocol::{self as protocol, Flags, Hello, Request, Welcome};
+pub use relay_protocol::{
+    Capabilities, InvalidRoomName, MessageId, Notification, RoomError, RoomName, PROTOCOL_VERSION,
+};
+#[cfg(feature = "async")]
+use tokio::sync::Notify;
 use uuid::Uuid;
+pub use x25519_dalek::PublicKey;
+
+use self::acknowledgements::Acknowledgements;
+pub use self::acknowledgements::{AcknowledgementConfig, Delivery};
+#[cfg(feature = "async")]
+use self::async_connection::POLL_INTERVAL;
+#[cfg(feature = "async")]
+pub use self::async_connection::{AsyncConnection, ConnectionGuard};
+pub use self::credentials::{CredentialStore, Credentials, Profiles};
+use self::encryption::Encryption;
+use self::keepalive::Heartbeat;
+pub use self::keepalive::KeepaliveConfig;
+use self::queue::OutboundQueue;
+pub use self::queue::{QueueConfig, QueueFull, QueuePolicy};
+use self::status::Retry;
+pub use self::status::{Backoff, ConnectionStatus};
+#[cfg(feature = "async")]
+use self::transport::AsyncWebSocketTransport;
+pub use self::transport::{
+    Frame, Link, LinkState, LoopbackHub, LoopbackTransport, Transport, WebSocketTransport,
+};
+
+mod acknowledgements;
+#[cfg(feature = "async")]
+mod async_connection;
+mod credentials;
+mod encryption;
+mod keepalive;
+mod queue;
+mod status;
+mod transport;
+
+/// The maximum number of status changes kept by a [Connection].
+pub const MAX_STATUS_CHANGES: usize = 64;
+
+/// The maximum number of notifications kept by a [Connection].
+pub const MAX_NOTIFICATIONS: usize = 1024;
 
 /// The state of a [Connection].
-#[derive(Debug)]
 enum ConnectionState {
     /// The [Connection] is not connected.
     Disconnected,
 
-    /// The underlying [TcpStream] is connecting.
-    Connecting(TcpStream, Instant),
-
-    /// The underlying [TcpStream] is connected.
-    Connected(TcpStream),
+    /// The [Connection] failed and waits until the given instant before
+    /// trying to connect again.
+    BackingOff(Instant),
 
-    /// The websocket handshake is in progress.
-    Handshaking(MidHandshake<ClientHandshake<MaybeTlsStream<TcpStream>>>),
+    /// The [Link] to the relay server is being opened.
+    Opening(Box<dyn Link>, LinkState),
 
-    /// The websocket handshake is finished.
-    Handshaked(WebSocket<MaybeTlsStream<TcpStream>>),
+    /// The [Link] to the relay server is open.
+    Opened(Box<dyn Link>),
 
-    /// The [Connection] is registering with the relay server.
-    Registering(WebSocket<MaybeTlsStream<TcpStream>>),
+    /// The [Connection] waits for the welcome message of the relay server
+    /// until the given instant.
+    Greeting(Box<dyn Link>, Instant),
 
     /// The [Connection] is connected.
-    Active(WebSocket<MaybeTlsStream<TcpStream>>),
+    Active(Box<dyn Link>),
 }
 
-/// A connection to a relay server.
-pub struct Connection {
-    /// The address list corresponding to the relay server.
-    address_list: Vec<SocketAddr>,
+/// The way a [ConnectionBuilder] reaches the relay server.
+enum Target {
+    /// A websocket to the given URL.
+    Url(String),
 
-    /// The domain of the relay server.
-    domain: String,
+    /// A custom [Transport].
+    Transport(Box<dyn Transport>),
+}
 
-    /// The path to the file where the identifier and secret key are stored.
-    data_path: PathBuf,
+/// A builder for a [Connection].
+pub struct ConnectionBuilder {
+    /// The way to reach the relay server.
+    target: Target,
 
-    /// The identifier of the connection for the relay server.
-    identifier: Option<Uuid>,
+    /// The path to the file where the credentials are stored.
+    credentials_path: Option<PathBuf>,
 
-    /// The secret key used to authenticate with the relay server.
-    secret: Option<Uuid>,
+    /// The path to a PEM file of additional trusted certificates.
+    trusted_certificates: Option<PathBuf>,
 
-    /// A list of messages that needs to be sent.
-    to_send: Mutex<LinkedList<Message>>,
+    /// Whether the credentials are stored on disk.
+    persistent: bool,
 
-    /// The state of the connection.
-    state: ConnectionState,
+    /// The name of the profile to use.
+    profile: Option<String>,
+
+    /// The backoff policy used between connection attempts.
+    backoff: Backoff,
+
+    /// The configuration of the outbound queue.
+    queue: QueueConfig,
+
+    /// The configuration of the keepalive.
+    keepalive: KeepaliveConfig,
+
+    /// The configuration of the acknowledged messages.
+    acknowledgements: AcknowledgementConfig,
+
+    /// Whether the messages exchanged with other clients are encrypted.
+    encrypted: bool,
 }
 
-impl Connection {
-    /// Create a new [Connection].
-    pub fn new<'a>(domain: impl Into<Cow<'a, str>>) -> io::Result<Self> {
-        let domain = domain.into();
-
-        // Loads the identifier and secret key from disk.
-        let (data_path, identifier, secret) = {
-            // Find the relay data file path.
-            let mut path = home::home_dir().ok_or_else(|| {
-                io::Error::new(io::ErrorKind::NotFound, "could not find home directory")
-            })?;
-            path.push(".relay-data");
-
-            // Check if the file exists.
-            match false {
-                true => {
-                    // Read the file and parse the identifier and secret key.
-                    let contents = fs::read(&path)?;
-                    if contents.len() != 32 {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "invalid data in .relay-data",
-                        ));
-                    }
-                    let identifier = Uuid::from_slice(&contents[..16]).map_err(io::Error::other)?;
-                    let secret = Uuid::from_slice(&contents[16..]).map_err(io::Error::other)?;
-                    (path, Some(identifier), Some(secret))
-                }
-                false => (path, None, None),
+impl ConnectionBuilder {
+    /// Create a new [ConnectionBuilder] with the default configuration.
+    fn new(target: Target) -> Self {
+        Self {
+            target,
+            credentials_path: None,
+            trusted_certificates: None,
+            persistent: true,
+            profile: None,
+            backoff: Backoff::default(),
+            queue: QueueConfig::default(),
+            keepalive: KeepaliveConfig::default(),
+            acknowledgements: AcknowledgementConfig::default(),
+            encrypted: false,
+        }
+    }
+
+    /// Set the path to the file where the credentials are stored.
+    ///
+    /// By default, the `.relay-data` file in the home directory is used.
+    pub fn credentials_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.credentials_path = Some(path.into());
+        self
+    }
+
+    /// Trust the certificates of the given PEM file, in addition to the
+    /// native root certificates, to connect to a `wss` URL.
+    ///
+    /// This allows to connect to a relay server using a self-signed
+    /// certificate, which must not be a CA certificate. It's ignored with a
+    /// custom [Transport].
+    pub fn trust_certificates(mut self, path: impl Into<PathBuf>) -> Self {
+        self.trusted_certificates = Some(path.into());
+        self
+    }
+
+    /// Do not load or save the credentials, so a new identity is registered
+    /// by each [Connection].
+    ///
+    /// This is useful for tests, especially with a [LoopbackHub] whose
+    /// identities are not valid on a real relay server.
+    pub const fn ephemeral(mut self) -> Self {
+        self.persistent = false;
+        self
+    }
+
+    /// Use the profile with the given name, stored in the default
+    /// [Profiles] directory.
+    ///
+    /// This is ignored if a path is given with
+    /// [credentials_path](Self::credentials_path).
+    pub fn profile(mut self, name: impl Into<String>) -> Self {
+        self.profile = Some(name.into());
+        self
+    }
+
+    /// Set the backoff policy used to wait between connection attempts.
+    pub const fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Set the configuration of the queue of messages waiting to be sent.
+    pub const fn queue(mut self, queue: QueueConfig) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    /// Set the configuration of the keepalive used to detect dead
+    /// connections.
+    ///
+    /// Building the connection fails if the interval or the timeout is zero.
+    pub const fn keepalive(mut self, keepalive: KeepaliveConfig) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Set the configuration of the acknowledged messages.
+    pub const fn acknowledgements(mut self, acknowledgements: AcknowledgementConfig) -> Self {
+        self.acknowledgements = acknowledgements;
+        self
+    }
+
+    /// Encrypt end to end the messages exchanged with other clients, so the
+    /// relay server only sees who they are sent to.
+    ///
+    /// The public key of the connection is sent to each peer before the
+    /// first message, and the public key announced by a peer is pinned to its
+    /// identifier: a different key announced later is rejected until it's
+    /// forgotten with [forget_peer_key](Connection::forget_peer_key). The keys
+    /// are stored next to the credentials, unless the connection is
+    /// [ephemeral](Self::ephemeral).
+    ///
+    /// The peers must enable the encryption too: the messages sent to a peer
+    /// wait until its public key is received.
+    ///
+    /// The messages received without encryption are dropped and counted by
+    /// [rejected_messages](Connection::rejected_messages), including the
+    /// messages sent to rooms since they are not encrypted.
+    pub const fn encryption(mut self) -> Self {
+        self.encrypted = true;
+        self
+    }
+
+    /// Build the [Connection].
+    ///
+    /// The URL must use the `ws` or `wss` scheme. If it has no port, the
+    /// default port of the scheme is used.
+    pub fn build(self) -> io::Result<Connection> {
+        self.build_with(|url, trusted_certificates| {
+            let mut transport = WebSocketTransport::new(url)?;
+            if let Some(path) = trusted_certificates {
+                transport = transport.trust_certificates(path)?;
             }
+            Ok(Box::new(transport))
+        })
+    }
+
+    /// Build an [AsyncConnection] and wait until it's connected, or returns
+    /// an error if the first attempt fails.
+    ///
+    /// This must be called inside a tokio runtime, and requires the `async`
+    /// feature. With a custom [Transport], the connection is updated
+    /// periodically instead of when something is received.
+    #[cfg(feature = "async")]
+    pub async fn connect(self) -> io::Result<AsyncConnection> {
+        let poll_interval = match self.target {
+            Target::Url(_) => None,
+            Target::Transport(_) => Some(POLL_INTERVAL),
+        };
+        let wake = Arc::new(Notify::new());
+        let connection = self.build_with(|url, trusted_certificates| {
+            let mut transport = AsyncWebSocketTransport::new(url, Arc::clone(&wake))?;
+            if let Some(path) = trusted_certificates {
+                transport = transport.trust_certificates(path)?;
+            }
+            Ok(Box::new(transport))
+        })?;
+        AsyncConnection::start(connection, wake, poll_interval).await
+    }
+
+    /// Build the [Connection], creating the websocket [Transport] with the
+    /// given function if a URL is used.
+    fn build_with(
+        self,
+        websocket: impl FnOnce(&str, Option<&Path>) -> io::Result<Box<dyn Transport>>,
+    ) -> io::Result<Connection> {
+        // Check the configuration.
+        if self.keepalive.interval.is_zero() || self.keepalive.timeout.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the keepalive interval and timeout must not be zero",
+            ));
+        }
+
+        // Create the transport to the relay server.
+        let transport = match self.target {
+            Target::Url(url) => websocket(&url, self.trusted_certificates.as_deref())?,
+            Target::Transport(transport) => transport,
+        };
+
+        // Loads the credentials from disk.
+        let store = match (self.persistent, self.credentials_path, self.profile) {
+            (false, _, _) => None,
+            (true, Some(path), _) => Some(CredentialStore::new(path)),
+            (true, None, Some(profile)) => Some(Profiles::in_home()?.store(&profile)?),
+            (true, None, None) => Some(CredentialStore::in_home()?),
+        };
+        let credentials = match &store {
+            Some(store) => store.load()?,
+            None => None,
+        };
+
+        // Loads the encryption keys from disk.
+        let encryption = match self.encrypted {
+            true => Some(Encryption::load(
+                store.as_ref().map(CredentialStore::keys_path),
+            )?),
+            false => None,
         };
 
         // Create the connection and return it.
-        Ok(Self {
-            address_list: (domain.as_ref(), 443).to_socket_addrs()?.collect(),
-            domain: domain.into_owned(),
-            data_path,
-            identifier,
-            secret,
-            to_send: Mutex::new(LinkedList::new()),
+        Ok(Connection {
+            transport,
+            store,
+            credentials,
+            to_send: OutboundQueue::new(self.queue),
             state: ConnectionState::Disconnected,
+            retry: Retry::new(self.backoff),
+            heartbeat: Heartbeat::new(self.keepalive),
+            acknowledgements: Acknowledgements::new(self.acknowledgements),
+            encryption,
+            rejected: 0,
+            status: ConnectionStatus::Connecting,
+            status_changes: LinkedList::new(),
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::NONE,
+            subscriptions: HashSet::new(),
+            rooms: HashSet::new(),
+            notifications: LinkedList::new(),
         })
     }
+}
+
+/// A connection to a relay server.
+pub struct Connection {
+    /// The transport used to reach the relay server.
+    transport: Box<dyn Transport>,
+
+    /// The file where the credentials are stored, if they are persistent.
+    store: Option<CredentialStore>,
+
+    /// The credentials used to authenticate with the relay server.
+    credentials: Option<Credentials>,
+
+    /// The messages that needs to be sent.
+    to_send: OutboundQueue,
+
+    /// The state of the connection.
+    state: ConnectionState,
+
+    /// The failed connection attempts.
+    retry: Retry,
+
+    /// The keepalive of the active connection.
+    heartbeat: Heartbeat,
+
+    /// The acknowledged messages sent and received.
+    acknowledgements: Acknowledgements,
+
+    /// The keys of the end-to-end encryption, if it's enabled.
+    encryption: Option<Encryption>,
+
+    /// The number of messages received from other clients that were dropped
+    /// because they were not encrypted or could not be decrypted.
+    rejected: u64,
+
+    /// The status of the connection after the last update.
+    status: ConnectionStatus,
+
+    /// The status changes that have not been taken yet.
+    status_changes: LinkedList<ConnectionStatus>,
+
+    /// The protocol version of the current session.
+    version: u16,
+
+    /// The capabilities of the current session.
+    capabilities: Capabilities,
+
+    /// The peers whose presence the connection is subscribed to.
+    subscriptions: HashSet<Uuid>,
+
+    /// The rooms the connection is a member of.
+    rooms: HashSet<RoomName>,
+
+    /// The notifications that have not been taken yet.
+    notifications: LinkedList<Notification>,
+}
+
+impl Connection {
+    /// Create a new [Connection] to the relay server at the given domain,
+    /// using a secure websocket on the default port.
+    pub fn new<'a>(domain: impl Into<Cow<'a, str>>) -> io::Result<Self> {
+        Self::builder(format!("wss://{}", domain.into())).build()
+    }
+
+    /// Create a new [Connection] like [Connection::new], but using the
+    /// identity stored in the profile with the given name.
+    pub fn with_profile<'a>(
+        domain: impl Into<Cow<'a, str>>,
+        profile: impl Into<String>,
+    ) -> io::Result<Self> {
+        Self::builder(format!("wss://{}", domain.into()))
+            .profile(profile)
+            .build()
+    }
+
+    /// Create a [ConnectionBuilder] for the relay server at the given URL.
+    ///
+    /// This allows to use a plain websocket (`ws://localhost:8080`) or a non
+    /// standard port.
+    pub fn builder(url: impl Into<String>) -> ConnectionBuilder {
+        ConnectionBuilder::new(Target::Url(url.into()))
+    }
+
+    /// Create a [ConnectionBuilder] using a custom [Transport] to reach the
+    /// relay server, for example a [LoopbackTransport].
+    pub fn builder_with_transport(transport: impl Transport + 'static) -> ConnectionBuilder {
+        ConnectionBuilder::new(Target::Transport(Box::new(transport)))
+    }
 
     /// Get the identifier of the connection.
     pub const fn identifier(&self) -> Option<Uuid> {
-        self.identifier
+        match self.credentials {
+            Some(credentials) => Some(credentials.identifier),
+            None => None,
+        }
+    }
+
+    /// Returns the status of the connection.
+    pub const fn status(&self) -> ConnectionStatus {
+        self.status
+    }
+
+    /// Returns the last error that made the connection fail.
+    pub fn last_error(&self) -> Option<&str> {
+        self.retry.last_error.as_deref()
+    }
+
+    /// Take the status changes that happened since the last call.
+    ///
+    /// Only the last [MAX_STATUS_CHANGES] changes are kept, so this should be
+    /// called regularly if the changes are needed.
+    pub fn take_status_changes(&mut self) -> LinkedList<ConnectionStatus> {
+        std::mem::take(&mut self.status_changes)
+    }
+
+    /// Returns the last measured round-trip time to the relay server.
+    pub const fn rtt(&self) -> Option<Duration> {
+        self.heartbeat.rtt
+    }
+
+    /// Returns the capabilities negotiated with the relay server during the
+    /// last handshake.
+    ///
+    /// The notifications can only be received if the relay server supports
+    /// them.
+    pub const fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Take the notifications received from the relay server since the last
+    /// call.
+    ///
+    /// Only the last [MAX_NOTIFICATIONS] notifications are kept, so this
+    /// should be called regularly if the notifications are needed.
+    pub fn take_notifications(&mut self) -> LinkedList<Notification> {
+        std::mem::take(&mut self.notifications)
+    }
+
+    /// Be notified when the given peer comes online or goes offline.
+    ///
+    /// A notification with the current presence of the peer is received
+    /// once the relay server has processed the subscription. Subscriptions
+    /// are renewed automatically after reconnecting.
+    pub fn subscribe_presence(&mut self, peer: Uuid) -> Result<(), QueueFull> {
+        self.subscriptions.insert(peer);
+        match self.status {
+            ConnectionStatus::Active => self
+                .to_send
+                .push(protocol::Frame::Control(Request::Subscribe(peer))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Stop being notified about the presence of the given peer.
+    pub fn unsubscribe_presence(&mut self, peer: Uuid) -> Result<(), QueueFull> {
+        self.subscriptions.remove(&peer);
+        match self.status {
+            ConnectionStatus::Active => self
+                .to_send
+                .push(protocol::Frame::Control(Request::Unsubscribe(peer))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the rooms the connection is a member of.
+    ///
+    /// The rooms are joined again automatically after reconnecting.
+    pub fn rooms(&self) -> impl Iterator<Item = &RoomName> {
+        self.rooms.iter()
+    }
+
+    /// Create a new room and join it.
+    ///
+    /// A [Notification::RoomJoined] is received once the room is created, or
+    /// a [Notification::RoomError] if it already exists.
+    pub fn create_room(&self, room: RoomName) -> Result<(), QueueFull> {
+        self.to_send
+            .push(protocol::Frame::Control(Request::CreateRoom(room)))
+    }
+
+    /// Join a room, creating it if it doesn't exist.
+    ///
+    /// A [Notification::RoomJoined] is received once the room is joined.
+    pub fn join_room(&mut self, room: RoomName) -> Result<(), QueueFull> {
+        self.rooms.insert(room.clone());
+        match self.status {
+            ConnectionStatus::Active => self
+                .to_send
+                .push(protocol::Frame::Control(Request::JoinRoom(room))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Leave a room.
+    pub fn leave_room(&mut self, room: RoomName) -> Result<(), QueueFull> {
+        self.rooms.remove(&room);
+        match self.status {
+            ConnectionStatus::Active => self
+                .to_send
+                .push(protocol::Frame::Control(Request::LeaveRoom(room))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Ask for the members of a room.
+    ///
+    /// The members are received in a [Notification::RoomMembers].
+    pub fn list_room(&self, room: RoomName) -> Result<(), QueueFull> {
+        self.to_send
+            .push(protocol::Frame::Control(Request::ListRoom(room)))
+    }
+
+    /// Send a message to all the other members of a room.
+    ///
+    /// The relay server sends it to each member, which receives it as a
+    /// message from this connection. It's not encrypted, so the members with
+    /// the [encryption](ConnectionBuilder::encryption) enabled drop it.
+    pub fn send_to_room<'a>(
+        &self,
+        room: RoomName,
+        message: impl Into<Cow<'a, [u8]>>,
+    ) -> Result<(), QueueFull> {
+        self.to_send
+            .push(protocol::Frame::Control(Request::SendToRoom {
+                room,
+                payload: message.into().into_owned(),
+            }))
+    }
+
+    /// Ask the relay server for a new secret, replacing the current one.
+    ///
+    /// The new secret is received in a [Notification::SecretRotated] and
+    /// saved to the credential file, if any.
+    pub fn rotate_secret(&self) -> Result<(), QueueFull> {
+        self.to_send
+            .push(protocol::Frame::Control(Request::RotateSecret))
+    }
+
+    /// Returns the number of messages waiting to be sent.
+    pub fn queue_len(&self) -> usize {
+        self.to_send.len()
+    }
+
+    /// Returns the number of messages that have been dropped from the
+    /// outbound queue.
+    pub fn dropped_messages(&self) -> u64 {
+        self.to_send.dropped()
+    }
+
+    /// Returns the number of messages received from other clients that were
+    /// dropped because they were not encrypted or could not be decrypted,
+    /// while the [encryption](ConnectionBuilder::encryption) is enabled.
+    pub const fn rejected_messages(&self) -> u64 {
+        self.rejected
     }
 
     /// Send a message to the target client.
-    pub fn send<'a>(&self, target_id: Uuid, message: impl Into<Cow<'a, [u8]>>) {
-        let mut data = message.into().into_owned();
-        data.extend_from_slice(target_id.as_bytes());
-        if let Ok(mut to_send) = self.to_send.lock() {
-            to_send.push_back(Message::binary(data));
+    ///
+    /// The message is added to the outbound queue and will be sent during
+    /// the next [update](Self::update). If the queue is full, the
+    /// [QueuePolicy] of the connection is applied.
+    pub fn send<'a>(
+        &self,
+        target_id: Uuid,
+        message: impl Into<Cow<'a, [u8]>>,
+    ) -> Result<(), QueueFull> {
+        self.to_send.push(protocol::Frame::Data {
+            peer: target_id,
+            flags: Flags::NONE,
+            payload: message.into().into_owned(),
+        })
+    }
+
+    /// Send a durable message to the target client.
+    ///
+    /// Unlike [send](Self::send), if the target is not connected the relay
+    /// server keeps the message for a while and delivers it when the target
+    /// reconnects. If the storage of the target is full, a
+    /// [Notification::StoreFull] is received instead.
+    pub fn send_durable<'a>(
+        &self,
+        target_id: Uuid,
+        message: impl Into<Cow<'a, [u8]>>,
+    ) -> Result<(), QueueFull> {
+        self.to_send.push(protocol::Frame::Data {
+            peer: target_id,
+            flags: Flags::DURABLE,
+            payload: message.into().into_owned(),
+        })
+    }
+
+    /// Send a message to the target client, and be notified of its
+    /// [Delivery].
+    ///
+    /// Unlike [send](Self::send), the message is sent again until a receipt
+    /// is received, as configured by the [AcknowledgementConfig] of the
+    /// connection, and the target receives it only once. The [Delivery] is
+    /// received with [take_deliveries](Self::take_deliveries).
+    ///
+    /// This requires the [ACKNOWLEDGEMENTS](Capabilities::ACKNOWLEDGEMENTS)
+    /// capability.
+    pub fn send_acknowledged<'a>(
+        &mut self,
+        target_id: Uuid,
+        message: impl Into<Cow<'a, [u8]>>,
+    ) -> Result<MessageId, QueueFull> {
+        self.push_acknowledged(target_id, Flags::NONE, &message.into())
+    }
+
+    /// Send a durable message to the target client, and be notified of its
+    /// [Delivery].
+    ///
+    /// This combines [send_durable](Self::send_durable) and
+    /// [send_acknowledged](Self::send_acknowledged): the message is
+    /// acknowledged once the target receives it or once the relay server
+    /// stores it.
+    pub fn send_durable_acknowledged<'a>(
+        &mut self,
+        target_id: Uuid,
+        message: impl Into<Cow<'a, [u8]>>,
+    ) -> Result<MessageId, QueueFull> {
+        self.push_acknowledged(target_id, Flags::DURABLE, &message.into())
+    }
+
+    /// Add an acknowledged message to the outbound queue and wait for its
+    /// receipt.
+    fn push_acknowledged(
+        &mut self,
+        target_id: Uuid,
+        flags: Flags,
+        message: &[u8],
+    ) -> Result<MessageId, QueueFull> {
+        let (id, frame) = self.acknowledgements.prepare(target_id, flags, message);
+        self.to_send.push(frame.clone())?;
+        self.acknowledgements.track(id, target_id, frame);
+        Ok(id)
+    }
+
+    /// Take the deliveries of the acknowledged messages that happened since
+    /// the last call.
+    pub fn take_deliveries(&mut self) -> LinkedList<Delivery> {
+        self.acknowledgements.take_deliveries()
+    }
+
+    /// Returns the public key used to encrypt the messages, if the
+    /// [encryption](ConnectionBuilder::encryption) is enabled.
+    ///
+    /// Comparing it with the key pinned by a peer through another channel
+    /// ensures the relay server didn't announce its own key instead.
+    pub fn public_key(&self) -> Option<PublicKey> {
+        self.encryption.as_ref().map(Encryption::public_key)
+    }
+
+    /// Returns the public key pinned for a peer, if it's known.
+    pub fn peer_key(&self, peer: Uuid) -> Option<PublicKey> {
+        self.encryption.as_ref()?.peer_key(peer)
+    }
+
+    /// Forget the public key pinned for a peer, so the next key it announces
+    /// is trusted, for example after it lost its keys.
+    pub fn forget_peer_key(&mut self, peer: Uuid) {
+        if let Some(encryption) = &mut self.encryption {
+            encryption.forget(peer);
         }
     }
 
-    /// Create a new [TcpStream] to the relay server.
-    fn create_stream(&mut self) -> ConnectionState {
-        // Take a random relay address.
-        let Some(address) = self.address_list.choose(&mut rand::thread_rng()) else {
-            warn!("no relay address available");
-            return ConnectionState::Disconnected;
-        };
+    /// Start opening a new [Link] to the relay server.
+    fn connect(&mut self) -> ConnectionState {
+        match self.transport.connect() {
+            Ok(link) => self.open_link(link),
+            Err(e) => self.fail(e.to_string()),
+        }
+    }
 
-        // Create the new TCP stream.
-        match TcpStream::connect(address.to_owned()) {
-            Ok(stream) => ConnectionState::Connecting(stream, Instant::now()),
-            Err(e) => {
-                warn!("failed to start connection to the relay server: {e}");
-                ConnectionState::Disconnected
-            }
+    /// Continue opening the [Link] to the relay server.
+    fn open_link(&mut self, mut link: Box<dyn Link>) -> ConnectionState {
+        match link.poll_open() {
+            Ok(LinkState::Open) => ConnectionState::Opened(link),
+            Ok(state) => ConnectionState::Opening(link, state),
+            Err(e) => self.fail(e.to_string()),
         }
     }
 
-    /// Check if the [TcpStream] of the [Connection] is connected.
-    fn check_connection(&mut self, stream: TcpStream, start: Instant) -> ConnectionState {
-        // Check for connection errors.
-        if let Err(e) = stream.take_error() {
-            warn!("failed to connect to the relay server: {e}");
-            return ConnectionState::Disconnected;
+    /// Start authentication with the relay server.
+    fn start_authentication(&mut self, mut link: Box<dyn Link>) -> ConnectionState {
+        // Send the hello message, without credentials to request a new
+        // identifier and secret key.
+        let hello = Hello::Versioned {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::ALL,
+            credentials: self
+                .credentials
+                .map(|credentials| (credentials.identifier, credentials.secret)),
+        };
+        match link.send(hello.encode()) {
+            Ok(()) => ConnectionState::Greeting(link, Instant::now() + self.heartbeat.timeout()),
+            Err(e) => self.fail(format!("failed to send hello message: {e}")),
         }
+    }
 
-        // Check if the stream is connected.
-        let connected = match stream.peek(&mut [0]) {
-            Ok(_) => true,
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => true,
-            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => false,
-            Err(e) => {
-                warn!("failed to connect to the relay server: {e}");
-                return ConnectionState::Disconnected;
+    /// Wait for the welcome message of the relay server, failing if it
+    /// doesn't come before the deadline.
+    fn get_welcome(&mut self, mut link: Box<dyn Link>, deadline: Instant) -> ConnectionState {
+        let data = match link.recv() {
+            Ok(Some(Frame::Data(data))) => data,
+            Ok(None | Some(Frame::Pong(_))) if Instant::now() >= deadline => {
+                return self.fail("the relay server didn't answer the hello message".to_owned());
+            }
+            Ok(None | Some(Frame::Pong(_))) => return ConnectionState::Greeting(link, deadline),
+            Err(e) => return self.fail(format!("failed to receive welcome message: {e}")),
+        };
+        let (version, capabilities, credentials) = match Welcome::decode(&data) {
+            Some(Welcome::Accepted {
+                version,
+                capabilities,
+                credentials,
+            }) => (version, capabilities, credentials),
+            Some(Welcome::Rejected(reason)) => {
+                return self.fail(format!(
+                    "the relay server rejected the connection: {reason}"
+                ));
             }
+            None => return self.fail("received malformed welcome message".to_owned()),
         };
+        self.version = version;
+        self.capabilities = capabilities;
 
-        // Check if the connection has timed out.
-        let elapsed = start.elapsed();
-        if elapsed > Duration::from_secs(5) {
-            warn!("connection to the relay server timed out");
-            return ConnectionState::Disconnected;
+        // Save the new client identifier and secret.
+        if let Some((identifier, secret)) = credentials {
+            self.save_credentials(Credentials { identifier, secret });
         }
 
-        // Update the connection state if connected.
-        match connected {
-            true => ConnectionState::Connected(stream),
-            false => ConnectionState::Connecting(stream, start),
-        }
+        // Activate the connection.
+        self.start_session(link)
     }
 
-    /// Start the websocket handshake.
-    fn start_handshake(&mut self, stream: TcpStream) -> ConnectionState {
-        match tungstenite::client_tls(format!("wss://{}", self.domain), stream) {
-            Ok((socket, _)) => ConnectionState::Handshaked(socket),
-            Err(HandshakeError::Interrupted(handshake)) => ConnectionState::Handshaking(handshake),
-            Err(HandshakeError::Failure(e)) => {
-                warn!("handshake failed with the relay server: {e}");
-                ConnectionState::Disconnected
+    /// Use new credentials and save them to the credential file, if any.
+    fn save_credentials(&mut self, credentials: Credentials) {
+        self.credentials = Some(credentials);
+        if let Some(store) = &self.store {
+            if let Err(e) = store.save(credentials) {
+                warn!(
+                    "failed to save credentials to {}: {e}",
+                    store.path().display()
+                );
             }
         }
     }
 
-    /// Continue the websocket handshake.
-    fn continue_handshake(
-        &mut self,
-        handshake: MidHandshake<ClientHandshake<MaybeTlsStream<TcpStream>>>,
-    ) -> ConnectionState {
-        match handshake.handshake() {
-            Ok((socket, _)) => ConnectionState::Handshaked(socket),
-            Err(HandshakeError::Interrupted(handshake)) => ConnectionState::Handshaking(handshake),
-            Err(HandshakeError::Failure(e)) => {
-                warn!("handshake failed with the relay server: {e}");
-                ConnectionState::Disconnected
+    /// Send the control requests that configure a new session.
+    fn start_session(&mut self, mut link: Box<dyn Link>) -> ConnectionState {
+        let mut requests = Vec::new();
+        if self.capabilities.contains(Capabilities::PRESENCE) {
+            requests.extend(self.subscriptions.iter().copied().map(Request::Subscribe));
+        }
+        if self.capabilities.contains(Capabilities::ROOMS) {
+            requests.extend(self.rooms.iter().cloned().map(Request::JoinRoom));
+        }
+        for request in requests {
+            let frame = protocol::Frame::Control(request);
+            if let Err(e) = link.send(frame.encode(self.version)) {
+                return ConnectionState::BackingOff(
+                    self.retry
+                        .fail(format!("failed to send control request: {e}")),
+                );
             }
         }
+        ConnectionState::Active(link)
     }
 
-    /// Start authentication with the relay server.
-    fn start_authentication(
+    /// Update the [Connection] by receiving and sending messages.
+    fn update_connection(
         &mut self,
-        mut socket: WebSocket<MaybeTlsStream<TcpStream>>,
+        mut link: Box<dyn Link>,
+        messages: &mut LinkedList<(Uuid, Vec<u8>)>,
     ) -> ConnectionState {
-        match (self.identifier, self.secret) {
-            (Some(identifier), Some(secret)) => {
-                // Create the authentication message.
-                let mut data = Vec::with_capacity(32);
-                data.extend(identifier.as_bytes());
-                data.extend(secret.as_bytes());
-
-                // Send the authentication message.
-                match socket.send(Message::Binary(data)) {
-                    Ok(()) => ConnectionState::Active(socket),
-                    Err(e) => {
-                        warn!("failed to send authentication message: {e}");
-                        ConnectionState::Disconnected
+        // Send the queued messages first, to make room for the frames queued
+        // while receiving.
+        if let Err(e) = self.send_queued(link.as_mut()) {
+            return self.fail(e);
+        }
+
+        // Receive messages from the link and send them to the receive channel.
+        loop {
+            match link.recv() {
+                Ok(Some(Frame::Pong(payload))) => self.heartbeat.pong(&payload),
+                Ok(Some(Frame::Data(data))) => {
+                    self.heartbeat.received();
+
+                    // Decode the frame, keeping the notifications from the relay
+                    // server apart from the messages.
+                    match protocol::Frame::decode(data, self.version) {
+                        Ok(protocol::Frame::Data {
+                            peer,
+                            flags,
+                            payload,
+                        }) => self.receive_data(peer, flags, payload, messages),
+                        Ok(protocol::Frame::Control(notification)) => {
+                            match &notification {
+                                Notification::RoomJoined(room) => {
+                                    self.rooms.insert(room.clone());
+                                }
+                                Notification::RoomLeft(room) => {
+                                    self.rooms.remove(room);
+                                }
+                                &Notification::SecretRotated(secret) => {
+                                    if let Some(credentials) = self.credentials {
+                                        let identifier = credentials.identifier;
+                                        self.save_credentials(Credentials { identifier, secret });
+                                    }
+                                }
+                                _ => (),
+                            }
+                            if self.notifications.len() >= MAX_NOTIFICATIONS {
+                                self.notifications.pop_front();
+                            }
+                            self.notifications.push_back(notification);
+                        }
+                        Err(e) => warn!("received {e} from the relay server"),
                     }
                 }
-            }
-            _ => {
-                // Send empty authentication message to request a new identifier and secret key.
-                match socket.send(Message::Binary(vec![])) {
-                    Ok(()) => ConnectionState::Registering(socket),
-                    Err(e) => {
-                        warn!("failed to send registration message: {e}");
-                        ConnectionState::Disconnected
-                    }
+                Ok(None) => break,
+                Err(e) => {
+                    return ConnectionState::BackingOff(
+                        self.retry.fail(format!("relay connection closed: {e}")),
+                    );
                 }
             }
         }
-    }
 
-    /// Wait for the registration response.
-    fn get_registration_response(
-        &mut self,
-        mut socket: WebSocket<MaybeTlsStream<TcpStream>>,
-    ) -> ConnectionState {
-        match socket.read() {
-            Ok(message) => {
-                // Check the message length.
-                let data = message.into_data();
-                if data.len() != 32 {
-                    warn!("received malformed registration response");
-                    return ConnectionState::Disconnected;
-                }
-
-                // Extract the client identifier and secret.
-                self.identifier = Some(Uuid::from_slice(&data[..16]).expect("invalid identifier"));
-                self.secret = Some(Uuid::from_slice(&data[16..]).expect("invalid secret"));
+        // Send again the acknowledged messages whose receipt didn't come,
+        // and ask for the keys needed to encrypt or decrypt messages.
+        for frame in self.acknowledgements.poll() {
+            self.to_send.push(frame).ok();
+        }
+        if let Some(encryption) = &mut self.encryption {
+            for frame in encryption.poll() {
+                self.to_send.push(frame).ok();
+            }
+        }
 
-                // Save the client identifier and secret.
-                fs::write(&self.data_path, data).ok();
+        // Send the receipts, keys and retransmissions queued above.
+        if let Err(e) = self.send_queued(link.as_mut()) {
+            return self.fail(e);
+        }
 
-                // Activate the connection.
-                ConnectionState::Active(socket)
-            }
-            Err(tungstenite::Error::Io(ref e))
-                if e.kind() == std::io::ErrorKind::WouldBlock
-                    || e.kind() == std::io::ErrorKind::Interrupted =>
-            {
-                ConnectionState::Registering(socket)
-            }
-            Err(e) => {
-                warn!("failed to receive registration response: {e}");
-                ConnectionState::Disconnected
+        // Check that the connection is alive and send pings.
+        match self.heartbeat.poll() {
+            Ok(Some(payload)) => {
+                if let Err(e) = link.ping(payload) {
+                    return ConnectionState::BackingOff(
+                        self.retry.fail(format!("relay connection closed: {e}")),
+                    );
+                }
             }
+            Ok(None) => (),
+            Err(e) => return ConnectionState::BackingOff(self.retry.fail(e)),
         }
+
+        // Keep the connection connected.
+        ConnectionState::Active(link)
     }
 
-    /// Update the [Connection] by receiving and sending messages.
-    fn update_connection(
-        &mut self,
-        mut socket: WebSocket<MaybeTlsStream<TcpStream>>,
-        messages: &mut LinkedList<(Uuid, Vec<u8>)>,
-    ) -> ConnectionState {
+    /// Send the messages of the outbound queue to the link, encrypting them
+    /// if needed, until it's empty or the link can't take more frames.
+    ///
+    /// Returns an error if the connection should be closed.
+    fn send_queued(&mut self, link: &mut dyn Link) -> Result<(), String> {
         // Unlock the sending list.
-        let Ok(mut to_send) = self.to_send.lock() else {
-            warn!("sending list closed");
-            return ConnectionState::Disconnected;
+        let identifier = self.identifier();
+        let Some(mut to_send) = self.to_send.lock() else {
+            return Err("sending list closed".to_owned());
         };
 
-        // Send messages from the send channel to the socket.
-        while let Some(message) = to_send.pop_front() {
-            match socket.send(message) {
+        // Send messages from the send channel to the link, encrypting them
+        // if needed.
+        while let Some(frame) = to_send.pop_front() {
+            let frame = match (&mut self.encryption, identifier) {
+                (Some(encryption), Some(identifier)) => match encryption.seal(identifier, frame) {
+                    Some(frame) => frame,
+                    None => continue,
+                },
+                _ => frame,
+            };
+            match link.send(frame.encode(self.version)) {
                 Ok(()) => (),
-                Err(tungstenite::Error::Io(ref e))
+                Err(ref e)
                     if e.kind() == std::io::ErrorKind::WouldBlock
                         || e.kind() == std::io::ErrorKind::Interrupted =>
                 {
                     break;
                 }
-                Err(e) => {
-                    warn!("relay connection closed: {e}");
-                    return ConnectionState::Disconnected;
-                }
+                Err(e) => return Err(format!("relay connection closed: {e}")),
             }
         }
+        Ok(())
+    }
 
-        // Receive messages from the socket and send them to the receive channel.
-        loop {
-            match socket.read() {
-                Ok(message) => {
-                    // Check the message length.
-                    let mut data = message.into_data();
-                    if data.len() < 16 {
-                        warn!("received malformed message with length: {}", data.len());
-                        continue;
-                    }
-
-                    // Extract the sender ID.
-                    let id_start = data.len() - 16;
-                    let sender_id = Uuid::from_slice(&data[id_start..]).expect("invalid sender id");
-                    data.truncate(id_start);
+    /// Handle a data frame received from a peer.
+    fn receive_data(
+        &mut self,
+        peer: Uuid,
+        flags: Flags,
+        payload: Vec<u8>,
+        messages: &mut LinkedList<(Uuid, Vec<u8>)>,
+    ) {
+        // Keep track of the receipts of the acknowledged messages.
+        if flags.contains(Flags::RECEIPT) {
+            self.acknowledgements.receipt(peer, &payload);
+            return;
+        }
 
-                    // Add the message to the message list.
-                    messages.push_back((sender_id, data));
-                }
-                Err(tungstenite::Error::Io(ref e))
-                    if e.kind() == std::io::ErrorKind::WouldBlock
-                        || e.kind() == std::io::ErrorKind::Interrupted =>
-                {
-                    break;
+        // Learn the keys of the peers, and decrypt their messages. The
+        // messages that waited for a key were already accepted by the queue.
+        if flags.contains(Flags::KEY_EXCHANGE) {
+            let frames = self
+                .encryption
+                .as_mut()
+                .and_then(|encryption| encryption.exchange(peer, &payload));
+            for frame in frames.unwrap_or_default() {
+                self.to_send.requeue(frame);
+            }
+            return;
+        }
+        let identifier = self.identifier();
+        let (flags, payload) = match (&mut self.encryption, identifier) {
+            (Some(encryption), Some(identifier)) if flags.contains(Flags::ENCRYPTED) => {
+                match encryption.open(identifier, peer, flags, &payload) {
+                    Some(payload) => (flags.difference(Flags::ENCRYPTED), payload),
+                    None => {
+                        warn!("failed to decrypt a message from {peer}");
+                        self.rejected += 1;
+                        return;
+                    }
                 }
-                Err(e) => {
-                    warn!("relay connection closed: {e}");
-                    return ConnectionState::Disconnected;
+            }
+            (Some(_), _) => {
+                warn!("received a message from {peer} without encryption, dropping it");
+                self.rejected += 1;
+                return;
+            }
+            (None, _) if flags.contains(Flags::ENCRYPTED) => {
+                warn!("received an encrypted message without encryption enabled");
+                return;
+            }
+            (None, _) => (flags, payload),
+        };
+
+        // Acknowledge the acknowledged messages, receiving them only once.
+        if flags.contains(Flags::ACKNOWLEDGED) {
+            match self.acknowledgements.receive(peer, &payload) {
+                Some((receipt, message)) => {
+                    self.to_send.push(receipt).ok();
+                    messages.extend(message.map(|message| (peer, message)));
                 }
+                None => warn!("received a malformed acknowledged message"),
             }
+            return;
         }
+        messages.push_back((peer, payload));
+    }
 
-        // Keep the connection connected.
-        ConnectionState::Active(socket)
+    /// Record a connection failure and back off before the next attempt.
+    fn fail(&mut self, error: String) -> ConnectionState {
+        ConnectionState::BackingOff(self.retry.fail(error))
+    }
+
+    /// Returns the [ConnectionStatus] corresponding to the current state.
+    const fn current_status(&self) -> ConnectionStatus {
+        match self.state {
+            ConnectionState::Disconnected | ConnectionState::Opening(_, LinkState::Connecting) => {
+                ConnectionStatus::Connecting
+            }
+            ConnectionState::BackingOff(retry_at) => ConnectionStatus::BackingOff { retry_at },
+            ConnectionState::Opening(..) | ConnectionState::Opened(_) => {
+                ConnectionStatus::Handshaking
+            }
+            ConnectionState::Greeting(..) => match self.credentials {
+                Some(_) => ConnectionStatus::Handshaking,
+                None => ConnectionStatus::Registering,
+            },
+            ConnectionState::Active(_) => ConnectionStatus::Active,
+        }
+    }
+
+    /// Returns the time at which the [Connection] should be updated again if
+    /// nothing is received from the relay server in the meantime, to
+    /// reconnect or to send its pings, retransmissions and key requests.
+    ///
+    /// Returns [None] if it only waits for the relay server.
+    pub fn next_update(&self) -> Option<Instant> {
+        match &self.state {
+            ConnectionState::Disconnected | ConnectionState::Opened(_) => Some(Instant::now()),
+            &ConnectionState::BackingOff(instant) | &ConnectionState::Greeting(_, instant) => {
+                Some(instant)
+            }
+            ConnectionState::Opening(..) => None,
+            ConnectionState::Active(_) => {
+                let acknowledgements = self.acknowledgements.next_poll();
+                let keys = self.encryption.as_ref().and_then(Encryption::next_poll);
+                [Some(self.heartbeat.next_poll()), acknowledgements, keys]
+                    .into_iter()
+                    .flatten()
+                    .min()
+            }
+        }
     }
 
     /// Update the [Connection] and return the received messages.
@@ -348,14 +1039,33 @@ impl Connection {
     pub fn update(&mut self) -> LinkedList<(Uuid, Vec<u8>)> {
         let mut messages = LinkedList::new();
         self.state = match std::mem::replace(&mut self.state, ConnectionState::Disconnected) {
-            ConnectionState::Disconnected => self.create_stream(),
-            ConnectionState::Connecting(stream, start) => self.check_connection(stream, start),
-            ConnectionState::Connected(stream) => self.start_handshake(stream),
-            ConnectionState::Handshaking(handshake) => self.continue_handshake(handshake),
-            ConnectionState::Handshaked(socket) => self.start_authentication(socket),
-            ConnectionState::Registering(socket) => self.get_registration_response(socket),
-            ConnectionState::Active(socket) => self.update_connection(socket, &mut messages),
+            ConnectionState::BackingOff(retry_at) if Instant::now() < retry_at => {
+                ConnectionState::BackingOff(retry_at)
+            }
+            ConnectionState::Disconnected | ConnectionState::BackingOff(_) => self.connect(),
+            ConnectionState::Opening(link, _) => self.open_link(link),
+            ConnectionState::Opened(link) => self.start_authentication(link),
+            ConnectionState::Greeting(link, deadline) => self.get_welcome(link, deadline),
+            ConnectionState::Active(link) => self.update_connection(link, &mut messages),
         };
+
+        // Keep track of the status changes.
+        let status = self.current_status();
+        if status != self.status {
+            if status == ConnectionStatus::Active {
+                self.retry.attempts = 0;
+                self.heartbeat.reset();
+            }
+            if self.status == ConnectionStatus::Active {
+                self.to_send.connection_lost();
+            }
+            if self.status_changes.len() >= MAX_STATUS_CHANGES {
+                self.status_changes.pop_front();
+            }
+            self.status_changes.push_back(status);
+            self.status = status;
+        }
+
         messages
     }
 }
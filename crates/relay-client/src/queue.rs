@@ -0,0 +1,309 @@
+//! The queue of the messages waiting to be sent to the relay server.
+
+use std::collections::LinkedList;
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use relay_protocol::{Flags, Frame, Request};
+
+/// What to do when a message is sent while the outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Remove the oldest message of the queue to make room for the new one.
+    DropOldest,
+
+    /// Discard the new message.
+    DropNewest,
+
+    /// Discard the new message and return a [QueueFull] error to the caller.
+    Error,
+}
+
+/// The configuration of the outbound queue of a
+/// [Connection](crate::Connection).
+///
+/// The capacity and the policy only apply to the messages sent to other
+/// clients. The requests to the relay server, the receipts and the key
+/// exchanges are always kept, so they are never lost to a burst of messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// The maximum number of messages to other clients waiting to be sent.
+    pub capacity: usize,
+
+    /// What to do when the queue is full.
+    pub policy: QueuePolicy,
+
+    /// Whether the queued messages are kept when the connection is lost, to
+    /// be sent after reconnecting, or discarded.
+    pub keep_on_reconnect: bool,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            policy: QueuePolicy::DropOldest,
+            keep_on_reconnect: true,
+        }
+    }
+}
+
+/// The error returned when a message can't be sent because the outbound queue
+/// is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull;
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the outbound queue is full")
+    }
+}
+
+impl Error for QueueFull {}
+
+/// A bounded queue of messages waiting to be sent.
+pub struct OutboundQueue {
+    /// The configuration of the queue.
+    pub config: QueueConfig,
+
+    /// The messages waiting to be sent.
+    messages: Mutex<LinkedList<Frame<Request>>>,
+
+    /// The number of messages that have been dropped.
+    dropped: AtomicU64,
+}
+
+impl OutboundQueue {
+    /// Create a new empty [OutboundQueue].
+    pub const fn new(config: QueueConfig) -> Self {
+        Self {
+            config,
+            messages: Mutex::new(LinkedList::new()),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Add a message to the queue, applying the [QueuePolicy] if it's full.
+    pub fn push(&self, message: Frame<Request>) -> Result<(), QueueFull> {
+        let Some(mut messages) = self.lock() else {
+            return Err(QueueFull);
+        };
+
+        // The protocol frames are not limited by the capacity.
+        if is_protocol(&message) {
+            messages.push_back(message);
+            return Ok(());
+        }
+
+        // Only the oldest message to another client can make room.
+        if count_messages(&messages) >= self.config.capacity {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            match self.config.policy {
+                QueuePolicy::DropOldest => {
+                    let Some(oldest) = messages.iter().position(|message| !is_protocol(message))
+                    else {
+                        return Ok(());
+                    };
+                    let mut newer = messages.split_off(oldest);
+                    newer.pop_front();
+                    messages.append(&mut newer);
+                }
+                QueuePolicy::DropNewest => return Ok(()),
+                QueuePolicy::Error => return Err(QueueFull),
+            }
+        }
+        messages.push_back(message);
+        Ok(())
+    }
+
+    /// Add a message that was already accepted by the queue, like a message
+    /// that waited for the key of its target, without applying the
+    /// [QueuePolicy].
+    pub fn requeue(&self, message: Frame<Request>) {
+        if let Some(mut messages) = self.lock() {
+            messages.push_back(message);
+        }
+    }
+
+    /// Lock the queue, returning [None] if the lock is poisoned.
+    pub fn lock(&self) -> Option<MutexGuard<'_, LinkedList<Frame<Request>>>> {
+        self.messages.lock().ok()
+    }
+
+    /// Returns the number of messages in the queue.
+    pub fn len(&self) -> usize {
+        self.lock().map_or(0, |messages| messages.len())
+    }
+
+    /// Returns true if a new message would make the queue apply its
+    /// [QueuePolicy].
+    #[cfg(feature = "async")]
+    pub fn is_full(&self) -> bool {
+        self.config.capacity > 0
+            && self
+                .lock()
+                .is_some_and(|messages| count_messages(&messages) >= self.config.capacity)
+    }
+
+    /// Returns the number of messages that have been dropped because the
+    /// queue was full or the connection was lost.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Called when the connection is lost, to discard the queued messages if
+    /// they should not be kept.
+    pub fn connection_lost(&self) {
+        if self.config.keep_on_reconnect {
+            return;
+        }
+        if let Some(mut messages) = self.lock() {
+            self.dropped
+                .fetch_add(messages.len() as u64, Ordering::Relaxed);
+            messages.clear();
+        }
+    }
+}
+
+/// Returns true if a frame is part of the protocol rather than a message to
+/// another client: a request to the relay server other than a message to a
+/// room, a receipt or a key exchange.
+const fn is_protocol(frame: &Frame<Request>) -> bool {
+    match frame {
+        Frame::Control(Request::SendToRoom { .. }) => false,
+        Frame::Control(_) => true,
+        Frame::Data { flags, .. } => {
+            flags.contains(Flags::RECEIPT) || flags.contains(Flags::KEY_EXCHANGE)
+        }
+    }
+}
+
+/// Returns the number of messages to other clients in a queue.
+fn count_messages(messages: &LinkedList<Frame<Request>>) -> usize {
+    messages
+        .iter()
+        .filter(|message| !is_protocol(message))
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    /// Returns a message to another client with the given payload.
+    fn message(payload: u8) -> Frame<Request> {
+        Frame::Data {
+            peer: Uuid::nil(),
+            flags: Flags::NONE,
+            payload: vec![payload],
+        }
+    }
+
+    /// Returns a receipt, which is a protocol frame.
+    fn receipt() -> Frame<Request> {
+        Frame::Data {
+            peer: Uuid::nil(),
+            flags: Flags::RECEIPT,
+            payload: vec![0; 8],
+        }
+    }
+
+    /// Returns a queue with a capacity of 2 and the given policy, holding a
+    /// subscription, two messages and a receipt.
+    fn full_queue(policy: QueuePolicy) -> Result<OutboundQueue, QueueFull> {
+        let queue = OutboundQueue::new(QueueConfig {
+            capacity: 2,
+            policy,
+            keep_on_reconnect: true,
+        });
+        queue.push(Frame::Control(Request::Subscribe(Uuid::nil())))?;
+        queue.push(message(1))?;
+        queue.push(message(2))?;
+        queue.push(receipt())?;
+        Ok(queue)
+    }
+
+    /// Returns the frames of a queue.
+    fn contents(queue: &OutboundQueue) -> Vec<Frame<Request>> {
+        queue
+            .lock()
+            .map(|messages| messages.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The oldest message is dropped, but not the protocol frames before it.
+    #[test]
+    fn drop_oldest() -> Result<(), QueueFull> {
+        let queue = full_queue(QueuePolicy::DropOldest)?;
+        queue.push(message(3))?;
+        assert_eq!(
+            contents(&queue),
+            [
+                Frame::Control(Request::Subscribe(Uuid::nil())),
+                message(2),
+                receipt(),
+                message(3),
+            ]
+        );
+        assert_eq!(queue.dropped(), 1);
+        Ok(())
+    }
+
+    /// The new message is dropped silently.
+    #[test]
+    fn drop_newest() -> Result<(), QueueFull> {
+        let queue = full_queue(QueuePolicy::DropNewest)?;
+        let before = contents(&queue);
+        queue.push(message(3))?;
+        assert_eq!(contents(&queue), before);
+        assert_eq!(queue.dropped(), 1);
+        Ok(())
+    }
+
+    /// The new message is refused with an error.
+    #[test]
+    fn error() -> Result<(), QueueFull> {
+        let queue = full_queue(QueuePolicy::Error)?;
+        let before = contents(&queue);
+        assert_eq!(queue.push(message(3)), Err(QueueFull));
+        assert_eq!(contents(&queue), before);
+        assert_eq!(queue.dropped(), 1);
+        Ok(())
+    }
+
+    /// The protocol frames are accepted by a full queue with any policy.
+    #[test]
+    fn protocol_frames_kept() -> Result<(), QueueFull> {
+        for policy in [
+            QueuePolicy::DropOldest,
+            QueuePolicy::DropNewest,
+            QueuePolicy::Error,
+        ] {
+            let queue = full_queue(policy)?;
+            queue.push(Frame::Control(Request::RotateSecret))?;
+            queue.push(receipt())?;
+            assert_eq!(queue.len(), 6);
+            assert_eq!(queue.dropped(), 0);
+        }
+        Ok(())
+    }
+
+    /// A queue without capacity only holds protocol frames.
+    #[test]
+    fn zero_capacity() -> Result<(), QueueFull> {
+        let queue = OutboundQueue::new(QueueConfig {
+            capacity: 0,
+            policy: QueuePolicy::DropOldest,
+            keep_on_reconnect: true,
+        });
+        queue.push(receipt())?;
+        queue.push(message(1))?;
+        assert_eq!(contents(&queue), [receipt()]);
+        assert_eq!(queue.dropped(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+//! The status of a connection and the policy used to reconnect.
+
+use std::time::{Duration, Instant};
+
+use log::warn;
+use rand::Rng;
+
+/// The status of a [Connection](crate::Connection).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    /// The connection to the relay server is being established.
+    Connecting,
+
+    /// The handshake with the relay server is in progress.
+    Handshaking,
+
+    /// The connection is registering a new identity with the relay server.
+    Registering,
+
+    /// The connection is ready to send and receive messages.
+    Active,
+
+    /// The connection failed and is waiting before retrying.
+    BackingOff {
+        /// The time of the next connection attempt.
+        retry_at: Instant,
+    },
+}
+
+/// The exponential backoff policy used to wait between connection attempts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    /// The delay before the first retry.
+    pub initial: Duration,
+
+    /// The maximum delay between two attempts.
+    pub max: Duration,
+
+    /// The factor applied to the delay after each failed attempt.
+    pub multiplier: f64,
+
+    /// The fraction of the delay that is randomly removed, between 0 and 1.
+    ///
+    /// This prevents all the clients from reconnecting at the same time
+    /// after a relay restart.
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.5,
+        }
+    }
+}
+
+impl Backoff {
+    /// Returns the delay to wait after the given number of failed attempts.
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let exponent = attempts.min(64) as i32;
+        let delay = self.initial.as_secs_f64() * self.multiplier.powi(exponent);
+        let delay =
+            Duration::try_from_secs_f64(delay).map_or(self.max, |delay| delay.min(self.max));
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        delay.mul_f64(1.0 - rand::thread_rng().gen_range(0.0..jitter))
+    }
+}
+
+/// Keeps track of the failed connection attempts.
+#[derive(Debug)]
+pub struct Retry {
+    /// The backoff policy.
+    pub backoff: Backoff,
+
+    /// The number of failed attempts since the last successful connection.
+    pub attempts: u32,
+
+    /// The last error that made the connection fail.
+    pub last_error: Option<String>,
+}
+
+impl Retry {
+    /// Create a new [Retry] with the given backoff policy.
+    pub const fn new(backoff: Backoff) -> Self {
+        Self {
+            backoff,
+            attempts: 0,
+            last_error: None,
+        }
+    }
+
+    /// Record a connection failure and returns the time of the next attempt.
+    pub fn fail(&mut self, error: String) -> Instant {
+        warn!("{error}");
+        let retry_at = Instant::now() + self.backoff.delay(self.attempts);
+        self.attempts = self.attempts.saturating_add(1);
+        self.last_error = Some(error);
+        retry_at
+    }
+}
@@ -0,0 +1,265 @@
+//! A [Transport] using a websocket driven by the tokio runtime.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::{SinkExt, StreamExt};
+use rand::seq::SliceRandom;
+use rustls::ClientConfig;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::http::Uri;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{client_async_tls_with_config, Connector};
+
+use super::websocket::{close_error, io_error, resolve, tls_config, CONNECT_TIMEOUT};
+use super::{Frame, Link, LinkState, Transport};
+
+/// The number of frames waiting to be written after which the [Link] asks
+/// to stop sending.
+const MAX_PENDING: usize = 256;
+
+/// A [Transport] connecting to a relay server with a websocket driven by a
+/// tokio task.
+///
+/// The [Link]s must be created inside a tokio runtime. Each event received
+/// from the relay server wakes the given [Notify], so the connection can be
+/// updated without polling it continuously.
+pub struct AsyncWebSocketTransport {
+    /// The address list corresponding to the relay server.
+    address_list: Vec<SocketAddr>,
+
+    /// The URL of the relay server.
+    url: Uri,
+
+    /// The TLS configuration used to secure the connections, or [None] to
+    /// use the default one.
+    tls_config: Option<Arc<ClientConfig>>,
+
+    /// The notification sent when something happens on a [Link].
+    wake: Arc<Notify>,
+}
+
+impl AsyncWebSocketTransport {
+    /// Create a new [AsyncWebSocketTransport] to the relay server at the
+    /// given URL.
+    pub fn new(url: &str, wake: Arc<Notify>) -> io::Result<Self> {
+        let (url, address_list) = resolve(url)?;
+        Ok(Self {
+            address_list,
+            url,
+            tls_config: None,
+            wake,
+        })
+    }
+
+    /// Trust the certificates of a PEM file in addition to the native root
+    /// certificates when using the `wss` scheme.
+    pub fn trust_certificates(mut self, path: &Path) -> io::Result<Self> {
+        self.tls_config = Some(tls_config(path)?);
+        Ok(self)
+    }
+}
+
+impl Transport for AsyncWebSocketTransport {
+    fn connect(&mut self) -> io::Result<Box<dyn Link>> {
+        // Take a random relay address.
+        let Some(&address) = self.address_list.choose(&mut rand::thread_rng()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no relay address available",
+            ));
+        };
+
+        // Open the websocket in the background.
+        let (events, receiver) = unbounded_channel();
+        let (sender, outgoing) = unbounded_channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(run(
+            address,
+            self.url.clone(),
+            self.tls_config.clone(),
+            Arc::clone(&self.wake),
+            events,
+            outgoing,
+            Arc::clone(&pending),
+        ));
+        Ok(Box::new(AsyncWebSocketLink {
+            open: false,
+            events: receiver,
+            sender,
+            pending,
+        }))
+    }
+}
+
+/// An event of a websocket, sent by its task to its [Link].
+enum Event {
+    /// The websocket is open.
+    Opened,
+
+    /// A frame was received.
+    Received(Frame),
+
+    /// The websocket failed or was closed.
+    Failed(io::Error),
+}
+
+/// A [Link] created by an [AsyncWebSocketTransport].
+struct AsyncWebSocketLink {
+    /// Whether the websocket is open.
+    open: bool,
+
+    /// The events of the websocket.
+    events: UnboundedReceiver<Event>,
+
+    /// The sender of the messages to write to the websocket.
+    sender: UnboundedSender<Message>,
+
+    /// The number of messages waiting to be written.
+    pending: Arc<AtomicUsize>,
+}
+
+impl AsyncWebSocketLink {
+    /// Send a message to the task of the websocket.
+    fn write(&self, message: Message) -> io::Result<()> {
+        let pending = self.pending.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.sender.send(message).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the websocket is closed",
+            ));
+        }
+        match pending >= MAX_PENDING {
+            true => Err(io::ErrorKind::WouldBlock.into()),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Link for AsyncWebSocketLink {
+    fn poll_open(&mut self) -> io::Result<LinkState> {
+        if self.open {
+            return Ok(LinkState::Open);
+        }
+        match self.events.try_recv() {
+            Ok(Event::Opened) => {
+                self.open = true;
+                Ok(LinkState::Open)
+            }
+            Ok(Event::Failed(e)) => Err(e),
+            Ok(Event::Received(_)) | Err(TryRecvError::Empty) => Ok(LinkState::Connecting),
+            Err(TryRecvError::Disconnected) => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the websocket is closed",
+            )),
+        }
+    }
+
+    fn send(&mut self, frame: Vec<u8>) -> io::Result<()> {
+        self.write(Message::Binary(frame))
+    }
+
+    fn ping(&mut self, payload: Vec<u8>) -> io::Result<()> {
+        self.write(Message::Ping(payload))
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Frame>> {
+        match self.events.try_recv() {
+            Ok(Event::Received(frame)) => Ok(Some(frame)),
+            Ok(Event::Failed(e)) => Err(e),
+            Ok(Event::Opened) | Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the websocket is closed",
+            )),
+        }
+    }
+}
+
+/// Open a websocket to the relay server and exchange its messages with its
+/// [Link], until one of them closes it.
+async fn run(
+    address: SocketAddr,
+    url: Uri,
+    tls_config: Option<Arc<ClientConfig>>,
+    wake: Arc<Notify>,
+    events: UnboundedSender<Event>,
+    mut outgoing: UnboundedReceiver<Message>,
+    pending: Arc<AtomicUsize>,
+) {
+    // Open the websocket.
+    let connect = async {
+        let stream = TcpStream::connect(address).await.map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to start connection to the relay server: {e}"),
+            )
+        })?;
+        let connector = tls_config.map(Connector::Rustls);
+        client_async_tls_with_config(url, stream, None, connector)
+            .await
+            .map_err(io_error)
+    };
+    let result = timeout(CONNECT_TIMEOUT, connect).await.unwrap_or_else(|_| {
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "connection to the relay server timed out",
+        ))
+    });
+    let socket = match result {
+        Ok((socket, _)) => socket,
+        Err(e) => {
+            events.send(Event::Failed(e)).ok();
+            wake.notify_one();
+            return;
+        }
+    };
+    events.send(Event::Opened).ok();
+    wake.notify_one();
+
+    // Write the messages of the link and forward the received frames.
+    let (mut sink, mut stream) = socket.split();
+    loop {
+        let event = tokio::select! {
+            message = outgoing.recv() => {
+                let Some(message) = message else {
+                    sink.close().await.ok();
+                    return;
+                };
+                // Wake the connection when the link can take frames again.
+                if pending.fetch_sub(1, Ordering::Relaxed) == MAX_PENDING {
+                    wake.notify_one();
+                }
+                match sink.send(message).await {
+                    Ok(()) => continue,
+                    Err(e) => Event::Failed(io_error(e)),
+                }
+            }
+            message = stream.next() => match message {
+                Some(Ok(Message::Binary(data))) => Event::Received(Frame::Data(data)),
+                Some(Ok(Message::Text(text))) => Event::Received(Frame::Data(text.into_bytes())),
+                Some(Ok(Message::Pong(payload))) => Event::Received(Frame::Pong(payload)),
+                Some(Ok(Message::Close(Some(frame)))) => Event::Failed(close_error(&frame)),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => Event::Failed(io_error(e)),
+                None => Event::Failed(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "closed by the relay server",
+                )),
+            },
+        };
+        let failed = matches!(event, Event::Failed(_));
+        events.send(event).ok();
+        wake.notify_one();
+        if failed {
+            return;
+        }
+    }
+}
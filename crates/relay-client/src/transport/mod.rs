@@ -0,0 +1,67 @@
+//! The transports used to reach a relay server.
+
+use std::io;
+
+#[cfg(feature = "async")]
+pub use self::async_websocket::AsyncWebSocketTransport;
+pub use self::loopback::{LoopbackHub, LoopbackTransport};
+pub use self::websocket::WebSocketTransport;
+
+#[cfg(feature = "async")]
+mod async_websocket;
+mod loopback;
+mod websocket;
+
+/// The state of a [Link] being opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    /// The underlying connection is being established.
+    Connecting,
+
+    /// The underlying connection is established and a handshake is in
+    /// progress.
+    Handshaking,
+
+    /// The [Link] is ready to send and receive frames.
+    Open,
+}
+
+/// A frame received from a relay server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// A data frame.
+    Data(Vec<u8>),
+
+    /// The answer to a ping, with the payload of the ping.
+    Pong(Vec<u8>),
+}
+
+/// A bidirectional channel of binary frames with a relay server.
+///
+/// All the methods of a [Link] must return without blocking.
+pub trait Link: Send + Sync {
+    /// Make progress on opening the [Link] and returns its state.
+    fn poll_open(&mut self) -> io::Result<LinkState>;
+
+    /// Send a frame to the relay server.
+    ///
+    /// An error of kind [io::ErrorKind::WouldBlock] means that the frame has
+    /// been accepted but the [Link] can't take more frames for now.
+    fn send(&mut self, frame: Vec<u8>) -> io::Result<()>;
+
+    /// Send a ping with the given payload to the relay server.
+    ///
+    /// The relay server answers with a [Frame::Pong] with the same payload.
+    fn ping(&mut self, payload: Vec<u8>) -> io::Result<()>;
+
+    /// Receive a frame from the relay server.
+    ///
+    /// Returns [None] if no frame is available for now.
+    fn recv(&mut self) -> io::Result<Option<Frame>>;
+}
+
+/// A way to reach a relay server.
+pub trait Transport: Send + Sync {
+    /// Start opening a new [Link] to the relay server.
+    fn connect(&mut self) -> io::Result<Box<dyn Link>>;
+}
@@ -0,0 +1,322 @@
+//! A [Transport] using a websocket over TCP, optionally secured with TLS.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use mio::net::TcpStream;
+use rand::seq::SliceRandom;
+use relay_protocol::CloseReason;
+use rustls::{ClientConfig, RootCertStore};
+use tungstenite::handshake::MidHandshake;
+use tungstenite::http::Uri;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{ClientHandshake, Connector, HandshakeError, Message, WebSocket};
+
+use super::{Frame, Link, LinkState, Transport};
+
+/// The maximum time to wait for the connection to the relay server.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The stream used by a websocket, optionally secured with TLS.
+type Stream = MaybeTlsStream<TcpStream>;
+
+/// A [Transport] connecting to a relay server with a websocket.
+pub struct WebSocketTransport {
+    /// The address list corresponding to the relay server.
+    address_list: Vec<SocketAddr>,
+
+    /// The URL of the relay server.
+    url: Uri,
+
+    /// The TLS configuration used to secure the connections, or [None] to
+    /// use the default one.
+    tls_config: Option<Arc<ClientConfig>>,
+}
+
+impl WebSocketTransport {
+    /// Create a new [WebSocketTransport] to the relay server at the given URL.
+    ///
+    /// The URL must use the `ws` or `wss` scheme. If it has no port, the
+    /// default port of the scheme is used.
+    pub fn new(url: &str) -> io::Result<Self> {
+        let (url, address_list) = resolve(url)?;
+        Ok(Self {
+            address_list,
+            url,
+            tls_config: None,
+        })
+    }
+
+    /// Trust the certificates of a PEM file in addition to the native root
+    /// certificates when using the `wss` scheme.
+    ///
+    /// This allows to connect to a relay server using a self-signed
+    /// certificate, for example in a local test setup.
+    pub fn trust_certificates(mut self, path: &Path) -> io::Result<Self> {
+        self.tls_config = Some(tls_config(path)?);
+        Ok(self)
+    }
+}
+
+/// Parse the URL of a relay server and resolve its addresses.
+///
+/// The URL must use the `ws` or `wss` scheme. If it has no port, the default
+/// port of the scheme is used.
+pub(super) fn resolve(url: &str) -> io::Result<(Uri, Vec<SocketAddr>)> {
+    // Parse the URL of the relay server.
+    let url: Uri = url
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let default_port = match url.scheme_str() {
+        Some("ws") => 80,
+        Some("wss") => 443,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "relay url scheme must be ws or wss",
+            ));
+        }
+    };
+    let host = url
+        .host()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "relay url has no host"))?;
+    let port = url.port_u16().unwrap_or(default_port);
+
+    // Resolve the addresses of the relay server.
+    let address_list = (host, port).to_socket_addrs()?.collect();
+    Ok((url, address_list))
+}
+
+/// Create a TLS configuration trusting the certificates of a PEM file in
+/// addition to the native root certificates.
+pub(super) fn tls_config(path: &Path) -> io::Result<Arc<ClientConfig>> {
+    // Add the native root certificates.
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(rustls_native_certs::load_native_certs()?);
+
+    // Add the certificates of the file.
+    let mut file = BufReader::new(File::open(path)?);
+    for certificate in rustls_pemfile::certs(&mut file) {
+        roots
+            .add(certificate?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    }
+
+    // Create the TLS configuration.
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+impl Transport for WebSocketTransport {
+    fn connect(&mut self) -> io::Result<Box<dyn Link>> {
+        // Take a random relay address.
+        let Some(address) = self.address_list.choose(&mut rand::thread_rng()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no relay address available",
+            ));
+        };
+
+        // Create the new TCP stream.
+        let stream = TcpStream::connect(address.to_owned()).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to start connection to the relay server: {e}"),
+            )
+        })?;
+        Ok(Box::new(WebSocketLink {
+            url: self.url.clone(),
+            tls_config: self.tls_config.clone(),
+            state: WebSocketState::Connecting(stream, Instant::now()),
+        }))
+    }
+}
+
+/// The state of a [WebSocketLink].
+#[derive(Debug)]
+enum WebSocketState {
+    /// The [Link] is closed.
+    Closed,
+
+    /// The underlying [TcpStream] is connecting.
+    Connecting(TcpStream, Instant),
+
+    /// The underlying [TcpStream] is connected.
+    Connected(TcpStream),
+
+    /// The websocket handshake is in progress.
+    Handshaking(MidHandshake<ClientHandshake<Stream>>),
+
+    /// The websocket is open.
+    Open(WebSocket<Stream>),
+}
+
+/// A [Link] created by a [WebSocketTransport].
+struct WebSocketLink {
+    /// The URL of the relay server.
+    url: Uri,
+
+    /// The TLS configuration used to secure the connection, if not the
+    /// default one.
+    tls_config: Option<Arc<ClientConfig>>,
+
+    /// The state of the link.
+    state: WebSocketState,
+}
+
+impl WebSocketLink {
+    /// Check if the [TcpStream] is connected.
+    fn check_connection(stream: TcpStream, start: Instant) -> io::Result<WebSocketState> {
+        // Check for connection errors.
+        if let Err(e) = stream.take_error() {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failed to connect to the relay server: {e}"),
+            ));
+        }
+
+        // Check if the stream is connected.
+        let connected = match stream.peek(&mut [0]) {
+            Ok(_) => true,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => true,
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => false,
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("failed to connect to the relay server: {e}"),
+                ));
+            }
+        };
+
+        // Check if the connection has timed out.
+        let elapsed = start.elapsed();
+        if elapsed > CONNECT_TIMEOUT {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "connection to the relay server timed out",
+            ));
+        }
+
+        // Update the connection state if connected.
+        match connected {
+            true => Ok(WebSocketState::Connected(stream)),
+            false => Ok(WebSocketState::Connecting(stream, start)),
+        }
+    }
+
+    /// Start the websocket handshake.
+    fn start_handshake(&self, stream: TcpStream) -> io::Result<WebSocketState> {
+        Self::handshake_result(tungstenite::client_tls_with_config(
+            &self.url,
+            stream,
+            None,
+            self.tls_config.clone().map(Connector::Rustls),
+        ))
+    }
+
+    /// Convert the result of a websocket handshake step into a new state.
+    fn handshake_result<T>(
+        result: Result<(WebSocket<Stream>, T), HandshakeError<ClientHandshake<Stream>>>,
+    ) -> io::Result<WebSocketState> {
+        match result {
+            Ok((socket, _)) => Ok(WebSocketState::Open(socket)),
+            Err(HandshakeError::Interrupted(handshake)) => {
+                Ok(WebSocketState::Handshaking(handshake))
+            }
+            Err(HandshakeError::Failure(e)) => Err(io::Error::other(format!(
+                "handshake failed with the relay server: {e}"
+            ))),
+        }
+    }
+
+    /// Returns the websocket if the link is open.
+    fn socket(&mut self) -> io::Result<&mut WebSocket<Stream>> {
+        match &mut self.state {
+            WebSocketState::Open(socket) => Ok(socket),
+            _ => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the websocket is not open",
+            )),
+        }
+    }
+}
+
+/// Convert a websocket error into an [io::Error].
+pub(super) fn io_error(error: tungstenite::Error) -> io::Error {
+    match error {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+/// Convert a close frame received from the relay server into an error.
+pub(super) fn close_error(frame: &CloseFrame<'_>) -> io::Error {
+    let code = u16::from(frame.code);
+    let reason = CloseReason::from_code(code)
+        .map_or_else(|| frame.reason.to_string(), |reason| reason.to_string());
+    let message = format!("closed by the relay server ({code}): {reason}");
+    io::Error::new(io::ErrorKind::ConnectionAborted, message)
+}
+
+impl Link for WebSocketLink {
+    fn poll_open(&mut self) -> io::Result<LinkState> {
+        self.state = match std::mem::replace(&mut self.state, WebSocketState::Closed) {
+            WebSocketState::Closed => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "the websocket is closed",
+                ));
+            }
+            WebSocketState::Connecting(stream, start) => Self::check_connection(stream, start)?,
+            WebSocketState::Connected(stream) => self.start_handshake(stream)?,
+            WebSocketState::Handshaking(handshake) => {
+                Self::handshake_result(handshake.handshake())?
+            }
+            WebSocketState::Open(socket) => WebSocketState::Open(socket),
+        };
+        Ok(match self.state {
+            WebSocketState::Closed | WebSocketState::Connecting(..) => LinkState::Connecting,
+            WebSocketState::Connected(_) | WebSocketState::Handshaking(_) => LinkState::Handshaking,
+            WebSocketState::Open(_) => LinkState::Open,
+        })
+    }
+
+    fn send(&mut self, frame: Vec<u8>) -> io::Result<()> {
+        self.socket()?
+            .send(Message::Binary(frame))
+            .map_err(io_error)
+    }
+
+    fn ping(&mut self, payload: Vec<u8>) -> io::Result<()> {
+        self.socket()?
+            .send(Message::Ping(payload))
+            .map_err(io_error)
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Frame>> {
+        let socket = self.socket()?;
+        loop {
+            match socket.read() {
+                Ok(Message::Binary(data)) => return Ok(Some(Frame::Data(data))),
+                Ok(Message::Text(text)) => return Ok(Some(Frame::Data(text.into_bytes()))),
+                Ok(Message::Pong(payload)) => return Ok(Some(Frame::Pong(payload))),
+                Ok(Message::Close(Some(frame))) => return Err(close_error(&frame)),
+                Ok(_) => continue,
+                Err(tungstenite::Error::Io(ref e))
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::Interrupted =>
+                {
+                    return Ok(None);
+                }
+                Err(e) => return Err(io_error(e)),
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! Helpers shared by the integration tests.
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use relay_client::{Connection, ConnectionStatus};
+use uuid::Uuid;
+
+/// The maximum time to wait for something to happen.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The time between two updates of the clients.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Update the connections until the condition is true, or returns false
+/// after the [TIMEOUT].
+///
+/// The condition is given the messages received by each connection during
+/// the last update.
+pub fn update_until(
+    connections: &mut [&mut Connection],
+    mut condition: impl FnMut(&mut [&mut Connection], &[Vec<(Uuid, Vec<u8>)>]) -> bool,
+) -> bool {
+    let deadline = Instant::now() + TIMEOUT;
+    while Instant::now() < deadline {
+        let messages: Vec<_> = connections
+            .iter_mut()
+            .map(|connection| connection.update().into_iter().collect())
+            .collect();
+        if condition(connections, &messages) {
+            return true;
+        }
+        sleep(UPDATE_INTERVAL);
+    }
+    false
+}
+
+/// Update a connection until it's active, and returns its identifier.
+pub fn activate(connection: &mut Connection) -> Option<Uuid> {
+    let active = update_until(&mut [&mut *connection], |connections, _| {
+        connections[0].status() == ConnectionStatus::Active
+    });
+    active.then(|| connection.identifier()).flatten()
+}
@@ -0,0 +1,178 @@
+//! Tests of the end-to-end encryption between clients of a [LoopbackHub].
+
+mod common;
+
+use std::io;
+
+use relay_client::{Connection, CredentialStore, LoopbackHub, QueueConfig, QueuePolicy};
+use uuid::Uuid;
+
+use self::common::{activate, update_until};
+
+/// Returns a new ephemeral connection to the hub, with the encryption
+/// enabled or not, once it's active.
+fn connect(hub: &LoopbackHub, encrypted: bool) -> io::Result<Connection> {
+    let mut builder = Connection::builder_with_transport(hub.transport()).ephemeral();
+    if encrypted {
+        builder = builder.encryption();
+    }
+    active(builder.build()?)
+}
+
+/// Update a connection until it's active, and returns it.
+fn active(mut connection: Connection) -> io::Result<Connection> {
+    match activate(&mut connection) {
+        Some(_) => Ok(connection),
+        None => Err(io::Error::other("the connection should be active")),
+    }
+}
+
+/// Update both connections until the receiver receives a message, and
+/// returns it.
+///
+/// The messages received by the sender are discarded.
+fn receive(sender: &mut Connection, receiver: &mut Connection) -> Option<(Uuid, Vec<u8>)> {
+    let mut received = None;
+    update_until(&mut [sender, receiver], |_, messages| {
+        received = messages[1].first().cloned();
+        received.is_some()
+    });
+    received
+}
+
+/// Returns the identifier of a connection.
+fn identifier(connection: &Connection) -> io::Result<Uuid> {
+    connection
+        .identifier()
+        .ok_or_else(|| io::Error::other("the connection should have an identifier"))
+}
+
+/// Two clients exchange their keys before their first messages, and receive
+/// each other's messages.
+#[test]
+fn key_exchange_and_round_trip() -> io::Result<()> {
+    let hub = LoopbackHub::new();
+    let mut alice = connect(&hub, true)?;
+    let mut bob = connect(&hub, true)?;
+    let (alice_id, bob_id) = (identifier(&alice)?, identifier(&bob)?);
+
+    alice.send(bob_id, b"hello".as_slice()).ok();
+    assert_eq!(
+        receive(&mut alice, &mut bob),
+        Some((alice_id, b"hello".to_vec()))
+    );
+    assert_eq!(alice.peer_key(bob_id), bob.public_key());
+    assert_eq!(bob.peer_key(alice_id), alice.public_key());
+
+    bob.send(alice_id, b"hi".as_slice()).ok();
+    assert_eq!(
+        receive(&mut bob, &mut alice),
+        Some((bob_id, b"hi".to_vec()))
+    );
+    assert_eq!(alice.rejected_messages(), 0);
+    assert_eq!(bob.rejected_messages(), 0);
+    Ok(())
+}
+
+/// The messages waiting for the key of their target are all sent once it's
+/// received, even if they don't fit in the outbound queue.
+#[test]
+fn waiting_messages_kept() -> io::Result<()> {
+    let hub = LoopbackHub::new();
+    let queue = QueueConfig {
+        capacity: 2,
+        policy: QueuePolicy::Error,
+        keep_on_reconnect: true,
+    };
+    let builder = Connection::builder_with_transport(hub.transport()).ephemeral();
+    let mut alice = active(builder.queue(queue).encryption().build()?)?;
+    let mut bob = connect(&hub, true)?;
+    let (alice_id, bob_id) = (identifier(&alice)?, identifier(&bob)?);
+
+    // Send more messages than the queue can hold before bob's key is known.
+    for index in 0..5_u8 {
+        alice.send(bob_id, [index].as_slice()).ok();
+        alice.update();
+    }
+    let expected: Vec<_> = (0..5_u8).map(|index| (alice_id, vec![index])).collect();
+    let mut received = Vec::new();
+    assert!(update_until(&mut [&mut alice, &mut bob], |_, messages| {
+        received.extend(messages[1].iter().cloned());
+        received.len() >= expected.len()
+    }));
+    assert_eq!(received, expected);
+    assert_eq!(alice.dropped_messages(), 0);
+    Ok(())
+}
+
+/// A client with the encryption enabled drops the messages that are not
+/// encrypted.
+#[test]
+fn plaintext_rejected() -> io::Result<()> {
+    let hub = LoopbackHub::new();
+    let mut alice = connect(&hub, false)?;
+    let mut bob = connect(&hub, true)?;
+    let bob_id = identifier(&bob)?;
+
+    alice.send(bob_id, b"hello".as_slice()).ok();
+    assert!(update_until(
+        &mut [&mut alice, &mut bob],
+        |connections, messages| {
+            assert!(messages[1].is_empty());
+            connections[1].rejected_messages() == 1
+        }
+    ));
+    Ok(())
+}
+
+/// A client announcing a key different from the one pinned by its peer
+/// can't exchange messages with it until the peer forgets the pinned key.
+#[test]
+fn changed_key_rejected() -> io::Result<()> {
+    let hub = LoopbackHub::new();
+    let store = CredentialStore::new(
+        std::env::temp_dir().join(format!("relay-credentials-{}", Uuid::new_v4())),
+    );
+    let builder = || {
+        Connection::builder_with_transport(hub.transport())
+            .credentials_path(store.path())
+            .encryption()
+    };
+    let mut alice = active(builder().build()?)?;
+    let mut bob = connect(&hub, true)?;
+    let (alice_id, bob_id) = (identifier(&alice)?, identifier(&bob)?);
+    alice.send(bob_id, b"hello".as_slice()).ok();
+    assert!(receive(&mut alice, &mut bob).is_some());
+    let pinned = bob.peer_key(alice_id);
+
+    // The same identity reconnects with new keys.
+    drop(alice);
+    std::fs::remove_file(store.keys_path())?;
+    let mut alice = active(builder().build()?)?;
+    assert_eq!(identifier(&alice)?, alice_id);
+    assert_ne!(alice.public_key(), pinned);
+    alice.send(bob_id, b"forged".as_slice()).ok();
+    assert_eq!(receive(&mut alice, &mut bob), None);
+    assert_eq!(bob.peer_key(alice_id), pinned);
+
+    // Once the previous key is forgotten, the new one is pinned and the
+    // waiting messages are received.
+    bob.forget_peer_key(alice_id);
+    bob.send(alice_id, b"again".as_slice()).ok();
+    let mut received = [Vec::new(), Vec::new()];
+    assert!(update_until(&mut [&mut alice, &mut bob], |_, messages| {
+        for (received, messages) in received.iter_mut().zip(messages) {
+            received.extend(messages.iter().cloned());
+        }
+        received.iter().all(|messages| !messages.is_empty())
+    }));
+    assert_eq!(
+        received,
+        [
+            vec![(bob_id, b"again".to_vec())],
+            vec![(alice_id, b"forged".to_vec())]
+        ]
+    );
+    assert_eq!(bob.peer_key(alice_id), alice.public_key());
+    store.delete()
+}
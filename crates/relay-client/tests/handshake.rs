@@ -0,0 +1,73 @@
+//! Tests of the handshake of a connection with a relay server that never
+//! answers.
+
+use std::io;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use relay_client::{
+    Connection, ConnectionStatus, Frame, KeepaliveConfig, Link, LinkState, Transport,
+};
+
+/// A [Transport] whose links open immediately and never receive anything.
+struct SilentTransport;
+
+impl Transport for SilentTransport {
+    fn connect(&mut self) -> io::Result<Box<dyn Link>> {
+        Ok(Box::new(SilentLink))
+    }
+}
+
+/// A [Link] created by a [SilentTransport].
+struct SilentLink;
+
+impl Link for SilentLink {
+    fn poll_open(&mut self) -> io::Result<LinkState> {
+        Ok(LinkState::Open)
+    }
+
+    fn send(&mut self, _frame: Vec<u8>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn ping(&mut self, _payload: Vec<u8>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Frame>> {
+        Ok(None)
+    }
+}
+
+/// A connection whose hello message is never answered backs off once the
+/// keepalive timeout is reached.
+#[test]
+fn unanswered_hello() -> io::Result<()> {
+    let timeout = Duration::from_millis(50);
+    let mut connection = Connection::builder_with_transport(SilentTransport)
+        .ephemeral()
+        .keepalive(KeepaliveConfig {
+            interval: timeout,
+            timeout,
+        })
+        .build()?;
+
+    // Send the hello message, and wait until the deadline of its answer.
+    for _ in 0..3 {
+        connection.update();
+    }
+    assert_eq!(connection.status(), ConnectionStatus::Registering);
+    let deadline = connection.next_update();
+    assert!(deadline.is_some_and(|deadline| deadline > Instant::now()));
+    if let Some(deadline) = deadline {
+        sleep(deadline.saturating_duration_since(Instant::now()));
+    }
+    connection.update();
+    assert!(matches!(
+        connection.status(),
+        ConnectionStatus::BackingOff { .. }
+    ));
+    let error = connection.last_error().unwrap_or_default();
+    assert!(error.contains("didn't answer"), "{error}");
+    Ok(())
+}
@@ -0,0 +1,41 @@
+//! Tests of the identity profiles stored in the home directory.
+
+mod common;
+
+use std::io;
+
+use relay_client::{Connection, LoopbackHub, Profiles};
+use tempfile::tempdir;
+
+use self::common::activate;
+
+/// Each profile registers its own identity, which is reused by the next
+/// connections with the same profile.
+#[test]
+fn identity_per_profile() -> io::Result<()> {
+    // This test is alone in its binary, so the environment is not shared.
+    let home = tempdir()?;
+    std::env::set_var("HOME", home.path());
+
+    let hub = LoopbackHub::new();
+    let connect = |profile: &str| -> io::Result<Connection> {
+        Connection::builder_with_transport(hub.transport())
+            .profile(profile)
+            .build()
+    };
+    let mut alice = connect("alice")?;
+    let mut bob = connect("bob")?;
+    let alice_id = activate(&mut alice);
+    let bob_id = activate(&mut bob);
+    assert!(alice_id.is_some());
+    assert!(bob_id.is_some());
+    assert_ne!(alice_id, bob_id);
+    assert_eq!(Profiles::in_home()?.list()?, ["alice", "bob"]);
+
+    // The same profile connects again with the stored identity.
+    drop(alice);
+    let mut alice = connect("alice")?;
+    assert_eq!(activate(&mut alice), alice_id);
+    assert!(connect("not a profile").is_err());
+    Ok(())
+}
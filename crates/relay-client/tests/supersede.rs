@@ -0,0 +1,66 @@
+//! Tests of the sessions superseded by a newer session of the same identity
+//! on a loopback hub.
+
+mod common;
+
+use std::error::Error;
+use std::io;
+
+use relay_client::{Connection, LoopbackHub, Notification, RoomName};
+use tempfile::tempdir;
+
+use self::common::{activate, update_until};
+
+/// The rooms of a superseded session are left without notifying the newer
+/// session, while the other members see the identity leave.
+#[test]
+fn superseded_rooms() -> Result<(), Box<dyn Error>> {
+    let directory = tempdir()?;
+    let credentials = directory.path().join("credentials");
+    let hub = LoopbackHub::new();
+    let connect = || -> io::Result<Connection> {
+        Connection::builder_with_transport(hub.transport())
+            .credentials_path(&credentials)
+            .build()
+    };
+    let room = RoomName::new("lobby")?;
+
+    // Both clients join the room.
+    let mut alice = connect()?;
+    let mut bob = Connection::builder_with_transport(hub.transport())
+        .ephemeral()
+        .build()?;
+    let alice_id = activate(&mut alice);
+    assert!(alice_id.is_some());
+    assert!(activate(&mut bob).is_some());
+    alice.join_room(room.clone())?;
+    bob.join_room(room.clone())?;
+    assert!(update_until(
+        &mut [&mut alice, &mut bob],
+        |connections, _| {
+            connections[1]
+                .take_notifications()
+                .contains(&Notification::RoomJoined(room.clone()))
+        }
+    ));
+
+    // A newer session of alice supersedes the older one.
+    let mut newer = connect()?;
+    assert_eq!(activate(&mut newer), alice_id);
+    let left = Notification::MemberLeft {
+        room,
+        member: alice_id.unwrap_or_default(),
+    };
+    assert!(update_until(
+        &mut [&mut newer, &mut bob],
+        |connections, _| { connections[1].take_notifications().contains(&left) }
+    ));
+    let notifications = newer.take_notifications();
+    assert!(
+        !notifications
+            .iter()
+            .any(|notification| matches!(notification, Notification::RoomLeft(_))),
+        "{notifications:?}"
+    );
+    Ok(())
+}
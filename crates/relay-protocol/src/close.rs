@@ -0,0 +1,130 @@
+//! The reasons for which the relay server closes a session.
+
+use std::error::Error;
+use std::fmt;
+
+/// The reason for which the relay server closed a session.
+///
+/// It's sent as the code of the websocket close frame, in the range reserved
+/// for private use by the websocket protocol unless a standard code exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CloseReason {
+    /// The client sent a frame larger than the maximum frame size.
+    FrameTooLarge,
+
+    /// The client sent too many messages or bytes per second.
+    RateLimited,
+
+    /// The relay server already has too many connections.
+    TooManyConnections,
+
+    /// Too many identities were registered from the address of the client.
+    TooManyRegistrations,
+
+    /// The client didn't receive its messages fast enough.
+    SlowConsumer,
+
+    /// The client was disconnected by an administrator.
+    Kicked,
+
+    /// The identity of the client is banned.
+    Banned,
+
+    /// A newer session was opened with the same identity.
+    Superseded,
+
+    /// The identity of the client is already used by another session.
+    AlreadyConnected,
+
+    /// The relay server is shutting down, and will probably be back soon.
+    ///
+    /// This uses the standard service restart code of the websocket protocol.
+    Restarting,
+}
+
+impl CloseReason {
+    /// Returns the websocket close code of the reason.
+    pub const fn code(self) -> u16 {
+        match self {
+            Self::FrameTooLarge => 4000,
+            Self::RateLimited => 4001,
+            Self::TooManyConnections => 4002,
+            Self::TooManyRegistrations => 4003,
+            Self::SlowConsumer => 4004,
+            Self::Kicked => 4005,
+            Self::Banned => 4006,
+            Self::Superseded => 4007,
+            Self::AlreadyConnected => 4008,
+            Self::Restarting => 1012,
+        }
+    }
+
+    /// Returns the reason corresponding to a websocket close code, if any.
+    pub const fn from_code(code: u16) -> Option<Self> {
+        match code {
+            4000 => Some(Self::FrameTooLarge),
+            4001 => Some(Self::RateLimited),
+            4002 => Some(Self::TooManyConnections),
+            4003 => Some(Self::TooManyRegistrations),
+            4004 => Some(Self::SlowConsumer),
+            4005 => Some(Self::Kicked),
+            4006 => Some(Self::Banned),
+            4007 => Some(Self::Superseded),
+            4008 => Some(Self::AlreadyConnected),
+            1012 => Some(Self::Restarting),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FrameTooLarge => write!(f, "frame too large"),
+            Self::RateLimited => write!(f, "rate limit exceeded"),
+            Self::TooManyConnections => write!(f, "too many connections"),
+            Self::TooManyRegistrations => write!(f, "too many registrations"),
+            Self::SlowConsumer => write!(f, "too slow to receive messages"),
+            Self::Kicked => write!(f, "kicked by an administrator"),
+            Self::Banned => write!(f, "banned"),
+            Self::Superseded => write!(f, "superseded by a newer session"),
+            Self::AlreadyConnected => write!(f, "already connected"),
+            Self::Restarting => write!(f, "server restarting"),
+        }
+    }
+}
+
+impl Error for CloseReason {}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the close codes.
+
+    use super::*;
+
+    /// Each reason is found back from its code.
+    #[test]
+    fn code_round_trip() {
+        for reason in [
+            CloseReason::FrameTooLarge,
+            CloseReason::RateLimited,
+            CloseReason::TooManyConnections,
+            CloseReason::TooManyRegistrations,
+            CloseReason::SlowConsumer,
+            CloseReason::Kicked,
+            CloseReason::Banned,
+            CloseReason::Superseded,
+            CloseReason::AlreadyConnected,
+            CloseReason::Restarting,
+        ] {
+            assert_eq!(CloseReason::from_code(reason.code()), Some(reason));
+        }
+    }
+
+    /// The standard codes without a reason are not recognized.
+    #[test]
+    fn unknown_codes() {
+        assert_eq!(CloseReason::from_code(1000), None);
+        assert_eq!(CloseReason::from_code(4999), None);
+    }
+}
@@ -0,0 +1,530 @@
+//! The control messages exchanged between the relay server and its clients.
+
+use std::error::Error;
+use std::fmt;
+
+use uuid::Uuid;
+
+use crate::Capabilities;
+
+/// A control message that can be carried by a [Frame::Control](crate::Frame).
+pub trait Control: Sized {
+    /// Encode the control message into the payload of a control frame.
+    fn encode(&self) -> Vec<u8>;
+
+    /// Decode a control message from the payload of a control frame.
+    ///
+    /// Returns [None] if the payload is malformed or unknown.
+    fn decode(payload: &[u8]) -> Option<Self>;
+}
+
+/// The maximum length of a [RoomName] in bytes.
+pub const MAX_ROOM_NAME_LENGTH: usize = 64;
+
+/// The name of a room, between 1 and [MAX_ROOM_NAME_LENGTH] bytes long.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RoomName(String);
+
+impl RoomName {
+    /// Create a new [RoomName], or returns an error if the name is empty or
+    /// too long.
+    pub fn new(name: impl Into<String>) -> Result<Self, InvalidRoomName> {
+        let name = name.into();
+        match (1..=MAX_ROOM_NAME_LENGTH).contains(&name.len()) {
+            true => Ok(Self(name)),
+            false => Err(InvalidRoomName),
+        }
+    }
+
+    /// Returns the name as a string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RoomName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The error returned when a [RoomName] is empty or too long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidRoomName;
+
+impl fmt::Display for InvalidRoomName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "room names must be between 1 and {MAX_ROOM_NAME_LENGTH} bytes long"
+        )
+    }
+}
+
+impl Error for InvalidRoomName {}
+
+/// The reason why a room request failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomError {
+    /// The room to create already exists.
+    AlreadyExists,
+
+    /// The room doesn't exist.
+    NotFound,
+
+    /// The client is not a member of the room.
+    NotMember,
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyExists => write!(f, "the room already exists"),
+            Self::NotFound => write!(f, "the room doesn't exist"),
+            Self::NotMember => write!(f, "not a member of the room"),
+        }
+    }
+}
+
+impl Error for RoomError {}
+
+/// A control request sent by a client to the relay server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Be notified when the given peer comes online or goes offline.
+    ///
+    /// The relay server answers immediately with the current presence of
+    /// the peer.
+    Subscribe(Uuid),
+
+    /// Stop being notified about the presence of the given peer.
+    Unsubscribe(Uuid),
+
+    /// Create a new room and join it, failing if it already exists.
+    CreateRoom(RoomName),
+
+    /// Join a room, creating it if it doesn't exist.
+    JoinRoom(RoomName),
+
+    /// Leave a room. The room is removed once it has no members left.
+    LeaveRoom(RoomName),
+
+    /// Ask for the members of a room.
+    ListRoom(RoomName),
+
+    /// Send a message to all the other members of a room.
+    ///
+    /// The members receive it as a message from the sender.
+    SendToRoom {
+        /// The room the message is sent to.
+        room: RoomName,
+
+        /// The content of the message.
+        payload: Vec<u8>,
+    },
+
+    /// Replace the secret of the client by a new one, received in a
+    /// [Notification::SecretRotated].
+    RotateSecret,
+}
+
+impl Request {
+    /// Returns the capability a session must have negotiated to make this
+    /// request, which the relay server ignores otherwise.
+    pub const fn capability(&self) -> Capabilities {
+        match self {
+            Self::Subscribe(_) | Self::Unsubscribe(_) => Capabilities::PRESENCE,
+            Self::CreateRoom(_)
+            | Self::JoinRoom(_)
+            | Self::LeaveRoom(_)
+            | Self::ListRoom(_)
+            | Self::SendToRoom { .. } => Capabilities::ROOMS,
+            Self::RotateSecret => Capabilities::NONE,
+        }
+    }
+}
+
+impl Control for Request {
+    fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::default();
+        match self {
+            Self::Subscribe(peer) => encoder.kind(0).id(*peer),
+            Self::Unsubscribe(peer) => encoder.kind(1).id(*peer),
+            Self::CreateRoom(room) => encoder.kind(2).room(room),
+            Self::JoinRoom(room) => encoder.kind(3).room(room),
+            Self::LeaveRoom(room) => encoder.kind(4).room(room),
+            Self::ListRoom(room) => encoder.kind(5).room(room),
+            Self::SendToRoom { room, payload } => encoder.kind(6).room(room).bytes(payload),
+            Self::RotateSecret => encoder.kind(7),
+        };
+        encoder.0
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let mut decoder = Decoder(payload);
+        let request = match decoder.kind()? {
+            0 => Self::Subscribe(decoder.id()?),
+            1 => Self::Unsubscribe(decoder.id()?),
+            2 => Self::CreateRoom(decoder.room()?),
+            3 => Self::JoinRoom(decoder.room()?),
+            4 => Self::LeaveRoom(decoder.room()?),
+            5 => Self::ListRoom(decoder.room()?),
+            6 => Self::SendToRoom {
+                room: decoder.room()?,
+                payload: decoder.rest(),
+            },
+            7 => Self::RotateSecret,
+            _ => return None,
+        };
+        decoder.end(request)
+    }
+}
+
+/// A control notification sent by the relay server to a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notification {
+    /// A subscribed peer is online.
+    Online(Uuid),
+
+    /// A subscribed peer is offline.
+    Offline(Uuid),
+
+    /// A message could not be delivered because its target is not
+    /// connected to the relay server.
+    ///
+    /// This is only sent to the clients with the
+    /// [BOUNCES](crate::Capabilities::BOUNCES) capability.
+    TargetUnknown(Uuid),
+
+    /// The client joined a room.
+    RoomJoined(RoomName),
+
+    /// The client left a room.
+    RoomLeft(RoomName),
+
+    /// The members of a room, answering a [Request::ListRoom].
+    RoomMembers {
+        /// The room.
+        room: RoomName,
+
+        /// The members of the room.
+        members: Vec<Uuid>,
+    },
+
+    /// Another client joined a room the client is a member of.
+    MemberJoined {
+        /// The room.
+        room: RoomName,
+
+        /// The client that joined the room.
+        member: Uuid,
+    },
+
+    /// Another client left a room the client is a member of.
+    MemberLeft {
+        /// The room.
+        room: RoomName,
+
+        /// The client that left the room.
+        member: Uuid,
+    },
+
+    /// A durable message could not be stored because the storage of its
+    /// offline target is full.
+    StoreFull(Uuid),
+
+    /// A room request failed.
+    RoomError {
+        /// The room of the request.
+        room: RoomName,
+
+        /// The reason of the failure.
+        error: RoomError,
+    },
+
+    /// The new secret of the client, answering a [Request::RotateSecret].
+    ///
+    /// The previous secret can't be used anymore.
+    SecretRotated(Uuid),
+}
+
+impl Control for Notification {
+    fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::default();
+        match self {
+            Self::Online(peer) => encoder.kind(0).id(*peer),
+            Self::Offline(peer) => encoder.kind(1).id(*peer),
+            Self::TargetUnknown(target) => encoder.kind(2).id(*target),
+            Self::RoomJoined(room) => encoder.kind(3).room(room),
+            Self::RoomLeft(room) => encoder.kind(4).room(room),
+            Self::RoomMembers { room, members } => {
+                let encoder = encoder.kind(5).room(room);
+                for member in members {
+                    encoder.id(*member);
+                }
+                encoder
+            }
+            Self::MemberJoined { room, member } => encoder.kind(6).room(room).id(*member),
+            Self::MemberLeft { room, member } => encoder.kind(7).room(room).id(*member),
+            Self::RoomError { room, error } => {
+                let error = match error {
+                    RoomError::AlreadyExists => 0,
+                    RoomError::NotFound => 1,
+                    RoomError::NotMember => 2,
+                };
+                encoder.kind(8).room(room).kind(error)
+            }
+            Self::StoreFull(target) => encoder.kind(9).id(*target),
+            Self::SecretRotated(secret) => encoder.kind(10).id(*secret),
+        };
+        encoder.0
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let mut decoder = Decoder(payload);
+        let notification = match decoder.kind()? {
+            0 => Self::Online(decoder.id()?),
+            1 => Self::Offline(decoder.id()?),
+            2 => Self::TargetUnknown(decoder.id()?),
+            3 => Self::RoomJoined(decoder.room()?),
+            4 => Self::RoomLeft(decoder.room()?),
+            5 => {
+                let room = decoder.room()?;
+                let mut members = Vec::with_capacity(decoder.0.len() / 16);
+                while !decoder.0.is_empty() {
+                    members.push(decoder.id()?);
+                }
+                Self::RoomMembers { room, members }
+            }
+            6 => Self::MemberJoined {
+                room: decoder.room()?,
+                member: decoder.id()?,
+            },
+            7 => Self::MemberLeft {
+                room: decoder.room()?,
+                member: decoder.id()?,
+            },
+            8 => Self::RoomError {
+                room: decoder.room()?,
+                error: match decoder.kind()? {
+                    0 => RoomError::AlreadyExists,
+                    1 => RoomError::NotFound,
+                    2 => RoomError::NotMember,
+                    _ => return None,
+                },
+            },
+            9 => Self::StoreFull(decoder.id()?),
+            10 => Self::SecretRotated(decoder.id()?),
+            _ => return None,
+        };
+        decoder.end(notification)
+    }
+}
+
+/// A helper to encode the fields of a control message.
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    /// Add a single byte.
+    fn kind(&mut self, kind: u8) -> &mut Self {
+        self.0.push(kind);
+        self
+    }
+
+    /// Add an identifier.
+    fn id(&mut self, id: Uuid) -> &mut Self {
+        self.0.extend_from_slice(id.as_bytes());
+        self
+    }
+
+    /// Add a room name prefixed by its length.
+    fn room(&mut self, room: &RoomName) -> &mut Self {
+        // The length of a room name always fits in a byte.
+        self.0.push(room.0.len() as u8);
+        self.0.extend_from_slice(room.0.as_bytes());
+        self
+    }
+
+    /// Add raw bytes, which must be the last field.
+    fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+}
+
+/// A helper to decode the fields of a control message.
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    /// Take a single byte.
+    fn kind(&mut self) -> Option<u8> {
+        let (&kind, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(kind)
+    }
+
+    /// Take an identifier.
+    fn id(&mut self) -> Option<Uuid> {
+        let (id, rest) = self.0.split_first_chunk::<16>()?;
+        self.0 = rest;
+        Some(Uuid::from_bytes(*id))
+    }
+
+    /// Take a room name prefixed by its length.
+    fn room(&mut self) -> Option<RoomName> {
+        let length = usize::from(self.kind()?);
+        if self.0.len() < length {
+            return None;
+        }
+        let (name, rest) = self.0.split_at(length);
+        self.0 = rest;
+        RoomName::new(std::str::from_utf8(name).ok()?).ok()
+    }
+
+    /// Take all the remaining bytes.
+    fn rest(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.0).to_vec()
+    }
+
+    /// Returns the decoded value if all the bytes have been decoded.
+    fn end<T>(self, value: T) -> Option<T> {
+        self.0.is_empty().then_some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the encoding of the control messages.
+
+    use super::*;
+
+    /// The peer used by the tests.
+    const PEER: Uuid = Uuid::from_u128(7);
+
+    /// Returns the room used by the tests.
+    fn room() -> RoomName {
+        RoomName::new("lobby").expect("the room name should be valid")
+    }
+
+    /// Requests are decoded as they were encoded.
+    #[test]
+    fn request_round_trip() {
+        for request in [
+            Request::Subscribe(PEER),
+            Request::Unsubscribe(PEER),
+            Request::CreateRoom(room()),
+            Request::JoinRoom(room()),
+            Request::LeaveRoom(room()),
+            Request::ListRoom(room()),
+            Request::SendToRoom {
+                room: room(),
+                payload: b"hello".to_vec(),
+            },
+            Request::SendToRoom {
+                room: room(),
+                payload: Vec::new(),
+            },
+            Request::RotateSecret,
+        ] {
+            assert_eq!(Request::decode(&request.encode()), Some(request));
+        }
+    }
+
+    /// Notifications are decoded as they were encoded.
+    #[test]
+    fn notification_round_trip() {
+        for notification in [
+            Notification::Online(PEER),
+            Notification::Offline(PEER),
+            Notification::TargetUnknown(PEER),
+            Notification::RoomJoined(room()),
+            Notification::RoomLeft(room()),
+            Notification::RoomMembers {
+                room: room(),
+                members: vec![PEER, Uuid::from_u128(8)],
+            },
+            Notification::RoomMembers {
+                room: room(),
+                members: Vec::new(),
+            },
+            Notification::MemberJoined {
+                room: room(),
+                member: PEER,
+            },
+            Notification::MemberLeft {
+                room: room(),
+                member: PEER,
+            },
+            Notification::StoreFull(PEER),
+            Notification::RoomError {
+                room: room(),
+                error: RoomError::NotMember,
+            },
+            Notification::SecretRotated(PEER),
+        ] {
+            assert_eq!(
+                Notification::decode(&notification.encode()),
+                Some(notification)
+            );
+        }
+    }
+
+    /// Truncated control messages are rejected, except for the ones whose
+    /// last field is variable.
+    #[test]
+    fn truncated_messages() {
+        let requests = [Request::Subscribe(PEER), Request::JoinRoom(room())];
+        for request in requests {
+            let data = request.encode();
+            for length in 0..data.len() {
+                assert_eq!(Request::decode(&data[..length]), None);
+            }
+        }
+        let notification = Notification::RoomMembers {
+            room: room(),
+            members: vec![PEER],
+        };
+        let data = notification.encode();
+        assert_eq!(Notification::decode(&data[..data.len() - 1]), None);
+    }
+
+    /// Unknown kinds, trailing bytes and invalid room names are rejected.
+    #[test]
+    fn malformed_messages() {
+        assert_eq!(Request::decode(&[0xff]), None);
+        assert_eq!(Notification::decode(&[0xff]), None);
+
+        let mut data = Request::Subscribe(PEER).encode();
+        data.push(0);
+        assert_eq!(Request::decode(&data), None);
+
+        // An empty room name.
+        assert_eq!(Request::decode(&[3, 0]), None);
+
+        // A room name that isn't valid UTF-8.
+        assert_eq!(Request::decode(&[3, 1, 0xff]), None);
+
+        // An unknown room error.
+        let mut data = Notification::RoomError {
+            room: room(),
+            error: RoomError::NotFound,
+        }
+        .encode();
+        if let Some(error) = data.last_mut() {
+            *error = 0xff;
+        }
+        assert_eq!(Notification::decode(&data), None);
+    }
+
+    /// Room names must be between 1 and [MAX_ROOM_NAME_LENGTH] bytes long.
+    #[test]
+    fn room_name_length() {
+        assert_eq!(RoomName::new(""), Err(InvalidRoomName));
+        assert!(RoomName::new("a".repeat(MAX_ROOM_NAME_LENGTH)).is_ok());
+        assert_eq!(
+            RoomName::new("a".repeat(MAX_ROOM_NAME_LENGTH + 1)),
+            Err(InvalidRoomName)
+        );
+    }
+}
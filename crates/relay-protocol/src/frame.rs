@@ -0,0 +1,354 @@
+//! The frames exchanged after the handshake.
+
+use std::error::Error;
+use std::fmt;
+use std::ops::BitOr;
+
+use uuid::Uuid;
+
+use crate::{Control, CONTROL_ID, LEGACY_VERSION};
+
+/// The kind of a data frame in the header of a versioned frame.
+const DATA: u8 = 0;
+
+/// The kind of a control frame in the header of a versioned frame.
+const CONTROL: u8 = 1;
+
+/// The flags of a data frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flags(pub u8);
+
+impl Flags {
+    /// No flag.
+    pub const NONE: Self = Self(0);
+
+    /// The message is stored by the relay server if its target is offline,
+    /// and delivered when the target reconnects.
+    ///
+    /// This requires the [DURABLE](crate::Capabilities::DURABLE) capability.
+    pub const DURABLE: Self = Self(1);
+
+    /// The payload starts with a [MessageId] chosen by the sender, and the
+    /// message is acknowledged with a [RECEIPT](Self::RECEIPT) once it's
+    /// received.
+    ///
+    /// The target acknowledges the message itself if its session has the
+    /// [ACKNOWLEDGEMENTS](crate::Capabilities::ACKNOWLEDGEMENTS) capability.
+    /// Otherwise the relay server removes the identifier from the payload and
+    /// acknowledges the message when delivering it, or when storing it if
+    /// it's durable.
+    ///
+    /// This requires the
+    /// [ACKNOWLEDGEMENTS](crate::Capabilities::ACKNOWLEDGEMENTS) capability.
+    pub const ACKNOWLEDGED: Self = Self(1 << 1);
+
+    /// The message acknowledges the reception of an
+    /// [ACKNOWLEDGED](Self::ACKNOWLEDGED) message sent by the peer, and its
+    /// payload is the [MessageId] of that message.
+    pub const RECEIPT: Self = Self(1 << 2);
+
+    /// The payload is encrypted and authenticated with a key shared by the
+    /// sender and the target, so the relay server can't read it.
+    ///
+    /// The relay server forwards it like any other message, and keeps the
+    /// flag when storing a [DURABLE](Self::DURABLE) message.
+    pub const ENCRYPTED: Self = Self(1 << 3);
+
+    /// The payload is the public key of the sender, used by the target to
+    /// compute the key of the [ENCRYPTED](Self::ENCRYPTED) messages they
+    /// exchange.
+    pub const KEY_EXCHANGE: Self = Self(1 << 4);
+
+    /// Returns true if all the flags of `other` are in `self`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the flags that are both in `self` and in `other`.
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Returns the flags of `self` that are not in `other`.
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// The identifier of an [ACKNOWLEDGED](Flags::ACKNOWLEDGED) message, chosen
+/// by its sender.
+///
+/// It's encoded as 8 big endian bytes at the start of the payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MessageId(pub u64);
+
+impl MessageId {
+    /// The length of an encoded identifier.
+    pub const LENGTH: usize = 8;
+
+    /// Returns the encoded identifier.
+    pub const fn to_bytes(self) -> [u8; Self::LENGTH] {
+        self.0.to_be_bytes()
+    }
+
+    /// Returns a payload starting with this identifier.
+    pub fn prefix(self, payload: &[u8]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(Self::LENGTH + payload.len());
+        data.extend_from_slice(&self.to_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    /// Split the identifier at the start of a payload from the rest of it,
+    /// or returns [None] if the payload is too short.
+    pub fn split(payload: &[u8]) -> Option<(Self, &[u8])> {
+        let (id, rest) = payload.split_first_chunk::<{ Self::LENGTH }>()?;
+        Some((Self(u64::from_be_bytes(*id)), rest))
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// A frame exchanged between the relay server and a client.
+///
+/// With the legacy protocol, a frame is a payload followed by the 16 bytes
+/// identifier of the peer, and control frames use the [CONTROL_ID] as peer.
+///
+/// Since version 1, a frame starts with a header made of a kind and a flags
+/// byte. Data frames follow it with the identifier of the peer and the
+/// payload, and control frames with the encoded control message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame<C> {
+    /// A message for or from another client.
+    Data {
+        /// The target of the message when sent by a client, or its sender
+        /// when delivered by the relay server.
+        peer: Uuid,
+
+        /// The flags of the message, which are lost with the legacy
+        /// protocol.
+        flags: Flags,
+
+        /// The content of the message.
+        payload: Vec<u8>,
+    },
+
+    /// A control message for or from the relay server.
+    Control(C),
+}
+
+/// The error returned when a frame can't be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The frame is malformed.
+    Malformed,
+
+    /// The frame is well formed but its kind or control message is unknown,
+    /// probably because it comes from a newer version of the protocol.
+    Unknown,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "malformed frame"),
+            Self::Unknown => write!(f, "unknown frame"),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+impl<C: Control> Frame<C> {
+    /// Encode the frame for the given protocol version.
+    pub fn encode(&self, version: u16) -> Vec<u8> {
+        if version == LEGACY_VERSION {
+            let (mut data, peer) = match self {
+                Self::Data { peer, payload, .. } => (payload.clone(), *peer),
+                Self::Control(control) => (control.encode(), CONTROL_ID),
+            };
+            data.extend_from_slice(peer.as_bytes());
+            return data;
+        }
+        match self {
+            Self::Data {
+                peer,
+                flags,
+                payload,
+            } => {
+                let mut data = Vec::with_capacity(18 + payload.len());
+                data.extend_from_slice(&[DATA, flags.0]);
+                data.extend_from_slice(peer.as_bytes());
+                data.extend_from_slice(payload);
+                data
+            }
+            Self::Control(control) => {
+                let mut data = vec![CONTROL, 0];
+                data.append(&mut control.encode());
+                data
+            }
+        }
+    }
+
+    /// Decode a frame for the given protocol version.
+    pub fn decode(mut data: Vec<u8>, version: u16) -> Result<Self, DecodeError> {
+        // Decode a legacy frame.
+        if version == LEGACY_VERSION {
+            if data.len() < 16 {
+                return Err(DecodeError::Malformed);
+            }
+            let id_start = data.len() - 16;
+            let peer = Uuid::from_slice(&data[id_start..]).map_err(|_| DecodeError::Malformed)?;
+            data.truncate(id_start);
+            return match peer == CONTROL_ID {
+                true => C::decode(&data)
+                    .map(Self::Control)
+                    .ok_or(DecodeError::Unknown),
+                false => Ok(Self::Data {
+                    peer,
+                    flags: Flags::NONE,
+                    payload: data,
+                }),
+            };
+        }
+
+        // Decode a versioned frame.
+        match data.as_slice() {
+            &[DATA, flags, ref rest @ ..] if rest.len() >= 16 => {
+                let peer = Uuid::from_slice(&rest[..16]).map_err(|_| DecodeError::Malformed)?;
+                data.drain(..18);
+                Ok(Self::Data {
+                    peer,
+                    flags: Flags(flags),
+                    payload: data,
+                })
+            }
+            [DATA, ..] | [] | [_] => Err(DecodeError::Malformed),
+            [CONTROL, _, control @ ..] => C::decode(control)
+                .map(Self::Control)
+                .ok_or(DecodeError::Unknown),
+            _ => Err(DecodeError::Unknown),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the encoding of the frames.
+
+    use super::*;
+    use crate::{Notification, Request, PROTOCOL_VERSION};
+
+    /// The peer used by the tests.
+    const PEER: Uuid = Uuid::from_u128(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef);
+
+    /// Data frames are decoded as they were encoded with each version, but
+    /// the legacy protocol loses their flags.
+    #[test]
+    fn data_round_trip() {
+        let frame = Frame::<Request>::Data {
+            peer: PEER,
+            flags: Flags::DURABLE | Flags::ACKNOWLEDGED,
+            payload: b"hello".to_vec(),
+        };
+        let data = frame.encode(PROTOCOL_VERSION);
+        assert_eq!(Frame::decode(data, PROTOCOL_VERSION), Ok(frame.clone()));
+
+        let data = frame.encode(LEGACY_VERSION);
+        assert_eq!(data.len(), 5 + 16);
+        assert_eq!(
+            Frame::decode(data, LEGACY_VERSION),
+            Ok(Frame::<Request>::Data {
+                peer: PEER,
+                flags: Flags::NONE,
+                payload: b"hello".to_vec(),
+            })
+        );
+    }
+
+    /// Control frames are decoded as they were encoded with each version.
+    #[test]
+    fn control_round_trip() {
+        let frame = Frame::Control(Notification::Online(PEER));
+        for version in [LEGACY_VERSION, PROTOCOL_VERSION] {
+            let data = frame.encode(version);
+            assert_eq!(Frame::decode(data, version), Ok(frame.clone()));
+        }
+    }
+
+    /// Legacy frames addressed to the nil identifier are control frames.
+    #[test]
+    fn legacy_nil_peer_is_control() {
+        let mut data = Request::Subscribe(PEER).encode();
+        data.extend_from_slice(CONTROL_ID.as_bytes());
+        assert_eq!(
+            Frame::decode(data, LEGACY_VERSION),
+            Ok(Frame::Control(Request::Subscribe(PEER)))
+        );
+
+        // An unknown control message is not mistaken for a data frame.
+        let data = [&[0xff][..], CONTROL_ID.as_bytes()].concat();
+        assert_eq!(
+            Frame::<Request>::decode(data, LEGACY_VERSION),
+            Err(DecodeError::Unknown)
+        );
+    }
+
+    /// Truncated frames are rejected instead of panicking.
+    #[test]
+    fn truncated_frames() {
+        let frame = Frame::<Request>::Data {
+            peer: PEER,
+            flags: Flags::NONE,
+            payload: Vec::new(),
+        };
+        for version in [LEGACY_VERSION, PROTOCOL_VERSION] {
+            let data = frame.encode(version);
+            for length in 0..data.len() {
+                assert_eq!(
+                    Frame::<Request>::decode(data[..length].to_vec(), version),
+                    Err(DecodeError::Malformed),
+                    "version {version}, length {length}"
+                );
+            }
+        }
+
+        // A truncated control message is not decoded either.
+        let data = Frame::Control(Request::Subscribe(PEER)).encode(PROTOCOL_VERSION);
+        assert!(
+            Frame::<Request>::decode(data[..data.len() - 1].to_vec(), PROTOCOL_VERSION).is_err()
+        );
+    }
+
+    /// Frames of an unknown kind are reported as unknown.
+    #[test]
+    fn unknown_kind() {
+        assert_eq!(
+            Frame::<Request>::decode(vec![2, 0, 1, 2, 3], PROTOCOL_VERSION),
+            Err(DecodeError::Unknown)
+        );
+    }
+
+    /// Message identifiers are split from the payload they prefix.
+    #[test]
+    fn message_id() {
+        let payload = MessageId(42).prefix(b"data");
+        assert_eq!(
+            MessageId::split(&payload),
+            Some((MessageId(42), &b"data"[..]))
+        );
+        assert_eq!(MessageId::split(&payload[..MessageId::LENGTH - 1]), None);
+    }
+}
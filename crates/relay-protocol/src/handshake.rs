@@ -0,0 +1,319 @@
+//! The handshake opening a session with the relay server.
+
+use std::ops::{BitAnd, BitOr};
+
+use uuid::Uuid;
+
+/// The bytes starting the handshake frames since version 1.
+///
+/// They make the handshake frames distinguishable from the legacy ones,
+/// which are either empty or 32 bytes long.
+const MAGIC: &[u8; 4] = b"RLAY";
+
+/// The optional features of the protocol supported by a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// No optional feature.
+    pub const NONE: Self = Self(0);
+
+    /// Presence subscriptions with [Request::Subscribe](crate::Request).
+    pub const PRESENCE: Self = Self(1);
+
+    /// Bounces of undeliverable messages with
+    /// [Notification::TargetUnknown](crate::Notification).
+    pub const BOUNCES: Self = Self(1 << 1);
+
+    /// Rooms with [Request::JoinRoom](crate::Request) and the other room
+    /// requests.
+    pub const ROOMS: Self = Self(1 << 2);
+
+    /// Durable messages with [Flags::DURABLE](crate::Flags).
+    pub const DURABLE: Self = Self(1 << 3);
+
+    /// Secret rotation with [Request::RotateSecret](crate::Request).
+    pub const ROTATION: Self = Self(1 << 4);
+
+    /// Acknowledged messages with [Flags::ACKNOWLEDGED](crate::Flags).
+    pub const ACKNOWLEDGEMENTS: Self = Self(1 << 5);
+
+    /// All the features supported by this version of the protocol.
+    pub const ALL: Self = Self(
+        Self::PRESENCE.0
+            | Self::BOUNCES.0
+            | Self::ROOMS.0
+            | Self::DURABLE.0
+            | Self::ROTATION.0
+            | Self::ACKNOWLEDGEMENTS.0,
+    );
+
+    /// Returns true if all the features of `other` are in `self`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+/// The credentials of a client, made of its identifier and its secret.
+pub type Credentials = (Uuid, Uuid);
+
+/// The first frame sent by a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hello {
+    /// A legacy client registering, with an empty frame, or authenticating,
+    /// with its credentials.
+    ///
+    /// The relay server answers a registration with the new credentials and
+    /// doesn't answer an authentication.
+    Legacy(Option<Credentials>),
+
+    /// A versioned client, answered with a [Welcome].
+    Versioned {
+        /// The protocol version of the client.
+        version: u16,
+
+        /// The capabilities of the client.
+        capabilities: Capabilities,
+
+        /// The credentials of the client, or [None] to register a new
+        /// identity.
+        credentials: Option<Credentials>,
+    },
+}
+
+impl Hello {
+    /// Encode the hello frame.
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Legacy(credentials) => credentials.map_or_else(Vec::new, encode_credentials),
+            Self::Versioned {
+                version,
+                capabilities,
+                credentials,
+            } => {
+                let mut data = Vec::with_capacity(42);
+                data.extend_from_slice(MAGIC);
+                data.extend_from_slice(&version.to_be_bytes());
+                data.extend_from_slice(&capabilities.0.to_be_bytes());
+                if let Some(credentials) = credentials {
+                    data.append(&mut encode_credentials(*credentials));
+                }
+                data
+            }
+        }
+    }
+
+    /// Decode a hello frame.
+    ///
+    /// Returns [None] if the frame is malformed.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        match data.len() {
+            0 => return Some(Self::Legacy(None)),
+            32 => return Some(Self::Legacy(Some(decode_credentials(data)?))),
+            _ => (),
+        }
+        let rest = data.strip_prefix(MAGIC)?;
+        let (version, rest) = rest.split_first_chunk::<2>()?;
+        let (capabilities, rest) = rest.split_first_chunk::<4>()?;
+        let credentials = match rest.len() {
+            0 => None,
+            _ => Some(decode_credentials(rest)?),
+        };
+        Some(Self::Versioned {
+            version: u16::from_be_bytes(*version),
+            capabilities: Capabilities(u32::from_be_bytes(*capabilities)),
+            credentials,
+        })
+    }
+}
+
+/// The answer of the relay server to a versioned [Hello].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Welcome {
+    /// The session is open.
+    Accepted {
+        /// The protocol version used for the session.
+        version: u16,
+
+        /// The capabilities enabled for the session.
+        capabilities: Capabilities,
+
+        /// The new credentials of the client if it registered.
+        credentials: Option<Credentials>,
+    },
+
+    /// The session is refused and the connection will be closed.
+    Rejected(String),
+}
+
+impl Welcome {
+    /// Encode the welcome frame.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        match self {
+            Self::Accepted {
+                version,
+                capabilities,
+                credentials,
+            } => {
+                data.push(0);
+                data.extend_from_slice(&version.to_be_bytes());
+                data.extend_from_slice(&capabilities.0.to_be_bytes());
+                if let Some(credentials) = credentials {
+                    data.append(&mut encode_credentials(*credentials));
+                }
+            }
+            Self::Rejected(reason) => {
+                data.push(1);
+                data.extend_from_slice(reason.as_bytes());
+            }
+        }
+        data
+    }
+
+    /// Decode a welcome frame.
+    ///
+    /// Returns [None] if the frame is malformed.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        match data.strip_prefix(MAGIC)? {
+            [0, rest @ ..] => {
+                let (version, rest) = rest.split_first_chunk::<2>()?;
+                let (capabilities, rest) = rest.split_first_chunk::<4>()?;
+                let credentials = match rest.len() {
+                    0 => None,
+                    _ => Some(decode_credentials(rest)?),
+                };
+                Some(Self::Accepted {
+                    version: u16::from_be_bytes(*version),
+                    capabilities: Capabilities(u32::from_be_bytes(*capabilities)),
+                    credentials,
+                })
+            }
+            [1, reason @ ..] => Some(Self::Rejected(String::from_utf8_lossy(reason).into_owned())),
+            _ => None,
+        }
+    }
+}
+
+/// Encode credentials into 32 bytes.
+fn encode_credentials((identifier, secret): Credentials) -> Vec<u8> {
+    let mut data = Vec::with_capacity(32);
+    data.extend_from_slice(identifier.as_bytes());
+    data.extend_from_slice(secret.as_bytes());
+    data
+}
+
+/// Decode credentials from 32 bytes.
+fn decode_credentials(data: &[u8]) -> Option<Credentials> {
+    if data.len() != 32 {
+        return None;
+    }
+    let identifier = Uuid::from_slice(&data[..16]).ok()?;
+    let secret = Uuid::from_slice(&data[16..]).ok()?;
+    Some((identifier, secret))
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the encoding of the handshake.
+
+    use super::*;
+    use crate::PROTOCOL_VERSION;
+
+    /// The credentials used by the tests.
+    const CREDENTIALS: Credentials = (Uuid::from_u128(1), Uuid::from_u128(2));
+
+    /// Hello frames are decoded as they were encoded.
+    #[test]
+    fn hello_round_trip() {
+        for credentials in [None, Some(CREDENTIALS)] {
+            let hello = Hello::Versioned {
+                version: PROTOCOL_VERSION,
+                capabilities: Capabilities::ALL,
+                credentials,
+            };
+            assert_eq!(Hello::decode(&hello.encode()), Some(hello));
+        }
+    }
+
+    /// The empty and 32 bytes frames of the legacy clients are accepted.
+    #[test]
+    fn legacy_hello() {
+        assert_eq!(Hello::decode(&[]), Some(Hello::Legacy(None)));
+        let data = Hello::Legacy(Some(CREDENTIALS)).encode();
+        assert_eq!(data.len(), 32);
+        assert_eq!(Hello::decode(&data), Some(Hello::Legacy(Some(CREDENTIALS))));
+    }
+
+    /// Truncated and malformed hello frames are rejected.
+    #[test]
+    fn malformed_hello() {
+        let hello = Hello::Versioned {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::ALL,
+            credentials: Some(CREDENTIALS),
+        };
+        let data = hello.encode();
+        for length in 1..data.len() {
+            // The 32 bytes prefix is a valid legacy frame.
+            if length != 32 && length != 10 {
+                assert_eq!(Hello::decode(&data[..length]), None, "length {length}");
+            }
+        }
+        assert_eq!(Hello::decode(b"NOPE and something else"), None);
+    }
+
+    /// Welcome frames are decoded as they were encoded.
+    #[test]
+    fn welcome_round_trip() {
+        for welcome in [
+            Welcome::Accepted {
+                version: PROTOCOL_VERSION,
+                capabilities: Capabilities::PRESENCE | Capabilities::ROOMS,
+                credentials: Some(CREDENTIALS),
+            },
+            Welcome::Accepted {
+                version: PROTOCOL_VERSION,
+                capabilities: Capabilities::NONE,
+                credentials: None,
+            },
+            Welcome::Rejected("invalid secret".to_owned()),
+        ] {
+            assert_eq!(Welcome::decode(&welcome.encode()), Some(welcome));
+        }
+    }
+
+    /// Truncated and malformed welcome frames are rejected.
+    #[test]
+    fn malformed_welcome() {
+        let welcome = Welcome::Accepted {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::ALL,
+            credentials: Some(CREDENTIALS),
+        };
+        let data = welcome.encode();
+        for length in 0..data.len() {
+            // Without the credentials, it's a valid welcome.
+            if length != 11 {
+                assert_eq!(Welcome::decode(&data[..length]), None, "length {length}");
+            }
+        }
+        assert_eq!(Welcome::decode(b"RLAY\x02"), None);
+    }
+}
@@ -0,0 +1,33 @@
+//! The protocol shared by the relay server and its clients.
+//!
+//! A session starts with a [Hello] sent by the client. Legacy clients send
+//! an empty frame to register or their credentials to authenticate, while
+//! versioned clients announce their protocol version and capabilities and
+//! are answered with a [Welcome]. After that, the client and the relay
+//! server exchange [Frame]s, whose encoding depends on the protocol version
+//! of the session, until one of them closes it, optionally with a
+//! [CloseReason].
+
+use uuid::Uuid;
+
+pub use self::close::CloseReason;
+pub use self::control::{
+    Control, InvalidRoomName, Notification, Request, RoomError, RoomName, MAX_ROOM_NAME_LENGTH,
+};
+pub use self::frame::{DecodeError, Flags, Frame, MessageId};
+pub use self::handshake::{Capabilities, Credentials, Hello, Welcome};
+
+mod close;
+mod control;
+mod frame;
+mod handshake;
+
+/// The version of the protocol used by the clients that don't announce one.
+pub const LEGACY_VERSION: u16 = 0;
+
+/// The latest version of the protocol.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// The identifier used by legacy frames to address control messages to and
+/// from the relay server.
+pub const CONTROL_ID: Uuid = Uuid::nil();
@@ -0,0 +1,244 @@
+//! The HTTP API used to administrate the relay server.
+//!
+//! Every request must have an `Authorization: Bearer <token>` header with the
+//! admin token of the configuration.
+
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use axum::extract::{FromRequestParts, Path, State};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::routing::{delete, get, put};
+use axum::{async_trait, Json, Router};
+use relay_protocol::CloseReason;
+use serde::Serialize;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::cluster::NodeMessage;
+use crate::Relay;
+
+/// Returns the routes of the admin API.
+pub fn router() -> Router<Arc<Relay>> {
+    Router::new()
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/:id", delete(kick))
+        .route("/identities/:id", delete(revoke))
+        .route("/bans", get(list_bans))
+        .route("/bans/:id", put(ban).delete(unban))
+}
+
+/// An extractor rejecting the requests without the admin token.
+pub struct Admin;
+
+#[async_trait]
+impl FromRequestParts<Arc<Relay>> for Admin {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        relay: &Arc<Relay>,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(token) = &relay.admin_token else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+        match authorized(&parts.headers, token) {
+            true => Ok(Self),
+            false => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+}
+
+/// Returns true if the request has an `Authorization: Bearer <token>` header
+/// with the given token.
+pub fn authorized(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()))
+}
+
+/// Compare two byte strings in a time that only depends on their lengths.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Log a database error and returns the corresponding status code.
+fn internal_error(error: &sled::Error) -> StatusCode {
+    warn!(error = %error, "admin request failed");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// A live session, as listed by the admin API.
+#[derive(Serialize)]
+struct SessionInfo {
+    /// The identifier of the client.
+    id: Uuid,
+
+    /// The identifier of the session.
+    session: u64,
+
+    /// The address the client is connected from.
+    address: IpAddr,
+
+    /// The protocol version of the session.
+    version: u16,
+
+    /// The capabilities enabled for the session.
+    capabilities: u32,
+
+    /// The number of seconds since the client connected.
+    connected_seconds: u64,
+
+    /// The number of frames dropped because the client was too slow.
+    dropped: u64,
+}
+
+/// List the live sessions.
+async fn list_sessions(_: Admin, State(relay): State<Arc<Relay>>) -> Json<Vec<SessionInfo>> {
+    let sessions = relay
+        .clients
+        .iter()
+        .map(|client| SessionInfo {
+            id: *client.key(),
+            session: client.session_id,
+            address: client.address,
+            version: client.session.version,
+            capabilities: client.session.capabilities.0,
+            connected_seconds: client.connected_at.elapsed().as_secs(),
+            dropped: client.dropped.load(Ordering::Relaxed),
+        })
+        .collect();
+    Json(sessions)
+}
+
+/// Force a client to disconnect.
+async fn kick(_: Admin, State(relay): State<Arc<Relay>>, Path(id): Path<Uuid>) -> StatusCode {
+    match relay.close(id, CloseReason::Kicked) {
+        true => {
+            info!(client_id = %id, "client kicked");
+            StatusCode::NO_CONTENT
+        }
+        false => StatusCode::NOT_FOUND,
+    }
+}
+
+/// Revoke the secret of an identity, which can't be used anymore, and
+/// disconnect its client.
+async fn revoke(
+    _: Admin,
+    State(relay): State<Arc<Relay>>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, StatusCode> {
+    if relay
+        .db
+        .remove(id.as_bytes())
+        .map_err(|e| internal_error(&e))?
+        .is_none()
+    {
+        return Ok(StatusCode::NOT_FOUND);
+    }
+    relay.last_seen.remove(id).map_err(|e| internal_error(&e))?;
+    relay
+        .db
+        .flush_async()
+        .await
+        .map_err(|e| internal_error(&e))?;
+    relay.close(id, CloseReason::Kicked);
+    relay.cluster.broadcast(&NodeMessage::Revoked(id));
+    info!(client_id = %id, "identity revoked");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List the banned identities.
+async fn list_bans(
+    _: Admin,
+    State(relay): State<Arc<Relay>>,
+) -> Result<Json<Vec<Uuid>>, StatusCode> {
+    let mut bans = Vec::new();
+    for key in relay.bans.iter().keys() {
+        let key = key.map_err(|e| internal_error(&e))?;
+        if let Ok(id) = Uuid::from_slice(&key) {
+            bans.push(id);
+        }
+    }
+    Ok(Json(bans))
+}
+
+/// Ban an identity and disconnect its client.
+async fn ban(
+    _: Admin,
+    State(relay): State<Arc<Relay>>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, StatusCode> {
+    relay
+        .bans
+        .insert(id.as_bytes(), &[])
+        .map_err(|e| internal_error(&e))?;
+    relay
+        .db
+        .flush_async()
+        .await
+        .map_err(|e| internal_error(&e))?;
+    relay.close(id, CloseReason::Banned);
+    relay.cluster.broadcast(&NodeMessage::Banned(id));
+    info!(client_id = %id, "identity banned");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Lift the ban of an identity.
+async fn unban(
+    _: Admin,
+    State(relay): State<Arc<Relay>>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, StatusCode> {
+    if relay
+        .bans
+        .remove(id.as_bytes())
+        .map_err(|e| internal_error(&e))?
+        .is_none()
+    {
+        return Ok(StatusCode::NOT_FOUND);
+    }
+    relay
+        .db
+        .flush_async()
+        .await
+        .map_err(|e| internal_error(&e))?;
+    relay.cluster.broadcast(&NodeMessage::Unbanned(id));
+    info!(client_id = %id, "identity unbanned");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    /// Returns headers with the given authorization header, if any.
+    fn headers(authorization: Option<&'static str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(authorization) = authorization {
+            headers.insert(
+                header::AUTHORIZATION,
+                HeaderValue::from_static(authorization),
+            );
+        }
+        headers
+    }
+
+    /// Only the requests with the admin token as bearer token are authorized.
+    #[test]
+    fn bearer_token() {
+        assert!(authorized(&headers(Some("Bearer secret")), "secret"));
+        assert!(!authorized(&headers(None), "secret"));
+        assert!(!authorized(&headers(Some("Bearer wrong")), "secret"));
+        assert!(!authorized(&headers(Some("Bearer secret2")), "secret"));
+        assert!(!authorized(&headers(Some("Basic secret")), "secret"));
+        assert!(!authorized(&headers(Some("secret")), "secret"));
+    }
+}
@@ -0,0 +1,732 @@
+//! The forwarding of the messages between the nodes of a relay cluster.
+//!
+//! Several relay servers can serve the same clients, for example behind a
+//! DNS round-robin. Each node links to the peer nodes of its configuration on
+//! their `/node` route, with an `Authorization: Bearer <token>` header holding
+//! the node token shared by the cluster. Over each link, both nodes announce
+//! the clients they host and forward to each other the messages whose target
+//! is hosted by the other node.
+//!
+//! The presence of the clients is known by all the nodes, and the durable
+//! messages stored by a node are forwarded when their target connects to
+//! another one. The identities registered, rotated, revoked or banned on a
+//! node are replicated to the nodes linked at that time, so that a client can
+//! authenticate on any of them. The rooms are local to each node.
+//!
+//! The nodes announce the start time of each session, so when an identity is
+//! connected to two nodes at once, both apply the duplicate session policy
+//! to the same session.
+
+use std::collections::HashSet;
+use std::future::ready;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context};
+use axum::extract::ws::Message;
+use axum::extract::{ConnectInfo, State, WebSocketUpgrade};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use dashmap::DashMap;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use relay_protocol::{CloseReason, Flags, MessageId};
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::time::{sleep, timeout};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{header, HeaderValue};
+use tokio_tungstenite::{connect_async, tungstenite};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::admin::authorized;
+use crate::config::DuplicateSessionPolicy;
+use crate::metrics::Metrics;
+use crate::{Config, Relay, Storage};
+
+/// The number of messages that can be waiting to be sent to a node.
+const LINK_CAPACITY: usize = 4096;
+
+/// The time to wait before linking again to a peer node.
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// The maximum time to open a link to a peer node.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The kind of a [NodeMessage::Hello].
+const HELLO: u8 = 0;
+
+/// The kind of a [NodeMessage::Hosted].
+const HOSTED: u8 = 1;
+
+/// The kind of a [NodeMessage::Left].
+const LEFT: u8 = 2;
+
+/// The kind of a [NodeMessage::Forward].
+const FORWARD: u8 = 3;
+
+/// The kind of a [NodeMessage::Secret].
+const SECRET: u8 = 4;
+
+/// The kind of a [NodeMessage::Revoked].
+const REVOKED: u8 = 5;
+
+/// The kind of a [NodeMessage::Banned].
+const BANNED: u8 = 6;
+
+/// The kind of a [NodeMessage::Unbanned].
+const UNBANNED: u8 = 7;
+
+/// A message exchanged by two nodes over a link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeMessage {
+    /// The first message sent on a link, with the identifier of the node
+    /// and the clients it hosts.
+    Hello {
+        /// The identifier of the node.
+        node: Uuid,
+
+        /// The clients connected to the node, with the start time of their
+        /// session.
+        clients: Vec<(Uuid, u64)>,
+    },
+
+    /// A client connected to the node.
+    Hosted {
+        /// The client that connected.
+        client_id: Uuid,
+
+        /// The start time of the session of the client.
+        started_at: u64,
+    },
+
+    /// A client disconnected from the node.
+    Left(Uuid),
+
+    /// A message for a client hosted by the receiving node.
+    Forward {
+        /// The client that sent the message.
+        sender: Uuid,
+
+        /// The client the message is sent to.
+        target: Uuid,
+
+        /// The flags of the message.
+        flags: Flags,
+
+        /// The payload of the message.
+        payload: Vec<u8>,
+    },
+
+    /// The secret of a client was created or rotated.
+    Secret {
+        /// The client whose secret changed.
+        client_id: Uuid,
+
+        /// The hashed secret, as stored in the database.
+        entry: Vec<u8>,
+    },
+
+    /// The secret of a client was revoked.
+    Revoked(Uuid),
+
+    /// A client was banned.
+    Banned(Uuid),
+
+    /// The ban of a client was lifted.
+    Unbanned(Uuid),
+}
+
+impl NodeMessage {
+    /// Returns the binary representation of the message.
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        match self {
+            Self::Hello { node, clients } => {
+                data.push(HELLO);
+                data.extend_from_slice(node.as_bytes());
+                for (client_id, started_at) in clients {
+                    data.extend_from_slice(client_id.as_bytes());
+                    data.extend_from_slice(&started_at.to_be_bytes());
+                }
+            }
+            Self::Hosted {
+                client_id,
+                started_at,
+            } => {
+                data.push(HOSTED);
+                data.extend_from_slice(client_id.as_bytes());
+                data.extend_from_slice(&started_at.to_be_bytes());
+            }
+            Self::Left(client) => {
+                data.push(LEFT);
+                data.extend_from_slice(client.as_bytes());
+            }
+            Self::Forward {
+                sender,
+                target,
+                flags,
+                payload,
+            } => {
+                data.push(FORWARD);
+                data.extend_from_slice(sender.as_bytes());
+                data.extend_from_slice(target.as_bytes());
+                data.push(flags.0);
+                data.extend_from_slice(payload);
+            }
+            Self::Secret { client_id, entry } => {
+                data.push(SECRET);
+                data.extend_from_slice(client_id.as_bytes());
+                data.extend_from_slice(entry);
+            }
+            Self::Revoked(client_id) => {
+                data.push(REVOKED);
+                data.extend_from_slice(client_id.as_bytes());
+            }
+            Self::Banned(client_id) => {
+                data.push(BANNED);
+                data.extend_from_slice(client_id.as_bytes());
+            }
+            Self::Unbanned(client_id) => {
+                data.push(UNBANNED);
+                data.extend_from_slice(client_id.as_bytes());
+            }
+        }
+        data
+    }
+
+    /// Parse a message from its binary representation, or returns [None] if
+    /// it's malformed.
+    fn decode(data: &[u8]) -> Option<Self> {
+        let (&kind, data) = data.split_first()?;
+        let uuid = |data: &[u8]| Uuid::from_slice(data).ok();
+        let session = |data: &[u8]| {
+            let (client_id, started_at) = data.split_at_checked(16)?;
+            let started_at = u64::from_be_bytes(started_at.try_into().ok()?);
+            Some((uuid(client_id)?, started_at))
+        };
+        match kind {
+            HELLO => {
+                let (node, clients) = data.split_at_checked(16)?;
+                if clients.len() % 24 != 0 {
+                    return None;
+                }
+                Some(Self::Hello {
+                    node: uuid(node)?,
+                    clients: clients
+                        .chunks_exact(24)
+                        .map(session)
+                        .collect::<Option<_>>()?,
+                })
+            }
+            HOSTED => {
+                let (client_id, started_at) = session(data)?;
+                Some(Self::Hosted {
+                    client_id,
+                    started_at,
+                })
+            }
+            LEFT => Some(Self::Left(uuid(data)?)),
+            FORWARD => {
+                let (sender, data) = data.split_at_checked(16)?;
+                let (target, data) = data.split_at_checked(16)?;
+                let (&flags, payload) = data.split_first()?;
+                Some(Self::Forward {
+                    sender: uuid(sender)?,
+                    target: uuid(target)?,
+                    flags: Flags(flags),
+                    payload: payload.to_vec(),
+                })
+            }
+            SECRET => {
+                let (client_id, entry) = data.split_at_checked(16)?;
+                Some(Self::Secret {
+                    client_id: uuid(client_id)?,
+                    entry: entry.to_vec(),
+                })
+            }
+            REVOKED => Some(Self::Revoked(uuid(data)?)),
+            BANNED => Some(Self::Banned(uuid(data)?)),
+            UNBANNED => Some(Self::Unbanned(uuid(data)?)),
+            _ => None,
+        }
+    }
+}
+
+/// An open link to another node.
+struct Link {
+    /// The identifier of the node, once it's known.
+    node: Option<Uuid>,
+
+    /// The sender of the messages to send to the node.
+    sender: Sender<NodeMessage>,
+
+    /// The clients the node announced on this link.
+    clients: HashSet<Uuid>,
+}
+
+/// The links to the other nodes of the cluster.
+pub struct Cluster {
+    /// The identifier of this node, used to detect a link to itself.
+    node: Uuid,
+
+    /// The token shared by the nodes of the cluster, if linking is enabled.
+    token: Option<String>,
+
+    /// The URLs of the peer nodes to link to.
+    peers: Vec<String>,
+
+    /// The open links, by identifier.
+    links: DashMap<u64, Link>,
+
+    /// The link to the node hosting each remote client.
+    locations: DashMap<Uuid, u64>,
+
+    /// The identifier of the next link.
+    next_link: AtomicU64,
+}
+
+impl Cluster {
+    /// Create the cluster described by the configuration, without any link.
+    pub fn new(config: &Config) -> Self {
+        Self {
+            node: Uuid::new_v4(),
+            token: config.node_token.clone(),
+            peers: config.peers.clone(),
+            links: DashMap::new(),
+            locations: DashMap::new(),
+            next_link: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns true if other nodes can link to this one.
+    pub const fn is_enabled(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// Returns the number of open links.
+    pub fn links(&self) -> usize {
+        self.links.len()
+    }
+
+    /// Returns true if the client is hosted by another node.
+    pub fn is_remote(&self, client_id: Uuid) -> bool {
+        self.locations.contains_key(&client_id)
+    }
+
+    /// Send a message to all the linked nodes.
+    pub fn broadcast(&self, message: &NodeMessage) {
+        for link in &self.links {
+            if link.sender.try_send(message.clone()).is_err() {
+                warn!(link = *link.key(), "failed to send a message to a node");
+            }
+        }
+    }
+
+    /// Announce to the other nodes that a client connected to this node with
+    /// a session started at the given time, or that it disconnected from it
+    /// if there is none.
+    pub fn announce(&self, client_id: Uuid, started_at: Option<u64>) {
+        let message = started_at.map_or(NodeMessage::Left(client_id), |started_at| {
+            NodeMessage::Hosted {
+                client_id,
+                started_at,
+            }
+        });
+        self.broadcast(&message);
+    }
+
+    /// Forward a message to the node hosting its target, and returns false
+    /// if no node hosts it or if the link is full.
+    pub fn forward(&self, sender: Uuid, target: Uuid, flags: Flags, payload: Vec<u8>) -> bool {
+        let Some(link) = self.locations.get(&target).map(|link| *link) else {
+            return false;
+        };
+        let Some(link) = self.links.get(&link) else {
+            return false;
+        };
+        let message = NodeMessage::Forward {
+            sender,
+            target,
+            flags,
+            payload,
+        };
+        link.sender.try_send(message).is_ok()
+    }
+
+    /// Add a new link, and returns its identifier.
+    fn open(&self, sender: Sender<NodeMessage>) -> u64 {
+        let link = self.next_link.fetch_add(1, Ordering::Relaxed);
+        let clients = HashSet::new();
+        let node = None;
+        self.links.insert(
+            link,
+            Link {
+                node,
+                sender,
+                clients,
+            },
+        );
+        link
+    }
+
+    /// Record the identifier of the node of a link.
+    fn identify(&self, link: u64, node: Uuid) {
+        if let Some(mut link) = self.links.get_mut(&link) {
+            link.node = Some(node);
+        }
+    }
+
+    /// Returns the identifier of the node of a link, if it's known.
+    fn node_of(&self, link: u64) -> Option<Uuid> {
+        self.links.get(&link).and_then(|link| link.node)
+    }
+
+    /// Record that a client is hosted by the node of a link, and returns
+    /// true if it was not hosted by another node.
+    fn host(&self, link: u64, client_id: Uuid) -> bool {
+        if let Some(mut link) = self.links.get_mut(&link) {
+            link.clients.insert(client_id);
+        }
+        self.locations.insert(client_id, link).is_none()
+    }
+
+    /// Record that a client left the node of a link, and returns true if it's
+    /// not hosted by another node.
+    fn leave(&self, link: u64, client_id: Uuid) -> bool {
+        if let Some(mut link) = self.links.get_mut(&link) {
+            link.clients.remove(&client_id);
+        }
+        self.relocate(link, client_id)
+    }
+
+    /// Find another link hosting a client whose link was the given one, and
+    /// returns true if there is none.
+    fn relocate(&self, link: u64, client_id: Uuid) -> bool {
+        if self.locations.get(&client_id).map(|location| *location) != Some(link) {
+            return false;
+        }
+        let other = self
+            .links
+            .iter()
+            .find(|other| other.clients.contains(&client_id))
+            .map(|other| *other.key());
+        let Some(other) = other else {
+            self.locations
+                .remove_if(&client_id, |_, location| *location == link);
+            return true;
+        };
+        self.locations.insert(client_id, other);
+        false
+    }
+
+    /// Remove a link, and returns the clients that are not hosted by another
+    /// node anymore.
+    fn close(&self, link: u64) -> Vec<Uuid> {
+        let Some((_, removed)) = self.links.remove(&link) else {
+            return Vec::new();
+        };
+        removed
+            .clients
+            .into_iter()
+            .filter(|&client_id| self.relocate(link, client_id))
+            .collect()
+    }
+}
+
+/// Link to the peer nodes of the configuration, and link again to them when
+/// their link closes until the server shuts down.
+pub fn connect_peers(relay: &Arc<Relay>) {
+    for peer in relay.cluster.peers.clone() {
+        let relay = Arc::clone(relay);
+        tokio::spawn(async move {
+            let mut stop = relay.shutdown.clone();
+            loop {
+                match dial(&relay, &peer).await {
+                    Ok(()) => info!(%peer, "node link closed"),
+                    Err(e) => debug!(%peer, error = format!("{e:#}"), "node link failed"),
+                }
+                tokio::select! {
+                    () = sleep(RECONNECT_DELAY) => (),
+                    () = async { stop.wait_for(|&stop| stop).await.ok(); } => break,
+                }
+            }
+        });
+    }
+}
+
+/// Open a link to a peer node and handle it until it closes.
+async fn dial(relay: &Arc<Relay>, peer: &str) -> anyhow::Result<()> {
+    // Connect to the peer with the node token.
+    let mut request = format!("{}/node", peer.trim_end_matches('/')).into_client_request()?;
+    let token = relay.cluster.token.as_deref().unwrap_or_default();
+    request.headers_mut().insert(
+        header::AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {token}"))?,
+    );
+    let (socket, _) = timeout(CONNECT_TIMEOUT, connect_async(request))
+        .await
+        .context("timed out")??;
+    info!(%peer, "linked to node");
+
+    // Exchange the binary messages of the link.
+    let (sink, stream) = socket.split();
+    let incoming = stream
+        .take_while(|message| ready(message.as_ref().is_ok_and(|message| !message.is_close())))
+        .filter_map(|message| {
+            ready(match message {
+                Ok(tungstenite::Message::Binary(data)) => Some(data),
+                _ => None,
+            })
+        });
+    let outgoing = sink.with(|data| {
+        ready(Ok::<_, tungstenite::Error>(tungstenite::Message::Binary(
+            data,
+        )))
+    });
+    run_link(relay, incoming, outgoing).await
+}
+
+/// Accept a link opened by another node of the cluster.
+pub async fn accept(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    State(relay): State<Arc<Relay>>,
+) -> Response {
+    let Some(token) = &relay.cluster.token else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if !authorized(&headers, token) {
+        warn!(%address, "node link refused: invalid token");
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    ws.on_upgrade(move |socket| async move {
+        info!(%address, "node linked");
+        let (sink, stream) = socket.split();
+        let incoming = stream
+            .take_while(|message| {
+                ready(
+                    message
+                        .as_ref()
+                        .is_ok_and(|message| !matches!(message, Message::Close(_))),
+                )
+            })
+            .filter_map(|message| {
+                ready(match message {
+                    Ok(Message::Binary(data)) => Some(data),
+                    _ => None,
+                })
+            });
+        let outgoing = sink.with(|data| ready(Ok::<_, axum::Error>(Message::Binary(data))));
+        match run_link(&relay, incoming, outgoing).await {
+            Ok(()) => info!(%address, "node link closed"),
+            Err(e) => warn!(%address, error = format!("{e:#}"), "node link failed"),
+        }
+    })
+}
+
+/// Handle an open link to another node until it closes.
+async fn run_link<E>(
+    relay: &Arc<Relay>,
+    incoming: impl Stream<Item = Vec<u8>>,
+    outgoing: impl Sink<Vec<u8>, Error = E>,
+) -> anyhow::Result<()>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let cluster = &relay.cluster;
+    let (sender, mut receiver) = channel(LINK_CAPACITY);
+    let link = cluster.open(sender);
+    let mut incoming = std::pin::pin!(incoming);
+    let mut outgoing = std::pin::pin!(outgoing);
+
+    // Send the messages of the link and handle the received ones. The hosted
+    // clients are listed once the link is open, so the ones connecting in the
+    // meantime are announced too.
+    let result = async {
+        let hello = NodeMessage::Hello {
+            node: cluster.node,
+            clients: relay
+                .clients
+                .iter()
+                .map(|client| (*client.key(), client.started_at))
+                .collect(),
+        };
+        outgoing.send(hello.encode()).await?;
+        let mut stop = relay.shutdown.clone();
+        loop {
+            tokio::select! {
+                message = receiver.recv() => match message {
+                    Some(message) => outgoing.send(message.encode()).await?,
+                    None => break,
+                },
+                data = incoming.next() => match data {
+                    Some(data) => handle_message(relay, link, &data)?,
+                    None => break,
+                },
+                () = async { stop.wait_for(|&stop| stop).await.ok(); } => break,
+            }
+        }
+        outgoing.close().await?;
+        Ok(())
+    }
+    .await;
+
+    // Remove the link, and announce that the clients it hosted went offline.
+    for client_id in cluster.close(link) {
+        if !relay.clients.contains_key(&client_id) {
+            relay.announce(client_id, false);
+        }
+    }
+    result
+}
+
+/// Handle a message received on a link.
+fn handle_message(relay: &Relay, link: u64, data: &[u8]) -> anyhow::Result<()> {
+    let Some(message) = NodeMessage::decode(data) else {
+        bail!("malformed node message");
+    };
+    match message {
+        NodeMessage::Hello { node, clients } => {
+            if node == relay.cluster.node {
+                bail!("linked to itself");
+            }
+            debug!(%node, clients = clients.len(), "node announced its clients");
+            relay.cluster.identify(link, node);
+            for (client_id, started_at) in clients {
+                host(relay, link, client_id, started_at);
+            }
+        }
+        NodeMessage::Hosted {
+            client_id,
+            started_at,
+        } => host(relay, link, client_id, started_at),
+        NodeMessage::Left(client_id) => {
+            if relay.cluster.leave(link, client_id) && !relay.clients.contains_key(&client_id) {
+                relay.announce(client_id, false);
+            }
+        }
+        NodeMessage::Forward {
+            sender,
+            target,
+            flags,
+            payload,
+        } => {
+            // Deliver the message like the node of its sender would.
+            let message_id = match flags.contains(Flags::ACKNOWLEDGED) {
+                true => match MessageId::split(&payload) {
+                    Some((message_id, _)) => Some(message_id),
+                    None => bail!("malformed forwarded message"),
+                },
+                false => None,
+            };
+            let capabilities = relay
+                .clients
+                .get(&target)
+                .map(|client| client.session.capabilities);
+            match capabilities {
+                Some(capabilities) => {
+                    relay.deliver_message(sender, target, capabilities, flags, payload, message_id);
+                }
+
+                // The target left this node in the meantime.
+                None if flags.contains(Flags::DURABLE) => {
+                    match relay.store_message(sender, target, flags, &payload, message_id) {
+                        Ok(Storage::Stored) => (),
+                        Ok(Storage::Full | Storage::Unregistered) => {
+                            Metrics::increment(&relay.metrics.undeliverable);
+                        }
+                        Err(e) => warn!(%target, error = %e, "failed to store a durable message"),
+                    }
+                }
+                None => Metrics::increment(&relay.metrics.undeliverable),
+            }
+        }
+
+        // Apply the changes of the identities made on the other node.
+        NodeMessage::Secret { client_id, entry } => {
+            relay.db.insert(client_id.as_bytes(), entry)?;
+        }
+        NodeMessage::Revoked(client_id) => {
+            relay.db.remove(client_id.as_bytes())?;
+            relay.last_seen.remove(client_id)?;
+            relay.close(client_id, CloseReason::Kicked);
+        }
+        NodeMessage::Banned(client_id) => {
+            relay.bans.insert(client_id.as_bytes(), &[])?;
+            relay.close(client_id, CloseReason::Banned);
+        }
+        NodeMessage::Unbanned(client_id) => {
+            relay.bans.remove(client_id.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the current time in milliseconds since the unix epoch, used as
+/// the start time of the sessions.
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| {
+            u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+        })
+}
+
+/// Record that a client is hosted by the node of a link with a session
+/// started at the given time, announcing its presence and forwarding the
+/// durable messages stored for it.
+fn host(relay: &Relay, link: u64, client_id: Uuid, started_at: u64) {
+    if relay.cluster.host(link, client_id) && !relay.clients.contains_key(&client_id) {
+        relay.announce(client_id, true);
+    }
+    resolve_duplicate(relay, link, client_id, started_at);
+    let stored = match relay.store.take(client_id) {
+        Ok(stored) => stored,
+        Err(e) => {
+            warn!(%client_id, error = %e, "failed to read the durable messages");
+            return;
+        }
+    };
+    for message in stored {
+        let flags = message.flags | Flags::DURABLE;
+        if !relay
+            .cluster
+            .forward(message.sender, client_id, flags, message.payload.clone())
+        {
+            relay
+                .store
+                .push(client_id, message.sender, message.flags, &message.payload)
+                .ok();
+        }
+    }
+}
+
+/// Apply the duplicate session policy to a client hosted both by this node
+/// and by the node of a link, with a session started at the given time.
+///
+/// Both nodes order the two sessions the same way, by start time and then by
+/// node identifier, so only one of them is closed: the older one if newer
+/// sessions supersede it, and the newer one otherwise.
+fn resolve_duplicate(relay: &Relay, link: u64, client_id: Uuid, started_at: u64) {
+    let Some(local) = relay
+        .clients
+        .get(&client_id)
+        .map(|client| client.started_at)
+    else {
+        return;
+    };
+    let Some(node) = relay.cluster.node_of(link) else {
+        return;
+    };
+    let newer = (local, relay.cluster.node) > (started_at, node);
+    let reason = match (relay.duplicate_sessions, newer) {
+        (DuplicateSessionPolicy::Supersede, false) => CloseReason::Superseded,
+        (DuplicateSessionPolicy::Reject, true) => CloseReason::AlreadyConnected,
+        _ => return,
+    };
+    info!(%client_id, %reason, "duplicate session on another node closed");
+    relay.close(client_id, reason);
+}
@@ -0,0 +1,516 @@
+//! The configuration of the relay server.
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+
+/// The format of the logs of the relay server.
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+
+    /// One JSON object per line, with the fields of each event.
+    Json,
+}
+
+/// What the relay server does with a client that doesn't receive its
+/// messages fast enough to keep its channel from filling up.
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowConsumerPolicy {
+    /// Drop the messages that don't fit in the channel.
+    #[default]
+    Drop,
+
+    /// Drop the message and close the session of the client.
+    Disconnect,
+}
+
+/// What the relay server does when a client connects with an identity that
+/// is already used by another session.
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateSessionPolicy {
+    /// Close the older session, so the newer one takes its place.
+    #[default]
+    Supersede,
+
+    /// Refuse the newer session.
+    Reject,
+}
+
+/// The command line arguments of the relay server.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+    /// The path to a TOML configuration file.
+    #[arg(short, long, env = "RELAY_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// The options overriding the configuration file.
+    #[command(flatten)]
+    pub options: Options,
+
+    /// The command to run instead of serving connections.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// A command run instead of serving connections.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Maintain the database, which must not be used by a running server.
+    #[command(subcommand)]
+    Maintenance(Maintenance),
+}
+
+/// A maintenance operation on the database.
+#[derive(Subcommand, Debug)]
+pub enum Maintenance {
+    /// Rewrite the database to reclaim the space left by removed entries.
+    Compact,
+
+    /// Export the identities as JSON lines.
+    Export {
+        /// The file to write the identities to, or the standard output if
+        /// there is none.
+        output: Option<PathBuf>,
+    },
+
+    /// Import identities exported as JSON lines, replacing the existing
+    /// identities with the same identifiers.
+    Import {
+        /// The file to read the identities from, or the standard input if
+        /// there is none.
+        input: Option<PathBuf>,
+    },
+
+    /// Remove the identities unused for a number of days, with their bans
+    /// and durable messages.
+    Prune {
+        /// The number of days after which an unused identity is removed.
+        #[arg(long)]
+        days: u64,
+    },
+}
+
+/// The options of the relay server.
+///
+/// Each option can be given on the command line, in the environment or in
+/// the configuration file, in this order of priority.
+#[derive(clap::Args, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Options {
+    /// The address the server listens on.
+    #[arg(long, env = "RELAY_ADDRESS")]
+    pub address: Option<IpAddr>,
+
+    /// The port the server listens on, 443 by default with TLS and 80
+    /// otherwise.
+    #[arg(long, env = "RELAY_PORT")]
+    pub port: Option<u16>,
+
+    /// The path to the database storing the client secrets.
+    #[arg(long, env = "RELAY_DATABASE")]
+    pub database: Option<PathBuf>,
+
+    /// The path to the PEM file of the TLS certificate chain.
+    ///
+    /// The server only accepts TLS connections if it's given with a key.
+    #[arg(long, env = "RELAY_TLS_CERTIFICATE")]
+    pub tls_certificate: Option<PathBuf>,
+
+    /// The path to the PEM file of the private key of the TLS certificate.
+    #[arg(long, env = "RELAY_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// The number of messages that can be waiting to be sent to a client.
+    #[arg(long, env = "RELAY_CHANNEL_CAPACITY")]
+    pub channel_capacity: Option<usize>,
+
+    /// What to do with a client whose channel is full.
+    #[arg(long, env = "RELAY_SLOW_CONSUMER", value_enum)]
+    pub slow_consumer: Option<SlowConsumerPolicy>,
+
+    /// What to do when a client connects with an identity already in use.
+    #[arg(long, env = "RELAY_DUPLICATE_SESSIONS", value_enum)]
+    pub duplicate_sessions: Option<DuplicateSessionPolicy>,
+
+    /// The interval between two pings sent to each client, in seconds.
+    #[arg(long, env = "RELAY_PING_INTERVAL")]
+    pub ping_interval: Option<u64>,
+
+    /// The time without receiving anything from a client after which its
+    /// session is closed, in seconds.
+    #[arg(long, env = "RELAY_PING_TIMEOUT")]
+    pub ping_timeout: Option<u64>,
+
+    /// The time after which a durable message waiting for an offline client
+    /// is discarded, in seconds.
+    #[arg(long, env = "RELAY_DURABLE_TTL")]
+    pub durable_ttl: Option<u64>,
+
+    /// The maximum number of bytes of durable messages stored for a single
+    /// offline client.
+    #[arg(long, env = "RELAY_DURABLE_CAPACITY")]
+    pub durable_capacity: Option<usize>,
+
+    /// The maximum number of bytes of durable messages stored for all the
+    /// offline clients.
+    #[arg(long, env = "RELAY_DURABLE_TOTAL_CAPACITY")]
+    pub durable_total_capacity: Option<usize>,
+
+    /// The maximum size of a frame sent by a client, in bytes.
+    #[arg(long, env = "RELAY_MAX_FRAME_SIZE")]
+    pub max_frame_size: Option<usize>,
+
+    /// The maximum number of frames a client can send per second.
+    #[arg(long, env = "RELAY_MAX_MESSAGES_PER_SECOND")]
+    pub max_messages_per_second: Option<u32>,
+
+    /// The maximum number of bytes a client can send per second.
+    #[arg(long, env = "RELAY_MAX_BYTES_PER_SECOND")]
+    pub max_bytes_per_second: Option<u32>,
+
+    /// The maximum number of identities registered from a single address per
+    /// hour.
+    #[arg(long, env = "RELAY_MAX_REGISTRATIONS_PER_HOUR")]
+    pub max_registrations_per_hour: Option<u32>,
+
+    /// The maximum number of concurrent connections.
+    #[arg(long, env = "RELAY_MAX_CONNECTIONS")]
+    pub max_connections: Option<usize>,
+
+    /// The token required to use the admin API, which is disabled if there
+    /// is none.
+    #[arg(long, env = "RELAY_ADMIN_TOKEN")]
+    pub admin_token: Option<String>,
+
+    /// The URLs of the other nodes of the cluster, to which the messages
+    /// for the clients they host are forwarded (for example
+    /// `wss://relay-2.example.com`).
+    #[arg(long, env = "RELAY_PEERS", value_delimiter = ',')]
+    pub peers: Option<Vec<String>>,
+
+    /// Allow linking to peer nodes over plain `ws` URLs, which send the node
+    /// token and the forwarded messages in clear text.
+    #[arg(
+        long,
+        env = "RELAY_INSECURE_PEERS",
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    pub insecure_peers: Option<bool>,
+
+    /// The token shared by the nodes of the cluster, which is required to
+    /// link them together.
+    #[arg(long, env = "RELAY_NODE_TOKEN")]
+    pub node_token: Option<String>,
+
+    /// The logging filter (for example `info` or `relay_server=debug`).
+    #[arg(long, env = "RELAY_LOG")]
+    pub log: Option<String>,
+
+    /// The format of the logs.
+    #[arg(long, env = "RELAY_LOG_FORMAT", value_enum)]
+    pub log_format: Option<LogFormat>,
+}
+
+impl Options {
+    /// Fill the missing options of `self` with the ones from `other`.
+    fn or(self, other: Self) -> Self {
+        Self {
+            address: self.address.or(other.address),
+            port: self.port.or(other.port),
+            database: self.database.or(other.database),
+            tls_certificate: self.tls_certificate.or(other.tls_certificate),
+            tls_key: self.tls_key.or(other.tls_key),
+            channel_capacity: self.channel_capacity.or(other.channel_capacity),
+            slow_consumer: self.slow_consumer.or(other.slow_consumer),
+            duplicate_sessions: self.duplicate_sessions.or(other.duplicate_sessions),
+            ping_interval: self.ping_interval.or(other.ping_interval),
+            ping_timeout: self.ping_timeout.or(other.ping_timeout),
+            durable_ttl: self.durable_ttl.or(other.durable_ttl),
+            durable_capacity: self.durable_capacity.or(other.durable_capacity),
+            durable_total_capacity: self.durable_total_capacity.or(other.durable_total_capacity),
+            max_frame_size: self.max_frame_size.or(other.max_frame_size),
+            max_messages_per_second: self
+                .max_messages_per_second
+                .or(other.max_messages_per_second),
+            max_bytes_per_second: self.max_bytes_per_second.or(other.max_bytes_per_second),
+            max_registrations_per_hour: self
+                .max_registrations_per_hour
+                .or(other.max_registrations_per_hour),
+            max_connections: self.max_connections.or(other.max_connections),
+            admin_token: self.admin_token.or(other.admin_token),
+            peers: self.peers.or(other.peers),
+            insecure_peers: self.insecure_peers.or(other.insecure_peers),
+            node_token: self.node_token.or(other.node_token),
+            log: self.log.or(other.log),
+            log_format: self.log_format.or(other.log_format),
+        }
+    }
+}
+
+/// The configuration of the relay server.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The address the server listens on.
+    pub address: SocketAddr,
+
+    /// The path to the database storing the client secrets.
+    ///
+    /// If there is none, a temporary database is used and removed when the
+    /// server stops.
+    pub database: Option<PathBuf>,
+
+    /// The TLS certificate used to secure the connections, which are in
+    /// plain text if there is none.
+    pub tls: Option<TlsConfig>,
+
+    /// The number of messages that can be waiting to be sent to a client.
+    pub channel_capacity: usize,
+
+    /// What to do with a client whose channel is full.
+    pub slow_consumer: SlowConsumerPolicy,
+
+    /// What to do when a client connects with an identity already in use.
+    pub duplicate_sessions: DuplicateSessionPolicy,
+
+    /// The interval between two pings sent to each client.
+    pub ping_interval: Duration,
+
+    /// The time without receiving anything from a client after which its
+    /// session is closed.
+    pub ping_timeout: Duration,
+
+    /// The time after which a durable message waiting for an offline client
+    /// is discarded.
+    pub durable_ttl: Duration,
+
+    /// The maximum number of bytes of durable messages stored for a single
+    /// offline client.
+    pub durable_capacity: usize,
+
+    /// The maximum number of bytes of durable messages stored for all the
+    /// offline clients.
+    pub durable_total_capacity: usize,
+
+    /// The maximum size of a frame sent by a client, in bytes.
+    pub max_frame_size: usize,
+
+    /// The maximum number of frames a client can send per second.
+    pub max_messages_per_second: u32,
+
+    /// The maximum number of bytes a client can send per second.
+    ///
+    /// This should be at least the maximum frame size, otherwise the largest
+    /// frames would never be accepted.
+    pub max_bytes_per_second: u32,
+
+    /// The maximum number of identities registered from a single address per
+    /// hour.
+    pub max_registrations_per_hour: u32,
+
+    /// The maximum number of concurrent connections.
+    pub max_connections: usize,
+
+    /// The token required to use the admin API, which is disabled if there
+    /// is none.
+    pub admin_token: Option<String>,
+
+    /// The URLs of the other nodes of the cluster.
+    pub peers: Vec<String>,
+
+    /// The token shared by the nodes of the cluster, which is required to
+    /// link them together. Other nodes can't link to this one if there is
+    /// none.
+    pub node_token: Option<String>,
+
+    /// The logging filter.
+    pub log: String,
+
+    /// The format of the logs.
+    pub log_format: LogFormat,
+}
+
+/// The files of the TLS certificate of the relay server.
+///
+/// They are reloaded when they change, so a renewed certificate is used
+/// without restarting the server.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// The path to the PEM file of the certificate chain.
+    pub certificate: PathBuf,
+
+    /// The path to the PEM file of the private key.
+    pub key: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::from(Options::default())
+    }
+}
+
+impl From<Options> for Config {
+    fn from(options: Options) -> Self {
+        let tls = match (options.tls_certificate, options.tls_key) {
+            (Some(certificate), Some(key)) => Some(TlsConfig { certificate, key }),
+            _ => None,
+        };
+        let default_port = if tls.is_some() { 443 } else { 80 };
+        Self {
+            address: SocketAddr::new(
+                options.address.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+                options.port.unwrap_or(default_port),
+            ),
+            database: Some(
+                options
+                    .database
+                    .unwrap_or_else(|| PathBuf::from("/data/secrets.db")),
+            ),
+            tls,
+            channel_capacity: options.channel_capacity.unwrap_or(128),
+            slow_consumer: options.slow_consumer.unwrap_or_default(),
+            duplicate_sessions: options.duplicate_sessions.unwrap_or_default(),
+            ping_interval: Duration::from_secs(options.ping_interval.unwrap_or(15)),
+            ping_timeout: Duration::from_secs(options.ping_timeout.unwrap_or(45)),
+            durable_ttl: Duration::from_secs(options.durable_ttl.unwrap_or(24 * 60 * 60)),
+            durable_capacity: options.durable_capacity.unwrap_or(1024 * 1024),
+            durable_total_capacity: options.durable_total_capacity.unwrap_or(256 * 1024 * 1024),
+            max_frame_size: options.max_frame_size.unwrap_or(64 * 1024),
+            max_messages_per_second: options.max_messages_per_second.unwrap_or(100),
+            max_bytes_per_second: options.max_bytes_per_second.unwrap_or(1024 * 1024),
+            max_registrations_per_hour: options.max_registrations_per_hour.unwrap_or(10),
+            max_connections: options.max_connections.unwrap_or(10_000),
+            admin_token: options.admin_token,
+            peers: options.peers.unwrap_or_default(),
+            node_token: options.node_token,
+            log: options.log.unwrap_or_else(|| "info".to_owned()),
+            log_format: options.log_format.unwrap_or_default(),
+        }
+    }
+}
+
+impl Config {
+    /// Create a configuration for a relay server listening on an ephemeral
+    /// localhost port and using a temporary database.
+    ///
+    /// This is useful to run a relay server inside of tests.
+    pub fn local() -> Self {
+        Self {
+            address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+            database: None,
+            ..Self::default()
+        }
+    }
+
+    /// Load the configuration from the command line, the environment and
+    /// the configuration file if there is one, and returns it with the
+    /// command to run, if any.
+    pub fn load() -> anyhow::Result<(Self, Option<Command>)> {
+        let args = Args::parse();
+
+        // Read the configuration file if there is one.
+        let file_options = match args.config {
+            Some(path) => {
+                let contents = fs::read_to_string(&path)
+                    .with_context(|| format!("unable to read {}", path.display()))?;
+                toml::from_str(&contents)
+                    .with_context(|| format!("invalid configuration in {}", path.display()))?
+            }
+            None => Options::default(),
+        };
+
+        // The command line and the environment take priority over the file.
+        let options = args.options.or(file_options);
+        if options.tls_certificate.is_some() != options.tls_key.is_some() {
+            bail!("the TLS certificate and key must be given together");
+        }
+        let peers = options.peers.as_deref().unwrap_or_default();
+        if !peers.is_empty() && options.node_token.is_none() {
+            bail!("the node token is required to link to other nodes");
+        }
+        let insecure_peers = options.insecure_peers.unwrap_or(false);
+        for peer in peers {
+            if !peer.starts_with("wss://") && !(insecure_peers && peer.starts_with("ws://")) {
+                bail!("the peer {peer} must use wss, or ws if insecure peers are allowed");
+            }
+        }
+        let config = Self::from(options);
+        config.validate()?;
+        Ok((config, args.command))
+    }
+
+    /// Returns an error if a value of the configuration can't be used by the
+    /// relay server.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.channel_capacity == 0 {
+            bail!("the channel capacity must be at least 1");
+        }
+        if self.ping_interval.is_zero() || self.ping_timeout.is_zero() {
+            bail!("the ping interval and timeout must not be zero");
+        }
+        if self.durable_total_capacity < self.durable_capacity {
+            bail!("the durable total capacity must be at least the durable capacity");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The default configuration is valid.
+    #[test]
+    fn default_valid() -> anyhow::Result<()> {
+        Config::default().validate()?;
+        Config::local().validate()
+    }
+
+    /// A channel that can't hold any message is rejected.
+    #[test]
+    fn zero_channel_capacity() {
+        let config = Config {
+            channel_capacity: 0,
+            ..Config::local()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    /// Zero ping durations are rejected.
+    #[test]
+    fn zero_ping_durations() {
+        let config = Config {
+            ping_interval: Duration::ZERO,
+            ..Config::local()
+        };
+        assert!(config.validate().is_err());
+        let config = Config {
+            ping_timeout: Duration::ZERO,
+            ..Config::local()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    /// A total durable capacity smaller than the capacity of a single client
+    /// is rejected.
+    #[test]
+    fn small_durable_total_capacity() {
+        let config = Config {
+            durable_total_capacity: 1024,
+            ..Config::local()
+        };
+        assert!(config.validate().is_err());
+    }
+}
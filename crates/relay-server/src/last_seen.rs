@@ -0,0 +1,84 @@
+//! The tracking of the last time each identity was used.
+
+use sled::{Db, Tree};
+use uuid::Uuid;
+
+use crate::store::now;
+
+/// The last time each identity was used, in seconds since the unix epoch.
+pub struct LastSeen {
+    /// The tree storing the timestamps.
+    tree: Tree,
+}
+
+impl LastSeen {
+    /// Open the timestamps in the given database.
+    pub fn open(db: &Db) -> sled::Result<Self> {
+        Ok(Self {
+            tree: db.open_tree("last-seen")?,
+        })
+    }
+
+    /// Record that an identity is used now.
+    pub fn touch(&self, client_id: Uuid) -> sled::Result<()> {
+        self.set(client_id, now())
+    }
+
+    /// Returns the last time an identity was used, if it's known.
+    pub fn get(&self, client_id: Uuid) -> sled::Result<Option<u64>> {
+        Ok(self.tree.get(client_id.as_bytes())?.and_then(|value| {
+            value
+                .first_chunk::<8>()
+                .map(|timestamp| u64::from_be_bytes(*timestamp))
+        }))
+    }
+
+    /// Record the time an identity was last used.
+    pub fn set(&self, client_id: Uuid, timestamp: u64) -> sled::Result<()> {
+        self.tree
+            .insert(client_id.as_bytes(), &timestamp.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Forget when an identity was last used.
+    pub fn remove(&self, client_id: Uuid) -> sled::Result<()> {
+        self.tree.remove(client_id.as_bytes())?;
+        Ok(())
+    }
+
+    /// Returns the identities that were not used since the given time.
+    pub fn before(&self, timestamp: u64) -> sled::Result<Vec<Uuid>> {
+        let mut identities = Vec::new();
+        for entry in self.tree.iter() {
+            let (key, value) = entry?;
+            let used = value
+                .first_chunk::<8>()
+                .map_or(0, |used| u64::from_be_bytes(*used));
+            if used >= timestamp {
+                continue;
+            }
+            if let Ok(client_id) = Uuid::from_slice(&key) {
+                identities.push(client_id);
+            }
+        }
+        Ok(identities)
+    }
+
+    /// Record the identities of the database that were never tracked,
+    /// which were created by previous versions of the relay server, as used
+    /// now, and returns how many were recorded.
+    pub fn track(&self, db: &Db) -> sled::Result<usize> {
+        let now = now().to_be_bytes();
+        let mut tracked = 0;
+        for key in db.iter().keys() {
+            let key = key?;
+            let swap = self
+                .tree
+                .compare_and_swap(&key, None::<&[u8]>, Some(&now))?;
+            if swap.is_ok() {
+                tracked += 1;
+            }
+        }
+        Ok(tracked)
+    }
+}
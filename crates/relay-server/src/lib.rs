@@ -0,0 +1,1205 @@
+//! A relay server for bevnet.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use axum::extract::{ConnectInfo, State, WebSocketUpgrade};
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use relay_protocol::{
+    Capabilities, CloseReason, Credentials, DecodeError, Flags, Frame, Hello, MessageId,
+    Notification, Request, RoomName, Welcome, LEGACY_VERSION, PROTOCOL_VERSION,
+};
+use sled::transaction::{ConflictableTransactionResult, TransactionalTree};
+use sled::{Db, IVec, Tree};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::watch;
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{interval, interval_at, sleep, timeout, Instant, MissedTickBehavior};
+use tokio_tungstenite::tungstenite;
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
+use uuid::Uuid;
+
+use self::cluster::{Cluster, NodeMessage};
+pub use self::config::Config;
+use self::config::{DuplicateSessionPolicy, SlowConsumerPolicy};
+use self::last_seen::LastSeen;
+use self::limits::Limits;
+use self::metrics::Metrics;
+use self::store::MessageStore;
+
+/// The maximum time to wait for the sessions to close when shutting down.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The interval between two removals of the expired durable messages and
+/// registrations.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+mod admin;
+mod cluster;
+pub mod config;
+mod last_seen;
+mod limits;
+pub mod maintenance;
+mod metrics;
+mod rooms;
+mod secrets;
+mod store;
+mod tls;
+
+/// A client connected to the relay server.
+struct Client {
+    /// The identifier of the session, unique to each connection.
+    session_id: u64,
+
+    /// The session negotiated with the client.
+    session: Session,
+
+    /// The address the client is connected from.
+    address: IpAddr,
+
+    /// The time the client connected.
+    connected_at: Instant,
+
+    /// The start time of the session in milliseconds since the unix epoch,
+    /// used to order the sessions of an identity on different nodes.
+    started_at: u64,
+
+    /// The sender of the frames to send to the client.
+    sender: Sender<Frame<Notification>>,
+
+    /// The sender used to close the session of the client.
+    closer: Arc<watch::Sender<Option<CloseReason>>>,
+
+    /// The number of frames dropped because the client didn't receive them
+    /// fast enough.
+    dropped: AtomicU64,
+}
+
+/// The outcome of storing a durable message.
+enum Storage {
+    /// The message is stored.
+    Stored,
+
+    /// The message doesn't fit in the store.
+    Full,
+
+    /// The target isn't a registered identity.
+    Unregistered,
+}
+
+/// The state shared by all the connections of a relay server.
+struct Relay {
+    /// The connected clients.
+    clients: DashMap<Uuid, Client>,
+
+    /// The peers whose presence each client is subscribed to.
+    subscriptions: DashMap<Uuid, HashSet<Uuid>>,
+
+    /// The clients subscribed to the presence of each peer.
+    watchers: DashMap<Uuid, HashSet<Uuid>>,
+
+    /// The members of each room.
+    rooms: DashMap<RoomName, HashSet<Uuid>>,
+
+    /// The rooms each client is a member of.
+    memberships: DashMap<Uuid, HashSet<RoomName>>,
+
+    /// The database storing the client secrets.
+    db: Db,
+
+    /// The banned identities.
+    bans: Tree,
+
+    /// The last time each identity was used.
+    last_seen: LastSeen,
+
+    /// The durable messages waiting for their offline targets.
+    store: MessageStore,
+
+    /// The limits enforced on the clients.
+    limits: Limits,
+
+    /// The number of messages that can be waiting to be sent to a client.
+    channel_capacity: usize,
+
+    /// What to do with a client whose channel is full.
+    slow_consumer: SlowConsumerPolicy,
+
+    /// What to do when a client connects with an identity already in use.
+    duplicate_sessions: DuplicateSessionPolicy,
+
+    /// The identifier of the next session.
+    next_session: AtomicU64,
+
+    /// The metrics of the server.
+    metrics: Metrics,
+
+    /// The interval between two pings sent to each client.
+    ping_interval: Duration,
+
+    /// The time without receiving anything from a client after which its
+    /// session is closed.
+    ping_timeout: Duration,
+
+    /// The token required to use the admin API, if it's enabled.
+    admin_token: Option<String>,
+
+    /// The links to the other nodes of the cluster.
+    cluster: Cluster,
+
+    /// The capabilities the relay server grants, without the rooms on a
+    /// node of a cluster since they aren't shared with the other nodes.
+    capabilities: Capabilities,
+
+    /// A receiver that is notified when the server is shutting down.
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Relay {
+    /// Returns the durable messages stored for a client that just connected,
+    /// with their keys to remove them from the store once they are written.
+    fn pending_stored(&self, client_id: Uuid) -> Vec<(IVec, Frame<Notification>)> {
+        let messages = match self.store.pending(client_id) {
+            Ok(messages) => messages,
+            Err(e) => {
+                warn!(%client_id, error = %e, "failed to read the durable messages");
+                return Vec::new();
+            }
+        };
+        messages
+            .into_iter()
+            .map(|message| {
+                let frame = Frame::Data {
+                    peer: message.sender,
+                    flags: message.flags | Flags::DURABLE,
+                    payload: message.payload,
+                };
+                (message.key, frame)
+            })
+            .collect()
+    }
+
+    /// Send a frame to a client if it's connected, without waiting.
+    ///
+    /// If the channel of the client is full, the frame is dropped and the
+    /// [SlowConsumerPolicy] of the server is applied.
+    fn deliver(&self, client_id: Uuid, frame: Frame<Notification>) -> bool {
+        let Some(client) = self.clients.get(&client_id) else {
+            return false;
+        };
+        let size = match &frame {
+            Frame::Data { payload, .. } => Some(payload.len()),
+            Frame::Control(_) => None,
+        };
+        match client.sender.try_send(frame) {
+            Ok(()) => {
+                if let Some(size) = size {
+                    self.metrics.relayed(size);
+                }
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                client.dropped.fetch_add(1, Ordering::Relaxed);
+                Metrics::increment(&self.metrics.dropped);
+                if self.slow_consumer == SlowConsumerPolicy::Disconnect {
+                    client.closer.send_replace(Some(CloseReason::SlowConsumer));
+                }
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Send the receipt of an acknowledged message to its sender, on behalf
+    /// of its target, through the node hosting the sender if it's not this
+    /// one.
+    fn acknowledge(&self, sender_id: Uuid, target_id: Uuid, message_id: MessageId) {
+        let payload = message_id.to_bytes().to_vec();
+        if !self.clients.contains_key(&sender_id) {
+            self.cluster
+                .forward(target_id, sender_id, Flags::RECEIPT, payload);
+            return;
+        }
+        let frame = Frame::Data {
+            peer: target_id,
+            flags: Flags::RECEIPT,
+            payload,
+        };
+        self.deliver(sender_id, frame);
+    }
+
+    /// Send a message to a connected client, and acknowledge it on its behalf
+    /// if it can't.
+    ///
+    /// The message identifier is only given if the message is acknowledged
+    /// and its sender can receive the receipts.
+    fn deliver_message(
+        &self,
+        sender_id: Uuid,
+        target_id: Uuid,
+        capabilities: Capabilities,
+        flags: Flags,
+        payload: Vec<u8>,
+        message_id: Option<MessageId>,
+    ) {
+        let acknowledger =
+            message_id.filter(|_| !capabilities.contains(Capabilities::ACKNOWLEDGEMENTS));
+        let frame = match acknowledger {
+            Some(_) => Frame::Data {
+                peer: sender_id,
+                flags: flags.difference(Flags::ACKNOWLEDGED),
+                payload: payload[MessageId::LENGTH..].to_vec(),
+            },
+            None => Frame::Data {
+                peer: sender_id,
+                flags,
+                payload,
+            },
+        };
+        if let (true, Some(message_id)) = (self.deliver(target_id, frame), acknowledger) {
+            self.acknowledge(sender_id, target_id, message_id);
+        }
+    }
+
+    /// Send the stored secret of a client to the other nodes, after it was
+    /// created or rotated.
+    fn share_secret(&self, client_id: Uuid) -> sled::Result<()> {
+        if let Some(entry) = self.db.get(client_id.as_bytes())? {
+            self.cluster.broadcast(&NodeMessage::Secret {
+                client_id,
+                entry: entry.to_vec(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Store a durable message for a registered client that is not
+    /// connected, and acknowledge it once stored.
+    fn store_message(
+        &self,
+        sender_id: Uuid,
+        target_id: Uuid,
+        flags: Flags,
+        payload: &[u8],
+        message_id: Option<MessageId>,
+    ) -> sled::Result<Storage> {
+        if !self.db.contains_key(target_id.as_bytes())? {
+            return Ok(Storage::Unregistered);
+        }
+        let stored = match message_id {
+            Some(_) => &payload[MessageId::LENGTH..],
+            None => payload,
+        };
+        let kept = flags.intersection(Flags::ENCRYPTED);
+        if !self.store.push(target_id, sender_id, kept, stored)? {
+            return Ok(Storage::Full);
+        }
+        debug!(target = %target_id, "durable message stored");
+        if let Some(message_id) = message_id {
+            self.acknowledge(sender_id, target_id, message_id);
+        }
+        Ok(Storage::Stored)
+    }
+
+    /// Close the session of a client, and returns false if it's not
+    /// connected.
+    fn close(&self, client_id: Uuid, reason: CloseReason) -> bool {
+        let Some(client) = self.clients.get(&client_id) else {
+            return false;
+        };
+        client.closer.send_replace(Some(reason));
+        true
+    }
+
+    /// Add a connected client, closing the older session of the same
+    /// identity if there is one, or returns false if the policy refuses the
+    /// newer session.
+    fn connect(&self, client_id: Uuid, client: Client) -> bool {
+        // The identity may be used by a session on another node.
+        if self.duplicate_sessions == DuplicateSessionPolicy::Reject
+            && self.cluster.is_remote(client_id)
+        {
+            return false;
+        }
+
+        let previous = match self.clients.entry(client_id) {
+            Entry::Vacant(entry) => {
+                entry.insert(client);
+                return true;
+            }
+            Entry::Occupied(_) if self.duplicate_sessions == DuplicateSessionPolicy::Reject => {
+                return false;
+            }
+            Entry::Occupied(mut entry) => entry.insert(client),
+        };
+
+        // The newer session starts without the subscriptions and rooms of the
+        // older one, which is closed without touching the new entry.
+        previous.closer.send_replace(Some(CloseReason::Superseded));
+        self.unsubscribe_all(client_id);
+        self.leave_all_rooms(client_id);
+        info!(
+            previous_session = previous.session_id,
+            "older session superseded"
+        );
+        true
+    }
+
+    /// Handle a control request sent by a client, ignoring it if its
+    /// session didn't negotiate the capability it needs.
+    fn control(&self, client_id: Uuid, capabilities: Capabilities, request: Request) {
+        if !capabilities.contains(request.capability()) {
+            debug!("request without its capability ignored");
+            return;
+        }
+        match request {
+            Request::Subscribe(peer) => self.subscribe(client_id, peer),
+            Request::Unsubscribe(peer) => self.unsubscribe(client_id, peer),
+            Request::CreateRoom(room) => self.create_room(client_id, room),
+            Request::JoinRoom(room) => self.join_room(client_id, room),
+            Request::LeaveRoom(room) => self.leave_room(client_id, room),
+            Request::ListRoom(room) => self.list_room(client_id, room),
+            Request::SendToRoom { room, payload } => {
+                self.send_to_room(client_id, room, payload);
+            }
+
+            // The rotation waits for the database, so it's handled by the
+            // session itself.
+            Request::RotateSecret => (),
+        }
+    }
+
+    /// Send a control notification to a client if it's connected.
+    fn notify(&self, client_id: Uuid, notification: Notification) {
+        self.deliver(client_id, Frame::Control(notification));
+    }
+
+    /// Notify the clients subscribed to the presence of a client that it
+    /// came online or went offline.
+    fn announce(&self, client_id: Uuid, online: bool) {
+        let watchers: Vec<Uuid> = self
+            .watchers
+            .get(&client_id)
+            .map(|watchers| watchers.iter().copied().collect())
+            .unwrap_or_default();
+        let notification = match online {
+            true => Notification::Online(client_id),
+            false => Notification::Offline(client_id),
+        };
+        for watcher in watchers {
+            self.notify(watcher, notification.clone());
+        }
+    }
+
+    /// Subscribe a client to the presence of a peer, and send it the current
+    /// presence of the peer.
+    fn subscribe(&self, client_id: Uuid, peer: Uuid) {
+        self.subscriptions
+            .entry(client_id)
+            .or_default()
+            .insert(peer);
+        self.watchers.entry(peer).or_default().insert(client_id);
+        let online = self.clients.contains_key(&peer) || self.cluster.is_remote(peer);
+        let notification = match online {
+            true => Notification::Online(peer),
+            false => Notification::Offline(peer),
+        };
+        self.notify(client_id, notification);
+    }
+
+    /// Unsubscribe a client from the presence of a peer.
+    fn unsubscribe(&self, client_id: Uuid, peer: Uuid) {
+        if let Some(mut peers) = self.subscriptions.get_mut(&client_id) {
+            peers.remove(&peer);
+        }
+        self.watchers.remove_if_mut(&peer, |_, watchers| {
+            watchers.remove(&client_id);
+            watchers.is_empty()
+        });
+    }
+
+    /// Remove all the presence subscriptions of a client.
+    fn unsubscribe_all(&self, client_id: Uuid) {
+        let Some((_, peers)) = self.subscriptions.remove(&client_id) else {
+            return;
+        };
+        for peer in peers {
+            self.unsubscribe(client_id, peer);
+        }
+    }
+}
+
+/// A relay server running in the background.
+///
+/// Multiple relay servers can run in the same process, which makes it
+/// possible to test clients against a real relay by binding one on an
+/// ephemeral localhost port (see [Config::local]).
+pub struct RelayServer {
+    /// The address the server is listening on.
+    local_addr: SocketAddr,
+
+    /// The sender used to ask the server to shut down.
+    shutdown: watch::Sender<bool>,
+
+    /// The task serving the connections.
+    task: JoinHandle<anyhow::Result<()>>,
+
+    /// The state shared by the connections.
+    relay: Arc<Relay>,
+}
+
+impl RelayServer {
+    /// Bind a new relay server with the given configuration and start
+    /// serving connections in the background.
+    ///
+    /// The configuration is validated first (see [Config::validate]).
+    pub async fn bind(config: Config) -> anyhow::Result<Self> {
+        config.validate().context("invalid configuration")?;
+
+        // Open the database.
+        let mut database = sled::Config::new().temporary(config.database.is_none());
+        if let Some(path) = &config.database {
+            database = database.path(path);
+        }
+        let db = database.open().context("unable to open the database")?;
+        let migrated = secrets::migrate(&db).context("unable to migrate the secrets")?;
+        if migrated > 0 {
+            info!(migrated, "plain secrets hashed");
+        }
+        let store = MessageStore::open(
+            &db,
+            config.durable_ttl,
+            config.durable_capacity,
+            config.durable_total_capacity,
+        )
+        .context("unable to open the message store")?;
+        let bans = db
+            .open_tree("bans")
+            .context("unable to open the ban list")?;
+        let last_seen = LastSeen::open(&db).context("unable to open the last seen times")?;
+        let tracked = last_seen
+            .track(&db)
+            .context("unable to track the identities")?;
+        if tracked > 0 {
+            info!(tracked, "untracked identities marked as seen");
+        }
+
+        // Create the shared state of the server.
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+        let cluster = Cluster::new(&config);
+        let capabilities = if cluster.is_enabled() {
+            Capabilities(Capabilities::ALL.0 & !Capabilities::ROOMS.0)
+        } else {
+            Capabilities::ALL
+        };
+        let relay = Arc::new(Relay {
+            clients: DashMap::new(),
+            subscriptions: DashMap::new(),
+            watchers: DashMap::new(),
+            rooms: DashMap::new(),
+            memberships: DashMap::new(),
+            db,
+            bans,
+            last_seen,
+            store,
+            limits: Limits::new(&config),
+            channel_capacity: config.channel_capacity,
+            slow_consumer: config.slow_consumer,
+            duplicate_sessions: config.duplicate_sessions,
+            next_session: AtomicU64::new(0),
+            metrics: Metrics::default(),
+            ping_interval: config.ping_interval,
+            ping_timeout: config.ping_timeout,
+            cluster,
+            capabilities,
+            admin_token: config.admin_token,
+            shutdown: shutdown_receiver,
+        });
+
+        // Remove the expired durable messages and registrations regularly.
+        let purger = Arc::clone(&relay);
+        tokio::spawn(async move {
+            let mut stop = purger.shutdown.clone();
+            let mut purges = interval(PURGE_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = purges.tick() => {
+                        match purger.store.purge() {
+                            Ok(0) => (),
+                            Ok(removed) => debug!(removed, "expired durable messages removed"),
+                            Err(e) => warn!(error = %e, "failed to remove the expired durable messages"),
+                        }
+                        purger.limits.prune();
+                    }
+                    _ = stop.wait_for(|&stop| stop) => break,
+                }
+            }
+        });
+
+        // Load the TLS certificate if there is one.
+        let acceptor = match &config.tls {
+            Some(tls) => Some(
+                tls::acceptor(tls, relay.shutdown.clone())
+                    .context("unable to load the TLS certificate")?,
+            ),
+            None => None,
+        };
+
+        // Bind the listener.
+        let listener = TcpListener::bind(config.address)
+            .await
+            .context("failed to bind")?;
+        let local_addr = listener.local_addr()?;
+        info!(%local_addr, tls = acceptor.is_some(), "listening");
+
+        // Serve the connections until the server is shut down.
+        let state = Arc::clone(&relay);
+        let mut stop = relay.shutdown.clone();
+        let mut app = Router::new()
+            .route(
+                "/",
+                get(
+                    |ws: WebSocketUpgrade,
+                     ConnectInfo(address): ConnectInfo<SocketAddr>,
+                     State(relay): State<Arc<Relay>>| async move {
+                        let address = address.ip();
+                        let span = info_span!(
+                            "session",
+                            %address,
+                            session_id = field::Empty,
+                            client_id = field::Empty,
+                        );
+                        // Reject the frames larger than the maximum frame
+                        // size before buffering them.
+                        let max_size = relay.limits.max_frame_size();
+                        ws.max_message_size(max_size)
+                            .max_frame_size(max_size)
+                            .on_upgrade(move |socket| async move {
+                                handle(relay, socket, address).instrument(span).await.ok();
+                            })
+                    },
+                ),
+            )
+            .route("/healthz", get(healthz))
+            .route("/metrics", get(metrics));
+        if relay.admin_token.is_some() {
+            app = app.nest("/admin", admin::router());
+        }
+        if relay.cluster.is_enabled() {
+            app = app.route("/node", get(cluster::accept));
+            info!(peers = config.peers.len(), "clustering enabled");
+        }
+        cluster::connect_peers(&relay);
+        let app = app.with_state(relay);
+        let task = match acceptor {
+            Some(acceptor) => tokio::spawn(tls::serve(listener, acceptor, app, stop)),
+            None => tokio::spawn(async move {
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(async move {
+                    stop.wait_for(|&stop| stop).await.ok();
+                })
+                .await
+                .context("failed to serve")
+            }),
+        };
+
+        Ok(Self {
+            local_addr,
+            shutdown,
+            task,
+            relay: state,
+        })
+    }
+
+    /// Returns the address the server is listening on.
+    pub const fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Wait until the server stops.
+    ///
+    /// Once the server stops accepting connections, the sessions are closed
+    /// after sending their queued frames and the database is flushed.
+    pub async fn wait(mut self) -> anyhow::Result<()> {
+        let result = (&mut self.task).await;
+        self.stop(result).await
+    }
+
+    /// Serve connections until the given signal completes, and then shut
+    /// down the server.
+    pub async fn run_until(mut self, signal: impl Future<Output = ()>) -> anyhow::Result<()> {
+        tokio::select! {
+            result = &mut self.task => self.stop(result).await,
+            () = signal => {
+                info!("shutting down");
+                self.shutdown().await
+            }
+        }
+    }
+
+    /// Close the sessions and flush the database once the task serving the
+    /// connections is done, and returns its result.
+    async fn stop(self, result: Result<anyhow::Result<()>, JoinError>) -> anyhow::Result<()> {
+        // Close the sessions and wait until they are done.
+        self.shutdown.send_replace(true);
+        let drained = timeout(DRAIN_TIMEOUT, self.relay.limits.wait_idle()).await;
+        if drained.is_err() {
+            warn!("some sessions did not close in time");
+        }
+
+        // Save the database.
+        self.relay
+            .db
+            .flush_async()
+            .await
+            .context("failed to flush the database")?;
+        info!("stopped");
+        result?
+    }
+
+    /// Shut down the server, disconnecting all the clients, and wait until
+    /// it stops.
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        self.shutdown.send_replace(true);
+        self.wait().await
+    }
+}
+
+/// Report whether the relay server is healthy.
+async fn healthz(State(relay): State<Arc<Relay>>) -> (StatusCode, &'static str) {
+    let stopping = *relay.shutdown.borrow();
+    match stopping {
+        true => (StatusCode::SERVICE_UNAVAILABLE, "shutting down"),
+        false => (StatusCode::OK, "ok"),
+    }
+}
+
+/// Export the metrics of the relay server.
+async fn metrics(State(relay): State<Arc<Relay>>) -> impl IntoResponse {
+    let content_type = "text/plain; version=0.0.4; charset=utf-8";
+    (
+        [(header::CONTENT_TYPE, content_type)],
+        relay.render_metrics(),
+    )
+}
+
+/// Create a new client and add it to the database.
+fn create_client(tx: &TransactionalTree) -> ConflictableTransactionResult<(Uuid, Uuid), io::Error> {
+    // Generates a new identifier for the client.
+    let client_id = loop {
+        // Generates a new random identifier.
+        let id = Uuid::new_v4();
+
+        // Check if the id isn't already in the database.
+        if tx.get(id.as_bytes())?.is_none() {
+            break id;
+        }
+    };
+
+    // Generate a random secret for the client.
+    let secret = Uuid::new_v4();
+
+    // Add the new client to the database.
+    tx.insert(client_id.as_bytes(), secrets::hash(secret))?;
+
+    // Returns the client identifier and his secret.
+    Ok((client_id, secret))
+}
+
+/// Replace the secret of a client by a new one and send it to the client.
+///
+/// The new secret is only stored once there is room for it in the channel of
+/// the client, without waiting, so the old secret stays valid if the client
+/// is too slow to receive the new one.
+async fn rotate_secret(relay: &Relay, client_id: Uuid) -> anyhow::Result<()> {
+    // Reserve room in the channel of the client first.
+    let Some(sender) = relay
+        .clients
+        .get(&client_id)
+        .map(|client| client.sender.clone())
+    else {
+        return Ok(());
+    };
+    let permit = match sender.try_reserve() {
+        Ok(permit) => permit,
+        Err(TrySendError::Full(())) => {
+            warn!("secret not rotated: the client is too slow");
+            return Ok(());
+        }
+        Err(TrySendError::Closed(())) => return Ok(()),
+    };
+
+    // Store the new secret before sending it.
+    let secret = Uuid::new_v4();
+    relay
+        .db
+        .insert(client_id.as_bytes(), secrets::hash(secret))?;
+    relay.db.flush_async().await?;
+    relay.share_secret(client_id)?;
+    permit.send(Frame::Control(Notification::SecretRotated(secret)));
+    info!("secret rotated");
+    Ok(())
+}
+
+/// The parameters of a client session negotiated during the handshake.
+#[derive(Debug, Clone, Copy)]
+struct Session {
+    /// The identifier of the client.
+    client_id: Uuid,
+
+    /// The protocol version used by the client.
+    version: u16,
+
+    /// The capabilities enabled for the client.
+    capabilities: Capabilities,
+}
+
+/// Returns the websocket message closing a session for the given reason.
+fn close_message(reason: CloseReason) -> Message {
+    Message::Close(Some(CloseFrame {
+        code: reason.code(),
+        reason: reason.to_string().into(),
+    }))
+}
+
+/// Check the credentials of a client, or register a new client if there are
+/// none, and returns the new credentials if any.
+async fn authenticate(
+    relay: &Relay,
+    address: IpAddr,
+    credentials: Option<Credentials>,
+) -> anyhow::Result<(Uuid, Option<Credentials>)> {
+    // If there are no credentials it means that the client want a new identifier
+    // and secret, so we create them.
+    let Some((client_id, secret)) = credentials else {
+        if !relay.limits.register(address) {
+            return Err(CloseReason::TooManyRegistrations.into());
+        }
+        let (client_id, secret) = relay.db.transaction(create_client)?;
+        relay.db.flush_async().await?;
+        relay.last_seen.touch(client_id)?;
+        relay.share_secret(client_id)?;
+        Metrics::increment(&relay.metrics.registrations);
+        info!(%client_id, "client registered");
+        return Ok((client_id, Some((client_id, secret))));
+    };
+
+    // Otherwise it means that the client want to reuse an identifier, so we check
+    // with the database if the secret is correct and if it's not banned.
+    match relay.db.get(client_id.as_bytes())? {
+        Some(entry) if secrets::verify(&entry, secret) => (),
+        _ => bail!("invalid secret"),
+    }
+    if relay.bans.contains_key(client_id.as_bytes())? {
+        return Err(CloseReason::Banned.into());
+    }
+    if relay.duplicate_sessions == DuplicateSessionPolicy::Reject
+        && relay.clients.contains_key(&client_id)
+    {
+        return Err(CloseReason::AlreadyConnected.into());
+    }
+    relay.last_seen.touch(client_id)?;
+    Ok((client_id, None))
+}
+
+/// Handle the handshake of a client and returns its session.
+async fn handshake(
+    relay: &Relay,
+    socket: &mut WebSocket,
+    address: IpAddr,
+    data: &[u8],
+) -> anyhow::Result<Session> {
+    let Some(hello) = Hello::decode(data) else {
+        bail!("malformed message");
+    };
+    match hello {
+        // Legacy clients receive their new credentials when registering and
+        // nothing when authenticating.
+        Hello::Legacy(credentials) => {
+            let (client_id, new_credentials) = authenticate(relay, address, credentials).await?;
+            if let Some(new_credentials) = new_credentials {
+                let data = Hello::Legacy(Some(new_credentials)).encode();
+                socket.send(Message::Binary(data)).await?;
+            }
+            Ok(Session {
+                client_id,
+                version: LEGACY_VERSION,
+                capabilities: Capabilities::NONE,
+            })
+        }
+
+        // Versioned clients are always answered with a welcome.
+        Hello::Versioned {
+            version,
+            capabilities,
+            credentials,
+        } => {
+            let result = match version {
+                LEGACY_VERSION => Err(anyhow!("unsupported protocol version {version}")),
+                _ => authenticate(relay, address, credentials).await,
+            };
+            let (client_id, credentials) = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    let data = Welcome::Rejected(e.to_string()).encode();
+                    socket.send(Message::Binary(data)).await.ok();
+                    return Err(e);
+                }
+            };
+            let session = Session {
+                client_id,
+                version: version.min(PROTOCOL_VERSION),
+                capabilities: capabilities & relay.capabilities,
+            };
+            let welcome = Welcome::Accepted {
+                version: session.version,
+                capabilities: session.capabilities,
+                credentials,
+            };
+            socket.send(Message::Binary(welcome.encode())).await?;
+            Ok(session)
+        }
+    }
+}
+
+/// Handle the websocket connection.
+async fn handle(relay: Arc<Relay>, mut socket: WebSocket, address: IpAddr) -> anyhow::Result<()> {
+    // Refuse the connection if there are already too many of them.
+    let Some(_slot) = relay.limits.connect() else {
+        debug!("connection refused: too many connections");
+        let message = close_message(CloseReason::TooManyConnections);
+        socket.send(message).await.ok();
+        return Ok(());
+    };
+
+    // Receive the first request from the client.
+    let mut shutdown = relay.shutdown.clone();
+    let data = tokio::select! {
+        message = socket.recv() => match message {
+            Some(Ok(message)) => message.into_data(),
+            _ => return Ok(()),
+        },
+        () = async { shutdown.wait_for(|&stop| stop).await.ok(); } => {
+            socket.send(close_message(CloseReason::Restarting)).await.ok();
+            return Ok(());
+        }
+        () = sleep(relay.ping_timeout) => bail!("authentication timed out"),
+    };
+
+    // Open the session of the client.
+    let session = match handshake(&relay, &mut socket, address, &data).await {
+        Ok(session) => session,
+        Err(e) => {
+            debug!(error = %e, "handshake failed");
+            if let Some(&reason) = e.downcast_ref::<CloseReason>() {
+                socket.send(close_message(reason)).await.ok();
+            }
+            return Err(e);
+        }
+    };
+    let client_id = session.client_id;
+    let session_id = relay.next_session.fetch_add(1, Ordering::Relaxed);
+    Span::current()
+        .record("session_id", session_id)
+        .record("client_id", field::display(client_id));
+
+    // Add the client, unless another session already uses its identity.
+    let started_at = cluster::timestamp();
+    let (sender, receiver) = channel(relay.channel_capacity);
+    let closer = Arc::new(watch::channel(None).0);
+    let client = Client {
+        session_id,
+        session,
+        address,
+        connected_at: Instant::now(),
+        started_at,
+        sender,
+        closer: Arc::clone(&closer),
+        dropped: AtomicU64::new(0),
+    };
+    if !relay.connect(client_id, client) {
+        debug!("session refused: already connected");
+        socket
+            .send(close_message(CloseReason::AlreadyConnected))
+            .await
+            .ok();
+        return Ok(());
+    }
+
+    // Handle the client connection.
+    info!(version = session.version, "client connected");
+    let stored = relay.pending_stored(client_id);
+    relay.announce(client_id, true);
+    relay.cluster.announce(client_id, Some(started_at));
+    let (writer, reader) = socket.split();
+    let writing = tokio::spawn(write_client(
+        Arc::clone(&relay),
+        writer,
+        session.version,
+        receiver,
+        stored,
+        closer.subscribe(),
+    ));
+    read_client(&relay, reader, session, &closer).await.ok();
+
+    // Wait until the frames queued for the client are sent. The entry of the
+    // client is only removed if it's not owned by a newer session: the
+    // subscriptions and rooms of a superseded session were already cleared
+    // when the newer one connected, and the identity stays online.
+    let removed = relay
+        .clients
+        .remove_if(&client_id, |_, client| client.session_id == session_id)
+        .map(|(_, client)| client.dropped.into_inner());
+    writing.await.ok();
+    if let Some(dropped) = removed {
+        if dropped > 0 {
+            info!(dropped, "frames dropped because the client was too slow");
+        }
+        relay.unsubscribe_all(client_id);
+        relay.leave_all_rooms(client_id);
+        relay.cluster.announce(client_id, None);
+        if !relay.cluster.is_remote(client_id) {
+            relay.announce(client_id, false);
+        }
+    }
+    if let Err(e) = relay.last_seen.touch(client_id) {
+        warn!(error = %e, "failed to record the last seen time");
+    }
+    info!("client disconnected");
+
+    // Returns success.
+    Ok(())
+}
+
+/// Send the frames and pings to the client, until the channel is closed or
+/// a reason to close the session is received.
+///
+/// The stored frames are sent before the ones received from the channel, and
+/// removed from the store once written, so the ones that couldn't be written
+/// are kept for the next session. The frames still in the channel are sent
+/// before closing the session.
+async fn write_client(
+    relay: Arc<Relay>,
+    mut writer: SplitSink<WebSocket, Message>,
+    version: u16,
+    mut receiver: Receiver<Frame<Notification>>,
+    stored: Vec<(IVec, Frame<Notification>)>,
+    mut closing: watch::Receiver<Option<CloseReason>>,
+) -> Result<(), axum::Error> {
+    for (key, frame) in stored {
+        writer.send(Message::Binary(frame.encode(version))).await?;
+        if let Err(e) = relay.store.remove(&key) {
+            warn!(error = %e, "failed to remove a delivered durable message");
+        }
+    }
+    let ping_interval = relay.ping_interval;
+    let mut pings = interval_at(Instant::now() + ping_interval, ping_interval);
+    pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        // The reason is checked first, as closing the session also closes the
+        // channel.
+        let message = tokio::select! {
+            biased;
+            reason = async {
+                closing.wait_for(Option::is_some).await.ok().and_then(|reason| *reason)
+            } => match reason {
+                Some(reason) => {
+                    while let Ok(frame) = receiver.try_recv() {
+                        writer.send(Message::Binary(frame.encode(version))).await?;
+                    }
+                    return writer.send(close_message(reason)).await;
+                }
+                None => break,
+            },
+            frame = receiver.recv() => match frame {
+                Some(frame) => Message::Binary(frame.encode(version)),
+                None => break,
+            },
+            _ = pings.tick() => Message::Ping(Vec::new()),
+        };
+        writer.send(message).await?;
+    }
+    writer.close().await
+}
+
+/// Returns true if a websocket error is caused by a frame or a message
+/// larger than the maximum frame size, which is rejected before being read.
+fn is_too_large(error: &axum::Error) -> bool {
+    error
+        .source()
+        .and_then(|e| e.downcast_ref::<tungstenite::Error>())
+        .is_some_and(|e| matches!(e, tungstenite::Error::Capacity(_)))
+}
+
+/// Handle the frames received from the client, until the session is closed.
+async fn read_client(
+    relay: &Relay,
+    mut reader: SplitStream<WebSocket>,
+    session: Session,
+    closer: &watch::Sender<Option<CloseReason>>,
+) -> anyhow::Result<()> {
+    // Handle messages from the client until the server shuts down or the
+    // client stops answering.
+    let client_id = session.client_id;
+    let mut shutdown = relay.shutdown.clone();
+    let mut rate_limiter = relay.limits.rate_limiter();
+    let mut closing = closer.subscribe();
+    loop {
+        let message = tokio::select! {
+            message = reader.next() => message,
+            _ = shutdown.wait_for(|&stop| stop) => {
+                closer.send_replace(Some(CloseReason::Restarting));
+                break;
+            }
+            _ = closing.wait_for(Option::is_some) => break,
+            () = sleep(relay.ping_timeout) => {
+                info!("client timed out");
+                break;
+            }
+        };
+        let data = match message {
+            Some(Ok(Message::Binary(data))) => data,
+            Some(Ok(Message::Text(text))) => text.into_bytes(),
+            Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+            Some(Err(e)) if is_too_large(&e) => {
+                let reason = CloseReason::FrameTooLarge;
+                info!(%reason, "session closed");
+                closer.send_replace(Some(reason));
+                break;
+            }
+            Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+        };
+
+        // Close the session if the client exceeds its rate limits.
+        if !rate_limiter.allow(data.len()) {
+            let reason = CloseReason::RateLimited;
+            info!(%reason, "session closed");
+            closer.send_replace(Some(reason));
+            break;
+        }
+
+        // Decode the frame and handle the control requests addressed to the
+        // relay server.
+        let (target_id, flags, payload) = match Frame::decode(data, session.version) {
+            Ok(Frame::Data {
+                peer,
+                flags,
+                payload,
+            }) => (peer, flags, payload),
+            Ok(Frame::Control(Request::RotateSecret)) => {
+                rotate_secret(relay, client_id).await?;
+                continue;
+            }
+            Ok(Frame::Control(request)) => {
+                relay.control(client_id, session.capabilities, request);
+                continue;
+            }
+            Err(DecodeError::Unknown) => {
+                debug!("unknown frame received");
+                continue;
+            }
+            Err(DecodeError::Malformed) => bail!("malformed message"),
+        };
+
+        // Split the identifier of an acknowledged message from its payload,
+        // for the targets that can't acknowledge it themselves.
+        let message_id = match flags.contains(Flags::ACKNOWLEDGED)
+            && session
+                .capabilities
+                .contains(Capabilities::ACKNOWLEDGEMENTS)
+        {
+            true => match MessageId::split(&payload) {
+                Some((message_id, _)) => Some(message_id),
+                None => bail!("malformed message"),
+            },
+            false => None,
+        };
+
+        // Send the message to the target client if it's connected, and
+        // acknowledge it on its behalf if it can't.
+        let target_capabilities = relay
+            .clients
+            .get(&target_id)
+            .map(|target| target.session.capabilities);
+        if let Some(capabilities) = target_capabilities {
+            relay.deliver_message(
+                client_id,
+                target_id,
+                capabilities,
+                flags,
+                payload,
+                message_id,
+            );
+            continue;
+        }
+
+        // Forward the message to the node hosting the target, with only the
+        // flags this node would have honored.
+        let durable =
+            flags.contains(Flags::DURABLE) && session.capabilities.contains(Capabilities::DURABLE);
+        if relay.cluster.is_remote(target_id) {
+            let mut forwarded = flags;
+            if message_id.is_none() {
+                forwarded = forwarded.difference(Flags::ACKNOWLEDGED);
+            }
+            if !durable {
+                forwarded = forwarded.difference(Flags::DURABLE);
+            }
+            match relay
+                .cluster
+                .forward(client_id, target_id, forwarded, payload)
+            {
+                true => Metrics::increment(&relay.metrics.forwarded),
+                false => Metrics::increment(&relay.metrics.dropped),
+            }
+            continue;
+        }
+
+        // Store the message if it's durable and the target is not connected,
+        // and acknowledge it once stored.
+        if durable {
+            match relay.store_message(client_id, target_id, flags, &payload, message_id) {
+                Ok(Storage::Stored) => continue,
+                Ok(Storage::Full) => {
+                    Metrics::increment(&relay.metrics.undeliverable);
+                    relay.notify(client_id, Notification::StoreFull(target_id));
+                    continue;
+                }
+                Ok(Storage::Unregistered) => (),
+                Err(e) => {
+                    warn!(target = %target_id, error = %e, "failed to store a durable message");
+                    continue;
+                }
+            }
+        }
+
+        // Otherwise bounce it.
+        Metrics::increment(&relay.metrics.undeliverable);
+        if session.capabilities.contains(Capabilities::BOUNCES) {
+            relay.notify(client_id, Notification::TargetUnknown(target_id));
+        }
+    }
+
+    // Returns success.
+    Ok(())
+}
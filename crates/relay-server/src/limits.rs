@@ -0,0 +1,298 @@
+//! The limits protecting the relay server from abusive clients.
+
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tokio::sync::Notify;
+
+use crate::Config;
+
+/// The window in which the registrations of an address are counted.
+const REGISTRATION_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// The limits of a relay server and the state needed to enforce them.
+pub struct Limits {
+    /// The maximum size of a frame sent by a client.
+    max_frame_size: usize,
+
+    /// The maximum number of frames a client can send per second.
+    max_messages_per_second: u32,
+
+    /// The maximum number of bytes a client can send per second.
+    max_bytes_per_second: u32,
+
+    /// The maximum number of identities registered from an address per hour.
+    max_registrations_per_hour: u32,
+
+    /// The maximum number of concurrent connections.
+    max_connections: usize,
+
+    /// The number of open connections.
+    connections: AtomicUsize,
+
+    /// Notified when the last open connection is closed.
+    idle: Notify,
+
+    /// The time of the recent registrations of each address.
+    registrations: DashMap<IpAddr, VecDeque<Instant>>,
+}
+
+impl Limits {
+    /// Create the limits from the configuration of the server.
+    pub fn new(config: &Config) -> Self {
+        Self {
+            max_frame_size: config.max_frame_size,
+            max_messages_per_second: config.max_messages_per_second,
+            max_bytes_per_second: config.max_bytes_per_second,
+            max_registrations_per_hour: config.max_registrations_per_hour,
+            max_connections: config.max_connections,
+            connections: AtomicUsize::new(0),
+            idle: Notify::new(),
+            registrations: DashMap::new(),
+        }
+    }
+
+    /// Returns the maximum size of a frame sent by a client.
+    pub const fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// Reserve a connection, or returns [None] if there are already too
+    /// many of them.
+    ///
+    /// The connection is released when the returned [ConnectionSlot] is
+    /// dropped.
+    pub fn connect(&self) -> Option<ConnectionSlot<'_>> {
+        self.connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |connections| {
+                (connections < self.max_connections).then_some(connections + 1)
+            })
+            .ok()
+            .map(|_| ConnectionSlot(self))
+    }
+
+    /// Wait until all the connections are closed.
+    pub async fn wait_idle(&self) {
+        loop {
+            let idle = self.idle.notified();
+            tokio::pin!(idle);
+            idle.as_mut().enable();
+            if self.connections.load(Ordering::Acquire) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    /// Record a registration from an address, or returns false if too many
+    /// identities were already registered from it.
+    pub fn register(&self, address: IpAddr) -> bool {
+        self.register_at(address, Instant::now())
+    }
+
+    /// Record a registration from an address at the given time, or returns
+    /// false if too many identities were already registered from it.
+    fn register_at(&self, address: IpAddr, now: Instant) -> bool {
+        let mut registrations = self.registrations.entry(address).or_default();
+        while registrations
+            .front()
+            .is_some_and(|time| now.duration_since(*time) >= REGISTRATION_WINDOW)
+        {
+            registrations.pop_front();
+        }
+        if registrations.len() >= self.max_registrations_per_hour as usize {
+            return false;
+        }
+        registrations.push_back(now);
+        true
+    }
+
+    /// Forget the addresses without recent registrations.
+    pub fn prune(&self) {
+        self.registrations.retain(|_, registrations| {
+            registrations
+                .back()
+                .is_some_and(|time| time.elapsed() < REGISTRATION_WINDOW)
+        });
+    }
+
+    /// Create a new [RateLimiter] for a client session.
+    pub fn rate_limiter(&self) -> RateLimiter {
+        RateLimiter {
+            messages: TokenBucket::new(self.max_messages_per_second),
+            bytes: TokenBucket::new(self.max_bytes_per_second),
+        }
+    }
+}
+
+/// A connection reserved with [Limits::connect].
+pub struct ConnectionSlot<'a>(&'a Limits);
+
+impl Drop for ConnectionSlot<'_> {
+    fn drop(&mut self) {
+        if self.0.connections.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// A token bucket refilled continuously, holding at most one second worth
+/// of tokens.
+struct TokenBucket {
+    /// The number of tokens added per second.
+    rate: f64,
+
+    /// The number of available tokens.
+    tokens: f64,
+
+    /// The time the tokens were last refilled.
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Create a new full [TokenBucket] with the given rate.
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: f64::from(rate),
+            tokens: f64::from(rate),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Take some tokens from the bucket at the given time, or returns false
+    /// if there are not enough of them.
+    fn take(&mut self, amount: f64, now: Instant) -> bool {
+        // Refill the bucket.
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = elapsed.mul_add(self.rate, self.tokens).min(self.rate);
+        self.refilled_at = now;
+
+        // Take the tokens if there are enough of them.
+        if self.tokens < amount {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+}
+
+/// Limits the number of messages and bytes per second sent by a client.
+pub struct RateLimiter {
+    /// The bucket of messages.
+    messages: TokenBucket,
+
+    /// The bucket of bytes.
+    bytes: TokenBucket,
+}
+
+impl RateLimiter {
+    /// Record a frame of the given size, or returns false if the client
+    /// exceeded its rate limits.
+    pub fn allow(&mut self, size: usize) -> bool {
+        let now = Instant::now();
+        self.messages.take(1.0, now) && self.bytes.take(size as f64, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the limits.
+
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// Returns limits allowing the given number of registrations per hour.
+    fn limits(max_registrations_per_hour: u32) -> Limits {
+        Limits::new(&Config {
+            max_registrations_per_hour,
+            ..Config::local()
+        })
+    }
+
+    /// A full bucket allows a burst of one second worth of tokens.
+    #[test]
+    fn bucket_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10);
+        for _ in 0..10 {
+            assert!(bucket.take(1.0, now));
+        }
+        assert!(!bucket.take(1.0, now));
+    }
+
+    /// An empty bucket is refilled continuously at its rate.
+    #[test]
+    fn bucket_refill() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10);
+        assert!(bucket.take(10.0, now));
+        assert!(!bucket.take(1.0, now + Duration::from_millis(50)));
+        assert!(bucket.take(1.0, now + Duration::from_millis(150)));
+        assert!(!bucket.take(1.0, now + Duration::from_millis(160)));
+    }
+
+    /// A bucket never holds more than one second worth of tokens.
+    #[test]
+    fn bucket_capacity() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10);
+        assert!(!bucket.take(11.0, now + Duration::from_secs(60)));
+        assert!(bucket.take(10.0, now + Duration::from_secs(60)));
+    }
+
+    /// The rate limiter counts both the messages and the bytes.
+    #[test]
+    fn rate_limiter() {
+        let limits = Limits::new(&Config {
+            max_messages_per_second: 3,
+            max_bytes_per_second: 100,
+            ..Config::local()
+        });
+        let mut rate_limiter = limits.rate_limiter();
+        assert!(rate_limiter.allow(10));
+        assert!(rate_limiter.allow(10));
+        assert!(rate_limiter.allow(10));
+        assert!(!rate_limiter.allow(10));
+
+        let mut rate_limiter = limits.rate_limiter();
+        assert!(rate_limiter.allow(80));
+        assert!(!rate_limiter.allow(80));
+    }
+
+    /// The registrations are counted per address over an hour.
+    #[test]
+    fn registration_window() {
+        let limits = limits(2);
+        let first = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let second = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let now = Instant::now();
+        assert!(limits.register_at(first, now));
+        assert!(limits.register_at(first, now + Duration::from_secs(60)));
+        assert!(!limits.register_at(first, now + Duration::from_secs(120)));
+
+        // Another address has its own window.
+        assert!(limits.register_at(second, now + Duration::from_secs(120)));
+
+        // The first registration leaves the window after an hour.
+        assert!(limits.register_at(first, now + REGISTRATION_WINDOW));
+        assert!(!limits.register_at(first, now + REGISTRATION_WINDOW));
+    }
+
+    /// The connections are released when their slot is dropped.
+    #[test]
+    fn connection_slots() {
+        let limits = Limits::new(&Config {
+            max_connections: 1,
+            ..Config::local()
+        });
+        let slot = limits.connect();
+        assert!(slot.is_some());
+        assert!(limits.connect().is_none());
+        drop(slot);
+        assert!(limits.connect().is_some());
+    }
+}
@@ -0,0 +1,358 @@
+//! The maintenance operations on the database of the relay server.
+//!
+//! They are run from the command line while the server is stopped, since the
+//! database can't be opened by two processes at the same time.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use sled::{Batch, Db};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::config::Maintenance;
+use crate::last_seen::LastSeen;
+use crate::store::{self, MessageStore};
+use crate::{secrets, Config};
+
+/// An identity, as exported by the maintenance commands.
+#[derive(Serialize, Deserialize)]
+struct Identity {
+    /// The identifier of the client.
+    id: Uuid,
+
+    /// The stored secret of the client, in hexadecimal.
+    secret: String,
+
+    /// The last time the identity was used, in seconds since the unix epoch.
+    #[serde(default)]
+    last_seen: Option<u64>,
+
+    /// Whether the identity is banned.
+    #[serde(default)]
+    banned: bool,
+}
+
+/// Run a maintenance operation on the database of the configuration.
+pub fn run(maintenance: &Maintenance, config: &Config) -> anyhow::Result<()> {
+    let Some(path) = &config.database else {
+        bail!("there is no database to maintain");
+    };
+    match maintenance {
+        Maintenance::Compact => compact(path),
+        Maintenance::Export { output } => {
+            let db = open(path)?;
+            match output {
+                Some(output) => {
+                    let file = File::create(output)
+                        .with_context(|| format!("unable to create {}", output.display()))?;
+                    export(&db, BufWriter::new(file))
+                }
+                None => export(&db, io::stdout().lock()),
+            }
+        }
+        Maintenance::Import { input } => {
+            let db = open(path)?;
+            match input {
+                Some(input) => {
+                    let file = File::open(input)
+                        .with_context(|| format!("unable to open {}", input.display()))?;
+                    import(&db, BufReader::new(file))
+                }
+                None => import(&db, io::stdin().lock()),
+            }
+        }
+        Maintenance::Prune { days } => prune(&open(path)?, config, *days),
+    }
+}
+
+/// Open the database at the given path.
+fn open(path: &Path) -> anyhow::Result<Db> {
+    sled::open(path).with_context(|| format!("unable to open the database {}", path.display()))
+}
+
+/// Returns the bytes encoded in hexadecimal.
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Returns the bytes of an hexadecimal string, or [None] if it's invalid.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect()
+}
+
+/// Rewrite the database to reclaim the space left by removed entries.
+fn compact(path: &Path) -> anyhow::Result<()> {
+    let compacted = path.with_extension("compacting");
+    let previous = path.with_extension("previous");
+    let db = open(path)?;
+    let before = db.size_on_disk()?;
+
+    // Copy every tree into a new database.
+    if compacted.exists() {
+        fs::remove_dir_all(&compacted)
+            .with_context(|| format!("unable to remove {}", compacted.display()))?;
+    }
+    let copy = open(&compacted)?;
+    for name in db.tree_names() {
+        let tree = db.open_tree(&name)?;
+        let mut batch = Batch::default();
+        for entry in tree.iter() {
+            let (key, value) = entry?;
+
+            // The sequence numbers of the durable messages are generated
+            // again, because the new database starts counting from zero.
+            if name == store::TREE && key.len() == 24 {
+                let mut renumbered = key[..16].to_vec();
+                renumbered.extend_from_slice(&copy.generate_id()?.to_be_bytes());
+                batch.insert(renumbered, value);
+            } else {
+                batch.insert(key, value);
+            }
+        }
+        copy.open_tree(&name)?.apply_batch(batch)?;
+    }
+    copy.flush()?;
+    let after = copy.size_on_disk()?;
+    drop(copy);
+    drop(db);
+
+    // Replace the database with the copy.
+    fs::rename(path, &previous).with_context(|| format!("unable to move {}", path.display()))?;
+    fs::rename(&compacted, path)
+        .with_context(|| format!("unable to move {}", compacted.display()))?;
+    fs::remove_dir_all(&previous)
+        .with_context(|| format!("unable to remove {}", previous.display()))?;
+    info!(before, after, "database compacted");
+    Ok(())
+}
+
+/// Write the identities of the database as JSON lines.
+fn export(db: &Db, mut output: impl Write) -> anyhow::Result<()> {
+    let last_seen = LastSeen::open(db)?;
+    let bans = db.open_tree("bans")?;
+    let mut exported = 0;
+    for entry in db.iter() {
+        let (key, secret) = entry?;
+        let Ok(id) = Uuid::from_slice(&key) else {
+            continue;
+        };
+        let identity = Identity {
+            id,
+            secret: encode_hex(&secret),
+            last_seen: last_seen.get(id)?,
+            banned: bans.contains_key(key)?,
+        };
+        serde_json::to_writer(&mut output, &identity)?;
+        writeln!(output)?;
+        exported += 1;
+    }
+    output.flush()?;
+    info!(exported, "identities exported");
+    Ok(())
+}
+
+/// Read identities written as JSON lines into the database.
+fn import(db: &Db, input: impl BufRead) -> anyhow::Result<()> {
+    let last_seen = LastSeen::open(db)?;
+    let bans = db.open_tree("bans")?;
+    let mut imported = 0;
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let identity: Identity = serde_json::from_str(&line)
+            .with_context(|| format!("invalid identity on line {}", number + 1))?;
+        let Some(secret) = decode_hex(&identity.secret).filter(|secret| secrets::is_valid(secret))
+        else {
+            bail!("invalid secret on line {}", number + 1);
+        };
+        db.insert(identity.id.as_bytes(), secret)?;
+        match identity.last_seen {
+            Some(timestamp) => last_seen.set(identity.id, timestamp)?,
+            None => last_seen.touch(identity.id)?,
+        }
+        match identity.banned {
+            true => bans.insert(identity.id.as_bytes(), &[])?,
+            false => bans.remove(identity.id.as_bytes())?,
+        };
+        imported += 1;
+    }
+    db.flush()?;
+    info!(imported, "identities imported");
+    Ok(())
+}
+
+/// Remove the identities unused for the given number of days, with their
+/// bans and durable messages.
+fn prune(db: &Db, config: &Config, days: u64) -> anyhow::Result<()> {
+    let last_seen = LastSeen::open(db)?;
+    let bans = db.open_tree("bans")?;
+    let messages = MessageStore::open(
+        db,
+        config.durable_ttl,
+        config.durable_capacity,
+        config.durable_total_capacity,
+    )?;
+    let cutoff = store::now().saturating_sub(days.saturating_mul(24 * 60 * 60));
+    let mut pruned = 0;
+    for id in last_seen.before(cutoff)? {
+        if db.remove(id.as_bytes())?.is_some() {
+            pruned += 1;
+        }
+        bans.remove(id.as_bytes())?;
+        messages.take(id)?;
+        last_seen.remove(id)?;
+    }
+    db.flush()?;
+    info!(pruned, "unused identities pruned");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use relay_protocol::Flags;
+
+    use super::*;
+
+    /// Returns a temporary database.
+    fn temporary() -> sled::Result<Db> {
+        sled::Config::new().temporary(true).open()
+    }
+
+    /// Add an identity with a new secret to the database, last used at the
+    /// given time, and returns its identifier.
+    fn add_identity(db: &Db, last_seen: u64) -> anyhow::Result<Uuid> {
+        let id = Uuid::new_v4();
+        db.insert(id.as_bytes(), secrets::hash(Uuid::new_v4()))?;
+        LastSeen::open(db)?.set(id, last_seen)?;
+        Ok(id)
+    }
+
+    /// The exported identities are imported with their secret, last use and
+    /// ban.
+    #[test]
+    fn export_import() -> anyhow::Result<()> {
+        let source = temporary()?;
+        let now = store::now();
+        let kept = add_identity(&source, now)?;
+        let banned = add_identity(&source, now - 60)?;
+        source.open_tree("bans")?.insert(banned.as_bytes(), &[])?;
+        let mut exported = Vec::new();
+        export(&source, &mut exported)?;
+
+        // The imported database has the same identities.
+        let target = temporary()?;
+        import(&target, exported.as_slice())?;
+        let last_seen = LastSeen::open(&target)?;
+        let bans = target.open_tree("bans")?;
+        for (id, seen, is_banned) in [(kept, now, false), (banned, now - 60, true)] {
+            assert_eq!(target.get(id.as_bytes())?, source.get(id.as_bytes())?);
+            assert_eq!(last_seen.get(id)?, Some(seen));
+            assert_eq!(bans.contains_key(id.as_bytes())?, is_banned);
+        }
+        assert_eq!(target.len(), 2);
+        Ok(())
+    }
+
+    /// The identities with a malformed secret aren't imported.
+    #[test]
+    fn import_invalid_secret() -> anyhow::Result<()> {
+        let db = temporary()?;
+        let id = Uuid::new_v4();
+        for secret in ["zz", "abc", "abcd", &encode_hex(&[0; 40])] {
+            let line = format!(r#"{{"id":"{id}","secret":"{secret}"}}"#);
+            assert!(import(&db, line.as_bytes()).is_err(), "{secret}");
+        }
+        assert!(db.is_empty());
+
+        // A secret stored in plain text is still imported.
+        let line = format!(r#"{{"id":"{id}","secret":"{}"}}"#, encode_hex(&[0; 16]));
+        import(&db, line.as_bytes())?;
+        assert!(db.contains_key(id.as_bytes())?);
+        Ok(())
+    }
+
+    /// The unused identities are removed with their bans and durable
+    /// messages, and the others are kept.
+    #[test]
+    fn prune_unused() -> anyhow::Result<()> {
+        let db = temporary()?;
+        let config = Config::local();
+        let now = store::now();
+        let recent = add_identity(&db, now - 24 * 60 * 60)?;
+        let unused = add_identity(&db, now - 40 * 24 * 60 * 60)?;
+        db.open_tree("bans")?.insert(unused.as_bytes(), &[])?;
+        let messages = MessageStore::open(
+            &db,
+            config.durable_ttl,
+            config.durable_capacity,
+            config.durable_total_capacity,
+        )?;
+        messages.push(unused, recent, Flags::NONE, b"message")?;
+        prune(&db, &config, 30)?;
+
+        // Only the recent identity is left.
+        let last_seen = LastSeen::open(&db)?;
+        assert!(db.contains_key(recent.as_bytes())?);
+        assert_eq!(last_seen.get(recent)?, Some(now - 24 * 60 * 60));
+        assert!(!db.contains_key(unused.as_bytes())?);
+        assert_eq!(last_seen.get(unused)?, None);
+        assert!(db.open_tree("bans")?.is_empty());
+        assert!(messages.pending(unused)?.is_empty());
+        Ok(())
+    }
+
+    /// The compacted database keeps its identities and durable messages, in
+    /// order.
+    #[test]
+    fn compact_keeps_entries() -> anyhow::Result<()> {
+        let directory = tempfile::tempdir()?;
+        let path = directory.path().join("secrets.db");
+        let ttl = Duration::from_secs(60);
+        let (id, sender) = {
+            let db = open(&path)?;
+            let id = add_identity(&db, store::now())?;
+            let sender = Uuid::new_v4();
+            let messages = MessageStore::open(&db, ttl, 1024, 1024)?;
+            messages.push(id, sender, Flags::NONE, b"first")?;
+            messages.push(id, sender, Flags::NONE, b"second")?;
+            db.flush()?;
+            (id, sender)
+        };
+        compact(&path)?;
+
+        // Only the compacted database is left, with the same entries.
+        let names = fs::read_dir(directory.path())?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(names, ["secrets.db"]);
+        let db = open(&path)?;
+        assert!(db.contains_key(id.as_bytes())?);
+        let messages = MessageStore::open(&db, ttl, 1024, 1024)?.take(id)?;
+        let payloads: Vec<_> = messages
+            .iter()
+            .map(|message| (message.sender, message.payload.as_slice()))
+            .collect();
+        assert_eq!(
+            payloads,
+            [
+                (sender, b"first".as_slice()),
+                (sender, b"second".as_slice())
+            ]
+        );
+        Ok(())
+    }
+}
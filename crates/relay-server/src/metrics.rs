@@ -0,0 +1,121 @@
+//! The metrics of the relay server, exported in the Prometheus text format.
+
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::Relay;
+
+/// The counters updated while the relay server is running.
+#[derive(Default)]
+pub struct Metrics {
+    /// The number of registered identities.
+    pub registrations: AtomicU64,
+
+    /// The number of messages relayed to their target.
+    pub messages: AtomicU64,
+
+    /// The number of payload bytes relayed to their target.
+    pub bytes: AtomicU64,
+
+    /// The number of frames dropped because their target didn't receive them
+    /// fast enough.
+    pub dropped: AtomicU64,
+
+    /// The number of messages whose target was not connected.
+    pub undeliverable: AtomicU64,
+
+    /// The number of messages forwarded to the node hosting their target.
+    pub forwarded: AtomicU64,
+}
+
+impl Metrics {
+    /// Increment a counter by one.
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a message relayed to its target.
+    pub fn relayed(&self, size: usize) {
+        Self::increment(&self.messages);
+        self.bytes.fetch_add(size as u64, Ordering::Relaxed);
+    }
+}
+
+/// Append a metric to a Prometheus text exposition.
+fn write_metric(output: &mut String, name: &str, kind: &str, help: &str, value: impl Display) {
+    output.push_str(&format!(
+        "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+    ));
+}
+
+impl Relay {
+    /// Returns the metrics of the relay server in the Prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        let counter = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut output = String::new();
+        write_metric(
+            &mut output,
+            "relay_connected_clients",
+            "gauge",
+            "The number of connected clients.",
+            self.clients.len(),
+        );
+        write_metric(
+            &mut output,
+            "relay_registrations_total",
+            "counter",
+            "The number of registered identities.",
+            counter(&self.metrics.registrations),
+        );
+        write_metric(
+            &mut output,
+            "relay_messages_total",
+            "counter",
+            "The number of messages relayed to their target.",
+            counter(&self.metrics.messages),
+        );
+        write_metric(
+            &mut output,
+            "relay_bytes_total",
+            "counter",
+            "The number of payload bytes relayed to their target.",
+            counter(&self.metrics.bytes),
+        );
+        write_metric(
+            &mut output,
+            "relay_dropped_total",
+            "counter",
+            "The number of frames dropped because their target was too slow.",
+            counter(&self.metrics.dropped),
+        );
+        write_metric(
+            &mut output,
+            "relay_undeliverable_total",
+            "counter",
+            "The number of messages whose target was not connected.",
+            counter(&self.metrics.undeliverable),
+        );
+        write_metric(
+            &mut output,
+            "relay_forwarded_total",
+            "counter",
+            "The number of messages forwarded to the node hosting their target.",
+            counter(&self.metrics.forwarded),
+        );
+        write_metric(
+            &mut output,
+            "relay_node_links",
+            "gauge",
+            "The number of open links to other nodes.",
+            self.cluster.links(),
+        );
+        write_metric(
+            &mut output,
+            "relay_database_bytes",
+            "gauge",
+            "The size of the database on disk.",
+            self.db.size_on_disk().unwrap_or(0),
+        );
+        output
+    }
+}
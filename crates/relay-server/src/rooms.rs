@@ -0,0 +1,157 @@
+//! The rooms, used to broadcast messages to groups of clients.
+
+use dashmap::mapref::entry::Entry;
+use relay_protocol::{Flags, Frame, Notification, RoomError, RoomName};
+use uuid::Uuid;
+
+use crate::Relay;
+
+impl Relay {
+    /// Create a new room with the client as its only member.
+    pub fn create_room(&self, client_id: Uuid, room: RoomName) {
+        let created = match self.rooms.entry(room.clone()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert([client_id].into());
+                true
+            }
+        };
+        if !created {
+            let error = RoomError::AlreadyExists;
+            self.notify(client_id, Notification::RoomError { room, error });
+            return;
+        }
+        self.memberships
+            .entry(client_id)
+            .or_default()
+            .insert(room.clone());
+        self.notify(client_id, Notification::RoomJoined(room));
+    }
+
+    /// Add the client to a room, creating it if it doesn't exist.
+    pub fn join_room(&self, client_id: Uuid, room: RoomName) {
+        // Add the client to the room and get the other members.
+        let others: Vec<Uuid> = {
+            let mut members = self.rooms.entry(room.clone()).or_default();
+            if !members.insert(client_id) {
+                return;
+            }
+            members
+                .iter()
+                .copied()
+                .filter(|&member| member != client_id)
+                .collect()
+        };
+        self.memberships
+            .entry(client_id)
+            .or_default()
+            .insert(room.clone());
+
+        // Notify the client and the other members.
+        self.notify(client_id, Notification::RoomJoined(room.clone()));
+        for member in others {
+            let notification = Notification::MemberJoined {
+                room: room.clone(),
+                member: client_id,
+            };
+            self.notify(member, notification);
+        }
+    }
+
+    /// Remove the client from a room, removing the room if it's empty.
+    pub fn leave_room(&self, client_id: Uuid, room: RoomName) {
+        let Some(others) = self.remove_member(client_id, &room) else {
+            let error = RoomError::NotMember;
+            self.notify(client_id, Notification::RoomError { room, error });
+            return;
+        };
+        if let Some(mut rooms) = self.memberships.get_mut(&client_id) {
+            rooms.remove(&room);
+        }
+
+        // Notify the client and the other members.
+        self.notify(client_id, Notification::RoomLeft(room.clone()));
+        self.announce_left(client_id, &room, others);
+    }
+
+    /// Remove the client from the members of a room, removing the room if
+    /// it's empty, and returns the other members, or [None] if the client
+    /// isn't a member.
+    fn remove_member(&self, client_id: Uuid, room: &RoomName) -> Option<Vec<Uuid>> {
+        let mut others = None;
+        self.rooms.remove_if_mut(room, |_, members| {
+            if members.remove(&client_id) {
+                others = Some(members.iter().copied().collect());
+            }
+            members.is_empty()
+        });
+        others
+    }
+
+    /// Notify the other members of a room that the client left it.
+    fn announce_left(&self, client_id: Uuid, room: &RoomName, others: Vec<Uuid>) {
+        for member in others {
+            let notification = Notification::MemberLeft {
+                room: room.clone(),
+                member: client_id,
+            };
+            self.notify(member, notification);
+        }
+    }
+
+    /// Send the members of a room to the client.
+    pub fn list_room(&self, client_id: Uuid, room: RoomName) {
+        let members: Option<Vec<Uuid>> = self
+            .rooms
+            .get(&room)
+            .map(|members| members.iter().copied().collect());
+        let notification = match members {
+            Some(members) => Notification::RoomMembers { room, members },
+            None => Notification::RoomError {
+                room,
+                error: RoomError::NotFound,
+            },
+        };
+        self.notify(client_id, notification);
+    }
+
+    /// Send a message from the client to all the other members of a room.
+    pub fn send_to_room(&self, client_id: Uuid, room: RoomName, payload: Vec<u8>) {
+        // Get the members of the room.
+        let members: Option<Vec<Uuid>> = self
+            .rooms
+            .get(&room)
+            .filter(|members| members.contains(&client_id))
+            .map(|members| members.iter().copied().collect());
+        let Some(members) = members else {
+            let error = RoomError::NotMember;
+            self.notify(client_id, Notification::RoomError { room, error });
+            return;
+        };
+
+        // Send the message to each of the other members.
+        for member in members.into_iter().filter(|&member| member != client_id) {
+            let frame = Frame::Data {
+                peer: client_id,
+                flags: Flags::NONE,
+                payload: payload.clone(),
+            };
+            self.deliver(member, frame);
+        }
+    }
+
+    /// Remove the client from all its rooms when its session ends.
+    ///
+    /// Only the other members are notified, since the identity may already
+    /// be used by a newer session that never joined these rooms.
+    pub fn leave_all_rooms(&self, client_id: Uuid) {
+        let Some((_, rooms)) = self.memberships.remove(&client_id) else {
+            return;
+        };
+        for room in rooms {
+            if let Some(others) = self.remove_member(client_id, &room) {
+                self.announce_left(client_id, &room, others);
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! The storage of the client secrets as salted hashes.
+//!
+//! Each entry of the default tree of the database maps the identifier of a
+//! client to a random salt followed by the hash of the salt and the secret,
+//! so that a leaked database doesn't leak the credentials of the clients.
+
+use sled::Db;
+use uuid::Uuid;
+
+/// The length of the salt of a hashed secret.
+const SALT_LENGTH: usize = 16;
+
+/// The length of a hashed secret entry, salt included.
+const ENTRY_LENGTH: usize = SALT_LENGTH + blake3::OUT_LEN;
+
+/// Hash a secret with the given salt.
+fn hash_with_salt(salt: &[u8], secret: Uuid) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(salt);
+    hasher.update(secret.as_bytes());
+    hasher.finalize()
+}
+
+/// Returns the entry storing a secret, with a new random salt.
+pub fn hash(secret: Uuid) -> Vec<u8> {
+    let salt = *Uuid::new_v4().as_bytes();
+    let mut entry = Vec::with_capacity(ENTRY_LENGTH);
+    entry.extend_from_slice(&salt);
+    entry.extend_from_slice(hash_with_salt(&salt, secret).as_bytes());
+    entry
+}
+
+/// Returns true if the secret matches the stored entry.
+///
+/// The hashes are compared in constant time.
+pub fn verify(entry: &[u8], secret: Uuid) -> bool {
+    if entry.len() != ENTRY_LENGTH {
+        return false;
+    }
+    let (salt, expected) = entry.split_at(SALT_LENGTH);
+    let Ok(expected) = <[u8; blake3::OUT_LEN]>::try_from(expected) else {
+        return false;
+    };
+    hash_with_salt(salt, secret) == blake3::Hash::from(expected)
+}
+
+/// Returns true if an entry has the length of a hashed secret, or of a
+/// secret stored in plain text by previous versions of the relay server,
+/// which is hashed when the server starts.
+pub const fn is_valid(entry: &[u8]) -> bool {
+    entry.len() == ENTRY_LENGTH || entry.len() == 16
+}
+
+/// Convert the secrets stored in plain text by previous versions of the
+/// relay server into hashes, and returns the number of converted entries.
+pub fn migrate(db: &Db) -> sled::Result<usize> {
+    let mut migrated = 0;
+    for entry in db.iter() {
+        let (client_id, secret) = entry?;
+        let Ok(secret) = Uuid::from_slice(&secret) else {
+            continue;
+        };
+        let swap = db.compare_and_swap(&client_id, Some(secret.as_bytes()), Some(hash(secret)))?;
+        if swap.is_ok() {
+            migrated += 1;
+        }
+    }
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the storage of the secrets.
+
+    use super::*;
+
+    /// A hashed secret is verified, and doesn't contain the secret.
+    #[test]
+    fn hash_and_verify() {
+        let secret = Uuid::new_v4();
+        let entry = hash(secret);
+        assert_eq!(entry.len(), ENTRY_LENGTH);
+        assert!(verify(&entry, secret));
+        assert!(!entry.windows(16).any(|window| window == secret.as_bytes()));
+
+        // Each hash uses a new salt.
+        assert_ne!(hash(secret), entry);
+    }
+
+    /// A wrong secret or a malformed entry is rejected.
+    #[test]
+    fn wrong_secret() {
+        let secret = Uuid::new_v4();
+        let entry = hash(secret);
+        assert!(!verify(&entry, Uuid::new_v4()));
+        assert!(!verify(&entry[1..], secret));
+        assert!(!verify(secret.as_bytes(), secret));
+    }
+
+    /// The secrets stored in plain text are hashed, and the clients can
+    /// still authenticate with them.
+    #[test]
+    fn migrate_plain_secrets() -> sled::Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let legacy = (Uuid::new_v4(), Uuid::new_v4());
+        let hashed = (Uuid::new_v4(), Uuid::new_v4());
+        db.insert(legacy.0, legacy.1.as_bytes())?;
+        db.insert(hashed.0, hash(hashed.1))?;
+
+        // Only the plain text entry is converted, once.
+        assert_eq!(migrate(&db)?, 1);
+        assert_eq!(migrate(&db)?, 0);
+
+        // Both clients can authenticate.
+        for (client_id, secret) in [legacy, hashed] {
+            let entry = db.get(client_id)?.unwrap_or_default();
+            assert!(verify(&entry, secret));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,257 @@
+//! The storage of the durable messages sent to offline clients.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use relay_protocol::Flags;
+use sled::{Db, IVec, Tree};
+use uuid::Uuid;
+
+/// The name of the tree storing the durable messages.
+pub const TREE: &str = "durable";
+
+/// The length of the header of a stored message, before its payload.
+const HEADER_LENGTH: usize = 25;
+
+/// The durable messages waiting for their offline targets.
+///
+/// Each message is stored under the identifier of its target followed by a
+/// monotonic sequence number, so the messages of a target are kept in order.
+/// The value is the expiration time of the message in seconds since the unix
+/// epoch, followed by the identifier of the sender, the flags of the message
+/// and the payload.
+pub struct MessageStore {
+    /// The database, used to generate the sequence numbers.
+    db: Db,
+
+    /// The tree storing the messages.
+    tree: Tree,
+
+    /// The time after which a stored message is discarded.
+    ttl: Duration,
+
+    /// The maximum number of bytes stored for a single target.
+    capacity: usize,
+
+    /// The maximum number of bytes stored for all the targets.
+    total_capacity: usize,
+
+    /// The number of bytes stored for all the targets.
+    used: AtomicUsize,
+}
+
+/// A stored message with its key, which removes it once it's delivered.
+pub struct StoredMessage {
+    /// The key of the message in the store.
+    pub key: IVec,
+
+    /// The identifier of the sender.
+    pub sender: Uuid,
+
+    /// The flags to deliver the message with.
+    pub flags: Flags,
+
+    /// The content of the message.
+    pub payload: Vec<u8>,
+}
+
+/// Returns the current time in seconds since the unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Returns the expiration time of a stored message.
+fn expiration(value: &[u8]) -> u64 {
+    value
+        .first_chunk::<8>()
+        .map_or(0, |expiration| u64::from_be_bytes(*expiration))
+}
+
+impl MessageStore {
+    /// Open the message store in the given database.
+    pub fn open(
+        db: &Db,
+        ttl: Duration,
+        capacity: usize,
+        total_capacity: usize,
+    ) -> sled::Result<Self> {
+        let tree = db.open_tree(TREE)?;
+        let mut used = 0;
+        for entry in tree.iter() {
+            used += entry?.1.len();
+        }
+        Ok(Self {
+            db: db.clone(),
+            tree,
+            ttl,
+            capacity,
+            total_capacity,
+            used: AtomicUsize::new(used),
+        })
+    }
+
+    /// Remove a message from the store.
+    pub fn remove(&self, key: &[u8]) -> sled::Result<()> {
+        if let Some(value) = self.tree.remove(key)? {
+            self.used.fetch_sub(value.len(), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Store a message for an offline target, with the flags to deliver it
+    /// with.
+    ///
+    /// Returns false if the message doesn't fit in the storage of the target,
+    /// or in the storage shared by all the targets.
+    pub fn push(
+        &self,
+        target: Uuid,
+        sender: Uuid,
+        flags: Flags,
+        payload: &[u8],
+    ) -> sled::Result<bool> {
+        // Compute the size used by the target, removing the expired messages.
+        let now = now();
+        let mut used = 0;
+        for entry in self.tree.scan_prefix(target.as_bytes()) {
+            let (key, value) = entry?;
+            if expiration(&value) <= now {
+                self.remove(&key)?;
+            } else {
+                used += value.len();
+            }
+        }
+
+        // Check if the message fits.
+        let size = HEADER_LENGTH + payload.len();
+        if used + size > self.capacity
+            || self.used.load(Ordering::Relaxed) + size > self.total_capacity
+        {
+            return Ok(false);
+        }
+
+        // Store the message.
+        let mut key = Vec::with_capacity(24);
+        key.extend_from_slice(target.as_bytes());
+        key.extend_from_slice(&self.db.generate_id()?.to_be_bytes());
+        let mut value = Vec::with_capacity(size);
+        value.extend_from_slice(&(now + self.ttl.as_secs()).to_be_bytes());
+        value.extend_from_slice(sender.as_bytes());
+        value.push(flags.0);
+        value.extend_from_slice(payload);
+        if let Some(previous) = self.tree.insert(key, value)? {
+            self.used.fetch_sub(previous.len(), Ordering::Relaxed);
+        }
+        self.used.fetch_add(size, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    /// Returns the messages stored for a target, in order, keeping them
+    /// until they are [removed](Self::remove) once delivered.
+    ///
+    /// The expired and malformed messages are removed.
+    pub fn pending(&self, target: Uuid) -> sled::Result<Vec<StoredMessage>> {
+        let now = now();
+        let mut messages = Vec::new();
+        for entry in self.tree.scan_prefix(target.as_bytes()) {
+            let (key, value) = entry?;
+            if expiration(&value) <= now || value.len() < HEADER_LENGTH {
+                self.remove(&key)?;
+                continue;
+            }
+            messages.push(StoredMessage {
+                key,
+                sender: Uuid::from_slice(&value[8..24]).unwrap_or_default(),
+                flags: Flags(value[24]),
+                payload: value[HEADER_LENGTH..].to_vec(),
+            });
+        }
+        Ok(messages)
+    }
+
+    /// Remove and returns the messages stored for a target, in order.
+    pub fn take(&self, target: Uuid) -> sled::Result<Vec<StoredMessage>> {
+        let messages = self.pending(target)?;
+        for message in &messages {
+            self.remove(&message.key)?;
+        }
+        Ok(messages)
+    }
+
+    /// Remove all the expired messages and returns how many were removed.
+    pub fn purge(&self) -> sled::Result<usize> {
+        let now = now();
+        let mut removed = 0;
+        for entry in self.tree.iter() {
+            let (key, value) = entry?;
+            if expiration(&value) <= now {
+                self.remove(&key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Open a store in a temporary database, with the given capacities.
+    fn open(capacity: usize, total_capacity: usize) -> sled::Result<MessageStore> {
+        let db = sled::Config::new().temporary(true).open()?;
+        MessageStore::open(&db, Duration::from_secs(60), capacity, total_capacity)
+    }
+
+    /// The pending messages are kept until they are removed.
+    #[test]
+    fn pending_until_removed() -> sled::Result<()> {
+        let store = open(1024, 1024)?;
+        let (target, sender) = (Uuid::new_v4(), Uuid::new_v4());
+        assert!(store.push(target, sender, Flags::NONE, b"first")?);
+        assert!(store.push(target, sender, Flags::ENCRYPTED, b"second")?);
+
+        // Reading the messages doesn't remove them.
+        let pending = store.pending(target)?;
+        assert_eq!(pending.len(), 2);
+        assert_eq!(store.pending(target)?.len(), 2);
+        assert_eq!(pending[0].sender, sender);
+        assert_eq!(pending[0].payload, b"first");
+        assert_eq!(pending[1].flags, Flags::ENCRYPTED);
+
+        // Only the removed message is gone.
+        store.remove(&pending[0].key)?;
+        let pending = store.pending(target)?;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].payload, b"second");
+        assert_eq!(store.take(target)?.len(), 1);
+        assert!(store.pending(target)?.is_empty());
+        Ok(())
+    }
+
+    /// A message is refused when it doesn't fit in the storage of its target
+    /// or in the storage shared by all the targets, until room is made.
+    #[test]
+    fn capacities() -> sled::Result<()> {
+        let store = open(2 * (HEADER_LENGTH + 10), 3 * (HEADER_LENGTH + 10))?;
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let sender = Uuid::new_v4();
+        let payload = [0; 10];
+
+        // The storage of a single target is limited.
+        assert!(store.push(first, sender, Flags::NONE, &payload)?);
+        assert!(store.push(first, sender, Flags::NONE, &payload)?);
+        assert!(!store.push(first, sender, Flags::NONE, &payload)?);
+
+        // The storage shared by all the targets is limited.
+        assert!(store.push(second, sender, Flags::NONE, &payload)?);
+        assert!(!store.push(second, sender, Flags::NONE, &payload)?);
+
+        // Taking the messages of a target makes room for the others.
+        store.take(first)?;
+        assert!(store.push(second, sender, Flags::NONE, &payload)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+//! The TLS termination of the relay server.
+//!
+//! The certificate is read from PEM files, which are checked regularly and
+//! reloaded when they change, so a renewed certificate is used by the new
+//! connections without restarting the server.
+
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context};
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::Request;
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::time::{interval, sleep, timeout, MissedTickBehavior};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{crypto, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::{debug, info, warn};
+
+use crate::config::TlsConfig;
+
+/// The interval between two checks of the certificate files.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The maximum time a client can take to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The time to wait before accepting connections again after a failure.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Gives the last loaded certificate to every new connection.
+#[derive(Debug)]
+struct CertificateResolver(RwLock<Arc<CertifiedKey>>);
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(
+            &self.0.read().unwrap_or_else(PoisonError::into_inner),
+        ))
+    }
+}
+
+/// Read a certificate chain and its private key from PEM files.
+fn load(config: &TlsConfig) -> anyhow::Result<CertifiedKey> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .with_context(|| format!("unable to open {}", path.display()))
+    };
+    let certificates = rustls_pemfile::certs(&mut open(&config.certificate)?)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid certificate in {}", config.certificate.display()))?;
+    if certificates.is_empty() {
+        return Err(anyhow!(
+            "no certificate in {}",
+            config.certificate.display()
+        ));
+    }
+    let key = rustls_pemfile::private_key(&mut open(&config.key)?)
+        .with_context(|| format!("invalid private key in {}", config.key.display()))?
+        .ok_or_else(|| anyhow!("no private key in {}", config.key.display()))?;
+    let key = crypto::ring::sign::any_supported_type(&key)
+        .with_context(|| format!("unsupported private key in {}", config.key.display()))?;
+    Ok(CertifiedKey::new(certificates, key))
+}
+
+/// Reload the certificate if its files were modified since the given times,
+/// which are updated once it's loaded.
+fn reload(
+    config: &TlsConfig,
+    resolver: &CertificateResolver,
+    loaded: &mut Option<(SystemTime, SystemTime)>,
+) {
+    let current = modified(config);
+    if current == *loaded {
+        return;
+    }
+    match load(config) {
+        Ok(certificate) => {
+            *resolver.0.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(certificate);
+            *loaded = current;
+            info!("TLS certificate reloaded");
+        }
+
+        // The files may be in the middle of being replaced, so the previous
+        // certificate is kept and they are read again later.
+        Err(e) => warn!(
+            error = format!("{e:#}"),
+            "failed to reload the TLS certificate"
+        ),
+    }
+}
+
+/// Returns the last modification times of the certificate files.
+fn modified(config: &TlsConfig) -> Option<(SystemTime, SystemTime)> {
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    Some((
+        modified(&config.certificate).ok()?,
+        modified(&config.key).ok()?,
+    ))
+}
+
+/// Load the certificate of the configuration and returns an acceptor using
+/// it, reloading it when its files change until the server shuts down.
+pub fn acceptor(
+    config: &TlsConfig,
+    mut stop: watch::Receiver<bool>,
+) -> anyhow::Result<TlsAcceptor> {
+    let mut loaded = modified(config);
+    let resolver = Arc::new(CertificateResolver(RwLock::new(Arc::new(load(config)?))));
+    let mut server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::clone(&resolver) as Arc<dyn ResolvesServerCert>);
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    // Reload the certificate when its files change.
+    let config = config.clone();
+    tokio::spawn(async move {
+        let mut checks = interval(RELOAD_INTERVAL);
+        checks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = checks.tick() => (),
+                () = async { stop.wait_for(|&stop| stop).await.ok(); } => break,
+            }
+            reload(&config, &resolver, &mut loaded);
+        }
+    });
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Serve the application over TLS until the server shuts down.
+pub async fn serve(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    app: Router,
+    mut stop: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    loop {
+        // Accept a new connection.
+        let (stream, address) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!(error = %e, "failed to accept a connection");
+                    sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            },
+            () = async { stop.wait_for(|&stop| stop).await.ok(); } => return Ok(()),
+        };
+
+        // Serve the connection in the background.
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        tokio::spawn(async move {
+            let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    debug!(%address, error = %e, "TLS handshake failed");
+                    return;
+                }
+                Err(_) => {
+                    debug!(%address, "TLS handshake timed out");
+                    return;
+                }
+            };
+            let service = service_fn(move |mut request: Request<Incoming>| {
+                request
+                    .extensions_mut()
+                    .insert(ConnectInfo::<SocketAddr>(address));
+                app.clone().oneshot(request)
+            });
+            let connection = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades();
+            if let Err(e) = connection.await {
+                debug!(%address, error = %e, "connection failed");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::FileTimes;
+    use std::io;
+
+    use super::*;
+
+    /// The first certificate of the tests.
+    const FIRST: (&str, &str) = (
+        include_str!("../tests/fixtures/first-certificate.pem"),
+        include_str!("../tests/fixtures/first-key.pem"),
+    );
+
+    /// The second certificate of the tests.
+    const SECOND: (&str, &str) = (
+        include_str!("../tests/fixtures/second-certificate.pem"),
+        include_str!("../tests/fixtures/second-key.pem"),
+    );
+
+    /// Write the certificate and key files, modified at the given time.
+    fn write(config: &TlsConfig, (certificate, key): (&str, &str), time: u64) -> io::Result<()> {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(time);
+        for (path, content) in [(&config.certificate, certificate), (&config.key, key)] {
+            fs::write(path, content)?;
+            File::options()
+                .write(true)
+                .open(path)?
+                .set_times(FileTimes::new().set_modified(time))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the certificate given to the new connections.
+    fn current(resolver: &CertificateResolver) -> Vec<u8> {
+        let certificate = resolver.0.read().unwrap_or_else(PoisonError::into_inner);
+        certificate
+            .cert
+            .first()
+            .map(|cert| cert.to_vec())
+            .unwrap_or_default()
+    }
+
+    /// Returns the certificate of a PEM file content.
+    fn parse((certificate, _): (&str, &str)) -> anyhow::Result<Vec<u8>> {
+        let certificate = rustls_pemfile::certs(&mut certificate.as_bytes())
+            .next()
+            .context("no certificate")??;
+        Ok(certificate.to_vec())
+    }
+
+    /// A rewritten certificate is reloaded, and an invalid one keeps the
+    /// previous certificate until the files are valid again.
+    #[test]
+    fn reload_certificate() -> anyhow::Result<()> {
+        let directory = tempfile::tempdir()?;
+        let config = TlsConfig {
+            certificate: directory.path().join("certificate.pem"),
+            key: directory.path().join("key.pem"),
+        };
+        write(&config, FIRST, 1000)?;
+        let resolver = CertificateResolver(RwLock::new(Arc::new(load(&config)?)));
+        let mut loaded = modified(&config);
+        assert_eq!(current(&resolver), parse(FIRST)?);
+
+        // Nothing changes while the files are the same.
+        reload(&config, &resolver, &mut loaded);
+        assert_eq!(current(&resolver), parse(FIRST)?);
+
+        // The rewritten certificate is reloaded.
+        write(&config, SECOND, 2000)?;
+        reload(&config, &resolver, &mut loaded);
+        assert_eq!(current(&resolver), parse(SECOND)?);
+
+        // An invalid certificate or key keeps the previous certificate.
+        write(&config, ("invalid", SECOND.1), 3000)?;
+        reload(&config, &resolver, &mut loaded);
+        assert_eq!(current(&resolver), parse(SECOND)?);
+        write(&config, (FIRST.0, "invalid"), 4000)?;
+        reload(&config, &resolver, &mut loaded);
+        assert_eq!(current(&resolver), parse(SECOND)?);
+
+        // The files are read again until they are valid.
+        write(&config, FIRST, 4000)?;
+        reload(&config, &resolver, &mut loaded);
+        assert_eq!(current(&resolver), parse(FIRST)?);
+        Ok(())
+    }
+}
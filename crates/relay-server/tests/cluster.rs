@@ -0,0 +1,122 @@
+//! Tests of two relay servers linked as the nodes of a cluster, with real
+//! clients connected to each of them.
+
+mod common;
+
+use std::path::Path;
+
+use anyhow::Context;
+use relay_client::{Connection, ConnectionStatus, Delivery, Notification};
+use relay_server::{Config, RelayServer};
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+use self::common::update_until;
+
+/// The token shared by the nodes.
+const NODE_TOKEN: &str = "node token";
+
+/// Connect a client to the relay server at the given URL, with the
+/// credentials stored at the given path, and wait until it's active.
+fn connect(url: &str, credentials: &Path) -> anyhow::Result<Connection> {
+    let mut connection = Connection::builder(url)
+        .credentials_path(credentials)
+        .build()?;
+    update_until(&mut [&mut connection], |connections, _| {
+        connections[0].status() == ConnectionStatus::Active
+    })
+    .context("the client should connect")?;
+    Ok(connection)
+}
+
+/// Update a client until it receives a presence notification.
+fn wait_for(connection: &mut Connection, notification: &Notification) -> anyhow::Result<()> {
+    update_until(&mut [connection], |connections, _| {
+        connections[0].take_notifications().contains(notification)
+    })
+    .with_context(|| format!("the client should receive {notification:?}"))
+}
+
+/// Forward a message to a client of the other node, hand a durable message
+/// off to the node its target connects to, and supersede a session of the
+/// other node.
+#[test]
+fn forward_and_hand_off() -> anyhow::Result<()> {
+    Runtime::new()?.block_on(async {
+        // Link a second node to the first one.
+        let mut first = Config::local();
+        first.node_token = Some(NODE_TOKEN.to_owned());
+        let first = RelayServer::bind(first).await?;
+        let first_url = format!("ws://{}", first.local_addr());
+        let mut second = Config::local();
+        second.node_token = Some(NODE_TOKEN.to_owned());
+        second.peers = vec![first_url.clone()];
+        let second = RelayServer::bind(second).await?;
+        let second_url = format!("ws://{}", second.local_addr());
+
+        let directory = std::env::temp_dir();
+        let alice_path = directory.join(format!("relay-credentials-{}", Uuid::new_v4()));
+        let bob_path = directory.join(format!("relay-credentials-{}", Uuid::new_v4()));
+        let result = tokio::task::spawn_blocking({
+            let (alice_path, bob_path) = (alice_path.clone(), bob_path.clone());
+            move || {
+                // Wait until the first node sees a client of the second one.
+                let mut alice = connect(&first_url, &alice_path)?;
+                let mut bob = connect(&second_url, &bob_path)?;
+                let alice_id = alice.identifier().context("alice should be registered")?;
+                let bob_id = bob.identifier().context("bob should be registered")?;
+                alice.subscribe_presence(bob_id)?;
+                wait_for(&mut alice, &Notification::Online(bob_id))?;
+
+                // Forward a message to the second node.
+                alice.send(bob_id, b"hello".as_slice())?;
+                update_until(&mut [&mut alice, &mut bob], |_, messages| {
+                    messages[1].contains(&(alice_id, b"hello".to_vec()))
+                })
+                .context("bob should receive the message")?;
+
+                // Store a durable message on the first node while bob is
+                // offline.
+                drop(bob);
+                wait_for(&mut alice, &Notification::Offline(bob_id))?;
+                alice.send_durable_acknowledged(bob_id, b"later".as_slice())?;
+                update_until(&mut [&mut alice], |connections, _| {
+                    connections[0]
+                        .take_deliveries()
+                        .iter()
+                        .any(|delivery| matches!(delivery, Delivery::Acknowledged { .. }))
+                })
+                .context("the durable message should be stored")?;
+
+                // Receive it from the second node.
+                let mut bob = connect(&second_url, &bob_path)?;
+                update_until(&mut [&mut alice, &mut bob], |_, messages| {
+                    messages[1].contains(&(alice_id, b"later".to_vec()))
+                })
+                .context("bob should receive the durable message")?;
+
+                // Supersede the session of the second node from the first one.
+                wait_for(&mut alice, &Notification::Online(bob_id))?;
+                let mut newer = Connection::builder(&first_url)
+                    .credentials_path(&bob_path)
+                    .build()?;
+                update_until(&mut [&mut bob, &mut newer], |connections, _| {
+                    connections[0].status() != ConnectionStatus::Active
+                        && connections[1].status() == ConnectionStatus::Active
+                })
+                .context("the older session should be closed")?;
+                let error = bob.last_error().unwrap_or_default();
+                assert!(error.contains("superseded"), "{error}");
+                anyhow::Ok(())
+            }
+        })
+        .await;
+
+        // Stop the nodes and remove the credentials.
+        second.shutdown().await?;
+        first.shutdown().await?;
+        std::fs::remove_file(alice_path).ok();
+        std::fs::remove_file(bob_path).ok();
+        result?
+    })
+}
@@ -0,0 +1,37 @@
+//! Helpers shared by the integration tests.
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use relay_client::Connection;
+use uuid::Uuid;
+
+/// The maximum time to wait for something to happen.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The time between two updates of the clients.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Update the connections until the condition is true, or returns an error
+/// after the [TIMEOUT].
+///
+/// The condition is given the messages received by each connection during
+/// the last update.
+pub fn update_until(
+    connections: &mut [&mut Connection],
+    mut condition: impl FnMut(&mut [&mut Connection], &[Vec<(Uuid, Vec<u8>)>]) -> bool,
+) -> anyhow::Result<()> {
+    let deadline = Instant::now() + TIMEOUT;
+    while Instant::now() < deadline {
+        let messages: Vec<_> = connections
+            .iter_mut()
+            .map(|connection| connection.update().into_iter().collect())
+            .collect();
+        if condition(connections, &messages) {
+            return Ok(());
+        }
+        sleep(UPDATE_INTERVAL);
+    }
+    bail!("timed out")
+}
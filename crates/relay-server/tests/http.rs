@@ -0,0 +1,222 @@
+//! Tests of the HTTP endpoints of a relay server, requested over plain
+//! HTTP/1.1 connections.
+
+mod common;
+
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use relay_client::{Connection, ConnectionStatus};
+use relay_server::{Config, RelayServer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+use tokio::time::sleep;
+use uuid::Uuid;
+
+use self::common::update_until;
+
+/// Send a request to the relay server, with a bearer token if given, and
+/// returns the status code and the body of the response.
+async fn request(
+    server: &RelayServer,
+    method: &str,
+    path: &str,
+    token: Option<&str>,
+) -> anyhow::Result<(u16, String)> {
+    let mut stream = TcpStream::connect(server.local_addr()).await?;
+    let mut request =
+        format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n");
+    if let Some(token) = token {
+        request.push_str(&format!("Authorization: Bearer {token}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .context("the response has no body")?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .context("the response has no status")?
+        .parse()?;
+    Ok((status, body.to_owned()))
+}
+
+/// The admin token of the relay servers of the admin API tests.
+const ADMIN_TOKEN: &str = "admin token";
+
+/// Send a request to the admin API of the relay server with its token.
+async fn admin(server: &RelayServer, method: &str, path: &str) -> anyhow::Result<(u16, String)> {
+    request(server, method, &format!("/admin{path}"), Some(ADMIN_TOKEN)).await
+}
+
+/// Wait until the live sessions listed by the admin API don't include the
+/// given client.
+async fn wait_disconnected(server: &RelayServer, client_id: Uuid) -> anyhow::Result<()> {
+    for _ in 0..1000 {
+        let (_, sessions) = admin(server, "GET", "/sessions").await?;
+        if !sessions.contains(&client_id.to_string()) {
+            return Ok(());
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    bail!("the client should be disconnected")
+}
+
+/// Connect a client to the relay server and wait until it's active.
+async fn connect(server: &RelayServer) -> anyhow::Result<Connection> {
+    let url = format!("ws://{}", server.local_addr());
+    tokio::task::spawn_blocking(move || {
+        let mut connection = Connection::builder(url).ephemeral().build()?;
+        update_until(&mut [&mut connection], |connections, _| {
+            connections[0].status() == ConnectionStatus::Active
+        })
+        .context("the client should connect")?;
+        anyhow::Ok(connection)
+    })
+    .await?
+}
+
+/// The health check answers while the server runs.
+#[test]
+fn healthz() -> anyhow::Result<()> {
+    Runtime::new()?.block_on(async {
+        let server = RelayServer::bind(Config::local()).await?;
+        assert_eq!(
+            request(&server, "GET", "/healthz", None).await?,
+            (200, "ok".to_owned())
+        );
+        server.shutdown().await
+    })
+}
+
+/// The metrics count the connected clients and the registrations.
+#[test]
+fn metrics() -> anyhow::Result<()> {
+    Runtime::new()?.block_on(async {
+        let server = RelayServer::bind(Config::local()).await?;
+        let (status, body) = request(&server, "GET", "/metrics", None).await?;
+        assert_eq!(status, 200);
+        assert!(body.contains("relay_connected_clients 0\n"), "{body}");
+        assert!(
+            body.contains("# TYPE relay_registrations_total counter\n"),
+            "{body}"
+        );
+
+        // A connected client is counted.
+        let client = connect(&server).await?;
+        let (_, body) = request(&server, "GET", "/metrics", None).await?;
+        assert!(body.contains("relay_connected_clients 1\n"), "{body}");
+        assert!(body.contains("relay_registrations_total 1\n"), "{body}");
+        drop(client);
+        server.shutdown().await
+    })
+}
+
+/// The admin API is only served with an admin token, and only answers the
+/// requests with this token.
+#[test]
+fn admin_authorization() -> anyhow::Result<()> {
+    Runtime::new()?.block_on(async {
+        let server = RelayServer::bind(Config::local()).await?;
+        let (status, _) = request(&server, "GET", "/admin/sessions", Some(ADMIN_TOKEN)).await?;
+        assert_eq!(status, 404);
+        server.shutdown().await?;
+
+        // The requests without the token are refused.
+        let config = Config {
+            admin_token: Some(ADMIN_TOKEN.to_owned()),
+            ..Config::local()
+        };
+        let server = RelayServer::bind(config).await?;
+        let (status, _) = request(&server, "GET", "/admin/sessions", None).await?;
+        assert_eq!(status, 401);
+        let (status, _) = request(&server, "GET", "/admin/sessions", Some("wrong")).await?;
+        assert_eq!(status, 401);
+        assert_eq!(
+            admin(&server, "GET", "/sessions").await?,
+            (200, "[]".to_owned())
+        );
+        server.shutdown().await
+    })
+}
+
+/// The live sessions are listed and can be closed.
+#[test]
+fn admin_sessions() -> anyhow::Result<()> {
+    Runtime::new()?.block_on(async {
+        let config = Config {
+            admin_token: Some(ADMIN_TOKEN.to_owned()),
+            ..Config::local()
+        };
+        let server = RelayServer::bind(config).await?;
+        let client = connect(&server).await?;
+        let client_id = client
+            .identifier()
+            .context("the client should be registered")?;
+        let (status, sessions) = admin(&server, "GET", "/sessions").await?;
+        assert_eq!(status, 200);
+        assert!(
+            sessions.contains(&format!("\"id\":\"{client_id}\"")),
+            "{sessions}"
+        );
+
+        // Only a connected client can be disconnected.
+        let unknown = Uuid::new_v4();
+        let (status, _) = admin(&server, "DELETE", &format!("/sessions/{unknown}")).await?;
+        assert_eq!(status, 404);
+        let (status, _) = admin(&server, "DELETE", &format!("/sessions/{client_id}")).await?;
+        assert_eq!(status, 204);
+        wait_disconnected(&server, client_id).await?;
+        drop(client);
+        server.shutdown().await
+    })
+}
+
+/// The identities can be revoked, and banned until the ban is lifted.
+#[test]
+fn admin_identities_and_bans() -> anyhow::Result<()> {
+    Runtime::new()?.block_on(async {
+        let config = Config {
+            admin_token: Some(ADMIN_TOKEN.to_owned()),
+            ..Config::local()
+        };
+        let server = RelayServer::bind(config).await?;
+
+        // Banning an identity disconnects its client, until the ban is
+        // lifted.
+        let client = connect(&server).await?;
+        let client_id = client
+            .identifier()
+            .context("the client should be registered")?;
+        let (status, _) = admin(&server, "PUT", &format!("/bans/{client_id}")).await?;
+        assert_eq!(status, 204);
+        wait_disconnected(&server, client_id).await?;
+        assert_eq!(
+            admin(&server, "GET", "/bans").await?,
+            (200, format!("[\"{client_id}\"]"))
+        );
+        let (status, _) = admin(&server, "DELETE", &format!("/bans/{client_id}")).await?;
+        assert_eq!(status, 204);
+        assert_eq!(
+            admin(&server, "GET", "/bans").await?,
+            (200, "[]".to_owned())
+        );
+        drop(client);
+
+        // Revoking an identity disconnects its client, and only works once.
+        let client = connect(&server).await?;
+        let client_id = client
+            .identifier()
+            .context("the client should be registered")?;
+        let path = format!("/identities/{client_id}");
+        assert_eq!(admin(&server, "DELETE", &path).await?.0, 204);
+        wait_disconnected(&server, client_id).await?;
+        assert_eq!(admin(&server, "DELETE", &path).await?.0, 404);
+        drop(client);
+        server.shutdown().await
+    })
+}
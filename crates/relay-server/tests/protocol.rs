@@ -0,0 +1,307 @@
+//! Tests of the protocol handling of a relay server, with raw websocket
+//! clients negotiating only some capabilities.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use futures::{SinkExt, StreamExt};
+use relay_protocol::{
+    Capabilities, Flags, Frame, Hello, Notification, Request, RoomName, Welcome, PROTOCOL_VERSION,
+};
+use relay_server::{Config, RelayServer};
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use uuid::Uuid;
+
+/// The maximum time to wait for a frame.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A client speaking the relay protocol directly over a websocket.
+struct RawClient {
+    /// The websocket to the relay server.
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+
+    /// The identifier of the client.
+    id: Uuid,
+
+    /// The secret of the client.
+    secret: Uuid,
+
+    /// The protocol version of the session.
+    version: u16,
+
+    /// The capabilities granted by the relay server.
+    capabilities: Capabilities,
+}
+
+impl RawClient {
+    /// Register a new client with the given capabilities.
+    async fn connect(server: &RelayServer, capabilities: Capabilities) -> anyhow::Result<Self> {
+        Self::authenticate(server, capabilities, None).await
+    }
+
+    /// Connect a client with the given capabilities and credentials, or
+    /// register a new one if there are none.
+    async fn authenticate(
+        server: &RelayServer,
+        capabilities: Capabilities,
+        credentials: Option<(Uuid, Uuid)>,
+    ) -> anyhow::Result<Self> {
+        let (mut socket, _) = connect_async(format!("ws://{}", server.local_addr())).await?;
+        let hello = Hello::Versioned {
+            version: PROTOCOL_VERSION,
+            capabilities,
+            credentials,
+        };
+        socket.send(Message::Binary(hello.encode())).await?;
+        let mut client = Self {
+            socket,
+            id: Uuid::nil(),
+            secret: Uuid::nil(),
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::NONE,
+        };
+        match Welcome::decode(&client.receive_data().await?) {
+            Some(Welcome::Accepted {
+                version,
+                capabilities,
+                credentials: registered,
+            }) => {
+                (client.id, client.secret) = registered
+                    .or(credentials)
+                    .context("no credentials for a new client")?;
+                client.version = version;
+                client.capabilities = capabilities;
+                Ok(client)
+            }
+            welcome => bail!("unexpected welcome: {welcome:?}"),
+        }
+    }
+
+    /// Send a frame to the relay server.
+    async fn send(&mut self, frame: &Frame<Request>) -> anyhow::Result<()> {
+        let data = frame.encode(self.version);
+        Ok(self.socket.send(Message::Binary(data)).await?)
+    }
+
+    /// Returns the next binary message received from the relay server.
+    async fn receive_data(&mut self) -> anyhow::Result<Vec<u8>> {
+        loop {
+            let message = timeout(TIMEOUT, self.socket.next())
+                .await
+                .context("timed out")?
+                .context("the websocket is closed")??;
+            if let Message::Binary(data) = message {
+                return Ok(data);
+            }
+        }
+    }
+
+    /// Returns the next frame received from the relay server.
+    async fn receive(&mut self) -> anyhow::Result<Frame<Notification>> {
+        let data = self.receive_data().await?;
+        Ok(Frame::decode(data, self.version)?)
+    }
+
+    /// Send a message to an unknown client and wait for its bounce, so every
+    /// frame sent before was handled, and returns the frames received before
+    /// it.
+    async fn sync(&mut self) -> anyhow::Result<Vec<Frame<Notification>>> {
+        let unknown = Uuid::new_v4();
+        let message = Frame::Data {
+            peer: unknown,
+            flags: Flags::NONE,
+            payload: Vec::new(),
+        };
+        self.send(&message).await?;
+        let mut received = Vec::new();
+        loop {
+            match self.receive().await? {
+                Frame::Control(Notification::TargetUnknown(peer)) if peer == unknown => {
+                    return Ok(received)
+                }
+                frame => received.push(frame),
+            }
+        }
+    }
+}
+
+/// The presence requests of a session that didn't negotiate the presence
+/// capability are ignored.
+#[test]
+fn presence_requires_capability() -> anyhow::Result<()> {
+    Runtime::new()?.block_on(async {
+        let server = RelayServer::bind(Config::local()).await?;
+        let mut peer = RawClient::connect(&server, Capabilities::BOUNCES).await?;
+        let mut client = RawClient::connect(&server, Capabilities::BOUNCES).await?;
+        client
+            .send(&Frame::Control(Request::Subscribe(peer.id)))
+            .await?;
+        assert_eq!(client.sync().await?, []);
+
+        // The same request is answered with the capability.
+        let mut watcher =
+            RawClient::connect(&server, Capabilities::BOUNCES | Capabilities::PRESENCE).await?;
+        watcher
+            .send(&Frame::Control(Request::Subscribe(peer.id)))
+            .await?;
+        assert_eq!(
+            watcher.sync().await?,
+            [Frame::Control(Notification::Online(peer.id))]
+        );
+        assert_eq!(peer.sync().await?, []);
+        server.shutdown().await
+    })
+}
+
+/// The room requests of a session that didn't negotiate the rooms capability
+/// are ignored.
+#[test]
+fn rooms_require_capability() -> anyhow::Result<()> {
+    Runtime::new()?.block_on(async {
+        let server = RelayServer::bind(Config::local()).await?;
+        let room = RoomName::new("lobby")?;
+        let mut client = RawClient::connect(&server, Capabilities::BOUNCES).await?;
+        client
+            .send(&Frame::Control(Request::JoinRoom(room.clone())))
+            .await?;
+        assert_eq!(client.sync().await?, []);
+
+        // The same request is answered with the capability.
+        let mut member =
+            RawClient::connect(&server, Capabilities::BOUNCES | Capabilities::ROOMS).await?;
+        member
+            .send(&Frame::Control(Request::JoinRoom(room.clone())))
+            .await?;
+        assert_eq!(
+            member.sync().await?,
+            [Frame::Control(Notification::RoomJoined(room))]
+        );
+        server.shutdown().await
+    })
+}
+
+/// A node of a cluster doesn't grant the rooms capability, since its rooms
+/// aren't shared with the other nodes.
+#[test]
+fn no_rooms_in_cluster() -> anyhow::Result<()> {
+    Runtime::new()?.block_on(async {
+        let config = Config {
+            node_token: Some("token".to_owned()),
+            ..Config::local()
+        };
+        let server = RelayServer::bind(config).await?;
+        let mut client = RawClient::connect(&server, Capabilities::ALL).await?;
+        assert!(!client.capabilities.contains(Capabilities::ROOMS));
+        assert!(client.capabilities.contains(Capabilities::PRESENCE));
+
+        // The room requests are ignored.
+        let room = RoomName::new("lobby")?;
+        client
+            .send(&Frame::Control(Request::JoinRoom(room)))
+            .await?;
+        assert_eq!(client.sync().await?, []);
+        server.shutdown().await
+    })
+}
+
+/// A durable message for an identity that was never registered isn't stored,
+/// and is bounced like any other message for an unknown target.
+#[test]
+fn durable_unregistered_target() -> anyhow::Result<()> {
+    Runtime::new()?.block_on(async {
+        let server = RelayServer::bind(Config::local()).await?;
+        let capabilities = Capabilities::BOUNCES | Capabilities::DURABLE;
+        let mut client = RawClient::connect(&server, capabilities).await?;
+        let unknown = Uuid::new_v4();
+        let message = Frame::Data {
+            peer: unknown,
+            flags: Flags::DURABLE,
+            payload: b"later".to_vec(),
+        };
+        client.send(&message).await?;
+        assert_eq!(
+            client.sync().await?,
+            [Frame::Control(Notification::TargetUnknown(unknown))]
+        );
+        server.shutdown().await
+    })
+}
+
+/// A rotated secret replaces the old one, which can't be used anymore.
+#[test]
+fn rotate_secret() -> anyhow::Result<()> {
+    Runtime::new()?.block_on(async {
+        let server = RelayServer::bind(Config::local()).await?;
+        let capabilities = Capabilities::BOUNCES | Capabilities::ROTATION;
+        let mut client = RawClient::connect(&server, capabilities).await?;
+        client.send(&Frame::Control(Request::RotateSecret)).await?;
+        let frames = client.sync().await?;
+        let [Frame::Control(Notification::SecretRotated(secret))] = frames[..] else {
+            bail!("unexpected frames: {frames:?}");
+        };
+        assert_ne!(secret, client.secret);
+
+        // Only the new secret is accepted.
+        let old = (client.id, client.secret);
+        assert!(RawClient::authenticate(&server, capabilities, Some(old))
+            .await
+            .is_err());
+        let new = (client.id, secret);
+        let client = RawClient::authenticate(&server, capabilities, Some(new)).await?;
+        assert_eq!(client.secret, secret);
+        server.shutdown().await
+    })
+}
+
+/// The rooms and subscriptions of a superseded session are cleared without
+/// notifying the newer session, while the other members see the identity
+/// leave.
+#[test]
+fn superseded_session() -> anyhow::Result<()> {
+    Runtime::new()?.block_on(async {
+        let server = RelayServer::bind(Config::local()).await?;
+        let capabilities = Capabilities::BOUNCES | Capabilities::PRESENCE | Capabilities::ROOMS;
+        let room = RoomName::new("lobby")?;
+        let mut older = RawClient::connect(&server, capabilities).await?;
+        let mut member = RawClient::connect(&server, capabilities).await?;
+        let peer = RawClient::connect(&server, capabilities).await?;
+        for client in [&mut older, &mut member] {
+            client
+                .send(&Frame::Control(Request::JoinRoom(room.clone())))
+                .await?;
+            client.sync().await?;
+        }
+        for client in [&mut older, &mut member] {
+            client
+                .send(&Frame::Control(Request::Subscribe(peer.id)))
+                .await?;
+            client.sync().await?;
+        }
+
+        // The newer session receives nothing about the older one.
+        let credentials = Some((older.id, older.secret));
+        let mut newer = RawClient::authenticate(&server, capabilities, credentials).await?;
+        assert_eq!(newer.sync().await?, []);
+        assert_eq!(
+            member.sync().await?,
+            [Frame::Control(Notification::MemberLeft {
+                room,
+                member: older.id,
+            })]
+        );
+
+        // The presence subscription isn't carried over either.
+        let peer_id = peer.id;
+        drop(peer);
+        assert_eq!(
+            member.receive().await?,
+            Frame::Control(Notification::Offline(peer_id))
+        );
+        assert_eq!(newer.sync().await?, []);
+        server.shutdown().await
+    })
+}
@@ -0,0 +1,234 @@
+//! Tests of a relay server bound on an ephemeral localhost port, with real
+//! clients connected over websockets.
+
+mod common;
+
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use futures::{SinkExt, StreamExt};
+use relay_client::{Connection, ConnectionStatus};
+use relay_protocol::{
+    Capabilities, CloseReason, Flags, Frame, Hello, Notification, Welcome, PROTOCOL_VERSION,
+};
+use relay_server::config::SlowConsumerPolicy;
+use relay_server::{Config, RelayServer};
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use uuid::Uuid;
+
+use self::common::update_until;
+
+/// A websocket to the relay server, read only when a test wants to.
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// The number of frames sent to a client that doesn't read them, which is
+/// more than the channel and the socket buffers of the client can hold.
+const FLOOD_FRAMES: usize = 64;
+
+/// The size of the payload of each frame sent to a client that doesn't read
+/// them.
+const FLOOD_SIZE: usize = 512 * 1024;
+
+/// The time without receiving anything after which a flooded client is
+/// considered drained.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Connect two clients, relay a message between them, and shut the server
+/// down while they are connected.
+#[test]
+fn relay_and_shutdown() -> anyhow::Result<()> {
+    Runtime::new()?.block_on(async {
+        let server = RelayServer::bind(Config::local()).await?;
+        let url = format!("ws://{}", server.local_addr());
+
+        // Connect two clients and send a message from one to the other.
+        let mut clients = tokio::task::spawn_blocking(move || {
+            let mut alice = Connection::builder(url.clone()).ephemeral().build()?;
+            let mut bob = Connection::builder(url).ephemeral().build()?;
+            update_until(&mut [&mut alice, &mut bob], |connections, _| {
+                connections
+                    .iter()
+                    .all(|connection| connection.status() == ConnectionStatus::Active)
+            })
+            .context("the clients should connect")?;
+            let alice_id = alice.identifier().context("alice should be registered")?;
+            let bob_id = bob.identifier().context("bob should be registered")?;
+            alice.send(bob_id, b"hello".as_slice())?;
+            update_until(&mut [&mut alice, &mut bob], |_, messages| {
+                messages[1].contains(&(alice_id, b"hello".to_vec()))
+            })
+            .context("bob should receive the message")?;
+            anyhow::Ok((alice, bob))
+        })
+        .await??;
+
+        // Shut the server down while the clients keep being updated.
+        let disconnected = tokio::task::spawn_blocking(move || {
+            let (alice, bob) = &mut clients;
+            update_until(&mut [alice, bob], |connections, _| {
+                connections
+                    .iter()
+                    .all(|connection| connection.status() != ConnectionStatus::Active)
+            })
+            .context("the clients should be disconnected")?;
+            anyhow::Ok(clients)
+        });
+        server.shutdown().await?;
+        let (alice, _) = disconnected.await??;
+        let error = alice.last_error().unwrap_or_default();
+        assert!(error.contains("server restarting"), "{error}");
+        Ok(())
+    })
+}
+
+/// Register a client over a raw websocket, and returns the websocket with
+/// the identifier of the client.
+async fn register(server: &RelayServer) -> anyhow::Result<(Socket, Uuid)> {
+    let (mut socket, _) = connect_async(format!("ws://{}", server.local_addr())).await?;
+    let hello = Hello::Versioned {
+        version: PROTOCOL_VERSION,
+        capabilities: Capabilities::NONE,
+        credentials: None,
+    };
+    socket.send(Message::Binary(hello.encode())).await?;
+    let data = match socket.next().await {
+        Some(Ok(Message::Binary(data))) => data,
+        message => bail!("unexpected message: {message:?}"),
+    };
+    match Welcome::decode(&data) {
+        Some(Welcome::Accepted {
+            credentials: Some((id, _)),
+            ..
+        }) => Ok((socket, id)),
+        welcome => bail!("unexpected welcome: {welcome:?}"),
+    }
+}
+
+/// Bind a relay server with the given slow consumer policy, and send more
+/// frames to a client than it can hold while it doesn't read them.
+///
+/// Returns the server, the websocket of the sender and the websocket and
+/// identifier of the flooded client.
+async fn flood(policy: SlowConsumerPolicy) -> anyhow::Result<(RelayServer, Socket, Socket, Uuid)> {
+    let config = Config {
+        channel_capacity: 1,
+        slow_consumer: policy,
+        max_frame_size: 2 * FLOOD_SIZE,
+        max_messages_per_second: 10_000,
+        max_bytes_per_second: u32::MAX,
+        ..Config::local()
+    };
+    let server = RelayServer::bind(config).await?;
+    let (receiver, receiver_id) = register(&server).await?;
+    let (mut sender, _) = register(&server).await?;
+    for _ in 0..FLOOD_FRAMES {
+        let frame = Frame::<Notification>::Data {
+            peer: receiver_id,
+            flags: Flags::NONE,
+            payload: vec![0; FLOOD_SIZE],
+        };
+        sender
+            .send(Message::Binary(frame.encode(PROTOCOL_VERSION)))
+            .await?;
+    }
+    Ok((server, sender, receiver, receiver_id))
+}
+
+/// Read the frames sent to a flooded client until nothing is received for
+/// a while or the session is closed, and returns the number of frames read
+/// with the close frame, if any.
+async fn drain(socket: &mut Socket) -> anyhow::Result<(usize, Option<CloseFrame<'static>>)> {
+    let mut received = 0;
+    while let Ok(message) = timeout(DRAIN_TIMEOUT, socket.next()).await {
+        match message.context("the websocket is closed")?? {
+            Message::Binary(_) => received += 1,
+            Message::Close(frame) => return Ok((received, frame)),
+            _ => (),
+        }
+    }
+    Ok((received, None))
+}
+
+/// Relay a message between two other clients, which aren't affected by the
+/// slow consumer.
+async fn relay_others(server: &RelayServer) -> anyhow::Result<()> {
+    let url = format!("ws://{}", server.local_addr());
+    tokio::task::spawn_blocking(move || {
+        let mut alice = Connection::builder(url.clone()).ephemeral().build()?;
+        let mut bob = Connection::builder(url).ephemeral().build()?;
+        update_until(&mut [&mut alice, &mut bob], |connections, _| {
+            connections
+                .iter()
+                .all(|connection| connection.status() == ConnectionStatus::Active)
+        })
+        .context("the other clients should connect")?;
+        let alice_id = alice.identifier().context("alice should be registered")?;
+        let bob_id = bob.identifier().context("bob should be registered")?;
+        alice.send(bob_id, b"hello".as_slice())?;
+        update_until(&mut [&mut alice, &mut bob], |_, messages| {
+            messages[1].contains(&(alice_id, b"hello".to_vec()))
+        })
+        .context("bob should receive the message")
+    })
+    .await?
+}
+
+/// The frames sent to a client whose channel is full are dropped with the
+/// drop policy, while it stays connected and the other clients are served.
+#[test]
+fn slow_consumer_drop() -> anyhow::Result<()> {
+    Runtime::new()?.block_on(async {
+        let (server, mut sender, mut receiver, receiver_id) =
+            flood(SlowConsumerPolicy::Drop).await?;
+        relay_others(&server).await?;
+
+        // Some frames were dropped, but the client is still connected.
+        let (received, close) = drain(&mut receiver).await?;
+        assert!(received < FLOOD_FRAMES, "{received} frames received");
+        assert_eq!(close, None);
+
+        // The client receives the frames sent once it reads them again.
+        let frame = Frame::<Notification>::Data {
+            peer: receiver_id,
+            flags: Flags::NONE,
+            payload: b"again".to_vec(),
+        };
+        sender
+            .send(Message::Binary(frame.encode(PROTOCOL_VERSION)))
+            .await?;
+        let message = timeout(DRAIN_TIMEOUT, receiver.next())
+            .await?
+            .context("the websocket is closed")??;
+        let Message::Binary(data) = message else {
+            bail!("unexpected message: {message:?}");
+        };
+        let frame = Frame::<Notification>::decode(data, PROTOCOL_VERSION)?;
+        assert!(matches!(frame, Frame::Data { payload, .. } if payload == b"again"));
+        server.shutdown().await
+    })
+}
+
+/// A client whose channel is full is disconnected with the disconnect
+/// policy, while the other clients are served.
+#[test]
+fn slow_consumer_disconnect() -> anyhow::Result<()> {
+    Runtime::new()?.block_on(async {
+        let (server, _sender, mut receiver, _) = flood(SlowConsumerPolicy::Disconnect).await?;
+        relay_others(&server).await?;
+
+        // The client receives the frames queued before being disconnected.
+        let (received, close) = drain(&mut receiver).await?;
+        assert!(received < FLOOD_FRAMES, "{received} frames received");
+        let code = close.context("the session should be closed")?.code;
+        assert_eq!(
+            CloseReason::from_code(code.into()),
+            Some(CloseReason::SlowConsumer)
+        );
+        server.shutdown().await
+    })
+}